@@ -15,7 +15,12 @@ pub enum ArgsCommand {
     Version, // prints the version of the client
     Command, // runs a command on a remote shell
     Populate, // populate the bin folder
-    ForwardTcp // forward a tcp connection
+    ForwardTcp, // forward a tcp connection
+    ForwardUdp, // forward the datagrams of a local udp port
+    ForwardHttp, // serve a remote http server locally
+    ReverseForwardTcp, // expose a local port on a remote shell
+    Socks, // local SOCKS5 proxy through a remote shell
+    Forward // run the forwards of the forwards config file
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +49,9 @@ pub struct Args {
     pub verbose: bool,
     /* */
     pub command_timeout: Duration,
+    pub script_timeout: Duration,
+    pub script_max_timeout: Duration,
+    pub allow_remote_bind: bool,
     pub extra_args: Vec<String>,
     pub format: OutputFormat
 }
@@ -72,16 +80,36 @@ impl Args {
     }
 
     pub fn consume_extra_arg(&mut self, xa: &str) -> bool {
-        let xa = String::from(xa);
-        
-        let xa_index = self.extra_args.iter().position(|x| *x == xa);
-        match xa_index {
-            Some(xa_index) => {
-                self.extra_args.remove(xa_index);
-                return true;
-            },
-            None => false
-        }
+        return consume_arg(&mut self.extra_args, xa);
+    }
+}
+
+/// Removes a flag (e.g. `--json`) from a list of arguments
+pub fn consume_arg(args: &mut Vec<String>, name: &str) -> bool {
+    let name_index = args.iter().position(|x| *x == name);
+    match name_index {
+        Some(name_index) => {
+            args.remove(name_index);
+            return true;
+        },
+        None => false
+    }
+}
+
+/// Removes an option and its value (e.g. `--depth 2`) from a list of arguments
+pub fn consume_arg_value(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let name_index = args.iter().position(|x| *x == name);
+    match name_index {
+        Some(name_index) => {
+            if name_index + 1 >= args.len() {
+                eprintln!("Missing value for option {}", name);
+                std::process::exit(-1);
+            }
+            let value = args.remove(name_index + 1);
+            args.remove(name_index);
+            return Some(value);
+        },
+        None => None
     }
 }
 
@@ -139,11 +167,40 @@ pub fn parse_args() -> Args {
                 command = ArgsCommand::Populate;
             },
             "forward-tcp" => {
-                /* hopo forward-tcp <shell> <local port> <host> <remote port>  */
+                /* hopo forward-tcp <shell> <local port>|unix:<path> <host> <remote port>|unix:<path> */
                 shell_name = Some(cmd_args[2].clone());
                 command = ArgsCommand::ForwardTcp;
                 extra_args = cmd_args[3..].to_vec();
             },
+            "forward-udp" => {
+                /* hopo forward-udp <shell> <local port> <host> <remote port> */
+                shell_name = Some(cmd_args[2].clone());
+                command = ArgsCommand::ForwardUdp;
+                extra_args = cmd_args[3..].to_vec();
+            },
+            "reverse-forward-tcp" => {
+                /* hopo reverse-forward-tcp <shell> <remote bind port> <local host> <local port> */
+                shell_name = Some(cmd_args[2].clone());
+                command = ArgsCommand::ReverseForwardTcp;
+                extra_args = cmd_args[3..].to_vec();
+            },
+            "forward-http" => {
+                /* hopo forward-http <shell> <local port> <remote base url> */
+                shell_name = Some(cmd_args[2].clone());
+                command = ArgsCommand::ForwardHttp;
+                extra_args = cmd_args[3..].to_vec();
+            },
+            "socks" => {
+                /* hopo socks <shell> <local port> */
+                shell_name = Some(cmd_args[2].clone());
+                command = ArgsCommand::Socks;
+                extra_args = cmd_args[3..].to_vec();
+            },
+            "forward" => {
+                /* hopo forward [start|list|status|add|remove|enable|disable] ..., each forward has its own shell */
+                command = ArgsCommand::Forward;
+                extra_args = cmd_args[2..].to_vec();
+            },
             _ => {
                 shell_name = Some(cmd_args[1].clone());
                 command = ArgsCommand::Connect;
@@ -188,6 +245,9 @@ pub fn parse_args() -> Args {
             Err(_) => false
         },
         command_timeout: Duration::from_secs(60),
+        script_timeout: Duration::from_secs(60),
+        script_max_timeout: Duration::from_secs(600),
+        allow_remote_bind: false,
         extra_args,
         format: OutputFormat::Text,
         working_dir: env::current_dir().unwrap().to_str().unwrap().to_string()
@@ -265,6 +325,24 @@ pub fn parse_args() -> Args {
         args.command_timeout = parse_duration_from_ms_str(command_timeout_ms_str);
     }
     
+    let script_timeout_ms_str = env::var("SCRIPT_TIMEOUT");
+    if let Ok(script_timeout_ms_str) = script_timeout_ms_str {
+        args.script_timeout = parse_duration_from_ms_str(script_timeout_ms_str);
+    }
+    
+    let script_max_timeout_ms_str = env::var("SCRIPT_MAX_TIMEOUT");
+    if let Ok(script_max_timeout_ms_str) = script_max_timeout_ms_str {
+        args.script_max_timeout = parse_duration_from_ms_str(script_max_timeout_ms_str);
+    }
+    
+    let allow_remote_bind_str = env::var("ALLOW_REMOTE_BIND");
+    if let Ok(allow_remote_bind_str) = allow_remote_bind_str {
+        args.allow_remote_bind = match allow_remote_bind_str.to_lowercase().as_str() {
+            "yes" | "true" | "1" => true,
+            _ => false
+        };
+    }
+
     let working_folder = env::var("HOPOSHELL_WORKING_FOLDER");
     if let Ok(working_folder) = working_folder {
         args.working_dir = working_folder;
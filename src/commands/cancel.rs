@@ -0,0 +1,67 @@
+/**
+ * hopo command <shell_id> cancel <message_id>
+ */
+
+use std::sync::atomic::Ordering;
+
+use crate::constants::OutputFormat;
+
+use super::{request_or_response::{maybe_string, Request, make_shell_target, requester_shell_id}, response_stream::RunningRequests, command_error::make_error};
+
+pub const COMMAND_NAME: &str = "cancel";
+
+pub fn process_cancel_command(
+    req: &Request,
+    running_requests: &RunningRequests
+) -> Result<serde_json::Value, serde_json::Value> {
+    let target_message_id = maybe_string(Some(&req.payload));
+
+    if target_message_id.is_none() {
+        return Result::Err(make_error("No message id provided"));
+    }
+    let target_message_id = target_message_id.unwrap();
+
+    /* A shell can only cancel its own requests */
+    if requester_shell_id(&target_message_id) != requester_shell_id(&req.message_id) {
+        return Result::Err(make_error(format!("Request {} was not sent by the same shell", target_message_id).as_str()));
+    }
+
+    match running_requests.lock().unwrap().get(&target_message_id) {
+        Some(cancelled) => {
+            eprintln!("[{}] Cancel request {}", req.message_id, target_message_id);
+            cancelled.store(true, Ordering::SeqCst);
+        },
+        None => {
+            return Result::Err(make_error(format!("Request {} is not running", target_message_id).as_str()));
+        }
+    }
+
+    return Result::Ok(serde_json::json!({
+        "cancelled": target_message_id
+    }));
+}
+
+pub fn process_cancel_response(response_payload: &[u8], format: OutputFormat) {
+    match format {
+        OutputFormat::Json => {
+            println!("{}", String::from_utf8_lossy(response_payload));
+        },
+        _ => {
+            let response: serde_json::Value = serde_json::from_slice(response_payload).unwrap_or(serde_json::Value::Null);
+            match response.get("cancelled") {
+                Some(message_id) => eprintln!("Cancelled request {}", message_id.as_str().unwrap_or("")),
+                None => eprintln!("Unexpected cancel response")
+            }
+        }
+    }
+}
+
+pub fn make_cancel_request(make_id: impl Fn() -> String, shell_id: &String, target_message_id: &String) -> Request {
+    let payload = target_message_id.clone().into_bytes();
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    }
+}
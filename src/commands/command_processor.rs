@@ -1,22 +1,57 @@
-use std::time::SystemTime;
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
 
-use crate::commands::command_error::make_error_bytes;
+use crate::{args::Args, commands::command_error::make_error_bytes};
 
 use super::command_history::CommandHistory;
-use super::request_or_response::{RequestOrResponse, Response, StatusCode};
-use super::{glob, ls, download, http, tcp, scripts};
+use super::request_or_response::{RequestOrResponse, Request, Response, StatusCode};
+use super::response_stream::{ResponseStream, RunningRequests, StreamSender};
+use super::{glob, ls, download, http, tcp, udp, tunnel, scripts, jobs, schedule, du, watch, cancel};
+use super::policy::PolicyFile;
+
+/// How the connected shell processes the commands it receives
+#[derive(Debug, Clone)]
+pub struct CommandSettings {
+    pub script_timeout: Duration,
+    pub script_max_timeout: Duration,
+    /* Whether clients can make the shell listen on a port */
+    pub allow_remote_bind: bool
+}
+
+impl CommandSettings {
+    pub fn from_args(args: &Args) -> CommandSettings {
+        return CommandSettings {
+            script_timeout: args.script_timeout,
+            script_max_timeout: args.script_max_timeout,
+            allow_remote_bind: args.allow_remote_bind
+        }
+    }
+}
 
 pub struct CommandProcessor {
-    history: CommandHistory
+    history: CommandHistory,
+    settings: CommandSettings,
+    tx_to_stream: StreamSender,
+    running_requests: RunningRequests,
+    tunnels: tunnel::Tunnels,
+    policy: PolicyFile
 }
 
 impl CommandProcessor {
-    pub fn new() -> CommandProcessor {
+    pub fn new(settings: CommandSettings, tx_to_stream: StreamSender) -> CommandProcessor {
         return CommandProcessor {
-            history: CommandHistory::new()
+            history: CommandHistory::new(),
+            settings,
+            tx_to_stream,
+            running_requests: Arc::new(Mutex::new(HashMap::new())),
+            tunnels: Arc::new(Mutex::new(HashMap::new())),
+            policy: PolicyFile::new()
         }
     }
 
+    fn make_response_stream(&self, req: &Request) -> ResponseStream {
+        return ResponseStream::new(req, self.tx_to_stream.clone(), self.running_requests.clone());
+    }
+
     pub fn process_msg(&mut self, msg: &Vec<u8>, hoposhell_folder: &String) -> Option<Response> {
         /* Parses and processes a command message in serialized form */
         /* (parsing is actually done inside command_history) */
@@ -27,6 +62,20 @@ impl CommandProcessor {
                 /* Got a request from the cloud or another shell */
                 /* This happens in the loop that processes incomming messages from the server */
 
+                let shell_policy = match self.policy.authorize(&req, hoposhell_folder) {
+                    Ok(shell_policy) => shell_policy,
+                    Err(reason) => {
+                        eprintln!("[{}] Denied request with command {:?}: {}", req.message_id, req.cmd, reason);
+                        return Some(Response {
+                            creation_timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+                            message_id: req.message_id,
+                            status_code: StatusCode::Forbidden,
+                            cmd: req.cmd,
+                            payload: make_error_bytes(reason.as_str())
+                        });
+                    }
+                };
+
                 let response_payload = match req.cmd.as_str() {
                     ls::COMMAND_NAME => match ls::process_ls_command(&req.payload) {
                         Ok(payload) => Result::Ok(payload.to_string().as_bytes().to_vec()),
@@ -40,13 +89,57 @@ impl CommandProcessor {
                         Err(payload) => Result::Err(payload.to_string().as_bytes().to_vec())
                     },
                     http::COMMAND_NAME => {
-                        http::process_http_command(&req.payload)
+                        /* The redirections are checked against the policy too */
+                        http::process_http_command(&req.payload, shell_policy.and_then(|shell_policy| shell_policy.make_redirect_check()))
                     },
                     tcp::COMMAND_NAME => {
                         tcp::process_tcp_command(&req.payload)
                     },
+                    udp::COMMAND_NAME => {
+                        udp::process_udp_command(&req.payload)
+                    },
+                    tunnel::COMMAND_NAME => {
+                        /* The bytes received by the tunnel are streamed from another thread */
+                        match tunnel::process_tunnel_command(&req, &self.tunnels, &self.settings, || self.make_response_stream(&req)) {
+                            Ok(Some(payload)) => Result::Ok(payload),
+                            Ok(None) => { return None },
+                            Err(payload) => Result::Err(payload)
+                        }
+                    },
                     scripts::COMMAND_NAME => {
-                        scripts::process_scripts_command(&req.payload, hoposhell_folder)
+                        /* Scripts are run in another thread, that sends the response when they end */
+                        match scripts::process_scripts_command(&req.payload, hoposhell_folder, &self.settings, self.make_response_stream(&req)) {
+                            Ok(Some(payload)) => Result::Ok(payload),
+                            Ok(None) => { return None },
+                            Err(payload) => Result::Err(payload)
+                        }
+                    },
+                    jobs::COMMAND_NAME => {
+                        /* The output of the jobs is streamed from another thread */
+                        match jobs::process_jobs_command(&req.payload, hoposhell_folder, self.make_response_stream(&req)) {
+                            Ok(Some(payload)) => Result::Ok(payload),
+                            Ok(None) => { return None },
+                            Err(payload) => Result::Err(payload)
+                        }
+                    },
+                    schedule::COMMAND_NAME => match schedule::process_schedule_command(&req.payload, hoposhell_folder) {
+                        Ok(payload) => Result::Ok(payload.to_string().as_bytes().to_vec()),
+                        Err(payload) => Result::Err(payload)
+                    },
+                    du::COMMAND_NAME => match du::process_du_command(&req.payload) {
+                        Ok(payload) => Result::Ok(payload.to_string().as_bytes().to_vec()),
+                        Err(payload) => Result::Err(payload.to_string().as_bytes().to_vec())
+                    },
+                    watch::COMMAND_NAME => {
+                        /* The response is streamed from another thread */
+                        match watch::process_watch_command(&req.payload, self.make_response_stream(&req)) {
+                            Ok(_) => { return None },
+                            Err(payload) => Result::Err(payload)
+                        }
+                    },
+                    cancel::COMMAND_NAME => match cancel::process_cancel_command(&req, &self.running_requests) {
+                        Ok(payload) => Result::Ok(payload.to_string().as_bytes().to_vec()),
+                        Err(payload) => Result::Err(payload.to_string().as_bytes().to_vec())
                     },
                     _ => {
                         eprintln!("[{}] Got request with unknown command: {:?}", req.message_id, req.cmd);
@@ -0,0 +1,169 @@
+/**
+ * Cron expressions with the 5 usual fields: minute hour day-of-month month day-of-week
+ * Each field accepts a star, values, ranges (1-5), steps (every 15 minutes: star/15, or 0-30/10) and lists (1,15).
+ * Days of the week go from 0 (sunday) to 7 (sunday again).
+ * Also accepts @yearly, @monthly, @weekly, @daily and @hourly.
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /* When both days are restricted, cron runs when either matches */
+    any_day_of_month: bool,
+    any_day_of_week: bool
+}
+
+impl CronExpression {
+    pub fn parse(expression: &str) -> Result<CronExpression, String> {
+        let expression = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            expression => expression
+        };
+
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Invalid cron expression {}: expected 5 fields", expression));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7)?;
+        /* 7 is sunday too */
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        return Ok(CronExpression {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days_of_month: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            days_of_week,
+            /* Like in standard cron, a day field starting with a star, steps included, does not make the days match with OR */
+            any_day_of_month: fields[2].starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*')
+        });
+    }
+
+    /// Checks a local time, as given by localtime_r
+    pub fn matches(&self, time: &libc::tm) -> bool {
+        let is_set = |bits: u64, value: libc::c_int| value >= 0 && value < 64 && bits & (1 << value) != 0;
+
+        let day_of_month_matches = is_set(self.days_of_month, time.tm_mday);
+        let day_of_week_matches = is_set(self.days_of_week, time.tm_wday);
+        /* Either day field matches when both are restricted, otherwise both have to */
+        let day_matches = if self.any_day_of_month || self.any_day_of_week {
+            day_of_month_matches && day_of_week_matches
+        } else {
+            day_of_month_matches || day_of_week_matches
+        };
+
+        return day_matches
+            && is_set(self.minutes, time.tm_min)
+            && is_set(self.hours, time.tm_hour)
+            && is_set(self.months, time.tm_mon + 1);
+    }
+}
+
+pub fn local_time(timestamp_secs: u64) -> libc::tm {
+    let timestamp_secs = timestamp_secs as libc::time_t;
+    let mut time: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&timestamp_secs, &mut time) };
+    return time;
+}
+
+/// Returns the allowed values as bits
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("Invalid cron step: {}", part))?),
+            None => (part, 1)
+        };
+        if step == 0 {
+            return Err(format!("Invalid cron step: {}", part));
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            match range.split_once('-') {
+                Some((start, end)) => (parse_value(start, min, max)?, parse_value(end, min, max)?),
+                None => {
+                    let value = parse_value(range, min, max)?;
+                    /* `5/10` means from 5 to the end */
+                    if part.contains('/') { (value, max) } else { (value, value) }
+                }
+            }
+        };
+        if start > end {
+            return Err(format!("Invalid cron range: {}", part));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    return Ok(bits);
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    return match value.parse::<u32>() {
+        Ok(value) if value >= min && value <= max => Ok(value),
+        _ => Err(format!("Invalid cron value {}: expected a number from {} to {}", value, min, max))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CronExpression;
+
+    fn make_time(min: i32, hour: i32, mday: i32, mon: i32, wday: i32) -> libc::tm {
+        let mut time: libc::tm = unsafe { std::mem::zeroed() };
+        time.tm_min = min;
+        time.tm_hour = hour;
+        time.tm_mday = mday;
+        time.tm_mon = mon - 1;
+        time.tm_wday = wday;
+        return time;
+    }
+
+    #[test]
+    fn test_cron_fields() {
+        let cron = CronExpression::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(cron.matches(&make_time(30, 9, 12, 3, 1)));
+        assert!(!cron.matches(&make_time(31, 9, 12, 3, 1)));
+        assert!(!cron.matches(&make_time(30, 18, 12, 3, 1)));
+        assert!(!cron.matches(&make_time(30, 9, 12, 3, 0)));
+
+        let cron = CronExpression::parse("0 3 1,15 * 7").unwrap();
+        assert!(cron.matches(&make_time(0, 3, 15, 6, 2)));
+        assert!(cron.matches(&make_time(0, 3, 10, 6, 0)));
+        assert!(!cron.matches(&make_time(0, 3, 10, 6, 2)));
+
+        /* Odd days that are mondays */
+        let cron = CronExpression::parse("0 0 */2 * 1").unwrap();
+        assert!(cron.matches(&make_time(0, 0, 3, 6, 1)));
+        assert!(!cron.matches(&make_time(0, 0, 4, 6, 1)));
+        assert!(!cron.matches(&make_time(0, 0, 3, 6, 2)));
+
+        assert_eq!(CronExpression::parse("@daily").unwrap(), CronExpression::parse("0 0 * * *").unwrap());
+    }
+
+    #[test]
+    fn test_invalid_cron() {
+        assert!(CronExpression::parse("* * * *").is_err());
+        assert!(CronExpression::parse("60 * * * *").is_err());
+        assert!(CronExpression::parse("*/0 * * * *").is_err());
+        assert!(CronExpression::parse("5-1 * * * *").is_err());
+        assert!(CronExpression::parse("a * * * *").is_err());
+    }
+}
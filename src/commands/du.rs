@@ -0,0 +1,224 @@
+/**
+ * hopo command <shell_id> du <remote_path> [--depth <n>] [--top <n>]
+ */
+
+use std::{ffi::CString, os::unix::prelude::OsStrExt, path::Path};
+
+use serde::{Serialize, Deserialize};
+
+use crate::constants::OutputFormat;
+
+use super::{request_or_response::{maybe_string, Request, make_shell_target}, command_error::make_error};
+
+pub const COMMAND_NAME: &str = "du";
+
+const DEFAULT_DEPTH: usize = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DuCommandRequestBody {
+    path: String,
+    depth: usize,
+    top: Option<usize>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuEntry {
+    pub name: String,
+    pub depth: usize,
+    pub size_in_bytes: u64,
+    pub file_count: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilesystemInfos {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub available_bytes: u64
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DuCommandResponseBody {
+    path: String,
+    entries: Vec<DuEntry>,
+    filesystem: Option<FilesystemInfos>
+}
+
+pub fn process_du_command(
+    payload: &[u8],
+) -> Result<serde_json::Value, serde_json::Value> {
+    let text_payload = maybe_string(Some(payload));
+    if text_payload.is_none() {
+        return Result::Err(make_error("No text payload provided"));
+    }
+    let text_payload = text_payload.unwrap();
+
+    /* Decode and validate the text payload to json */
+    let json_payload = serde_json::from_str::<DuCommandRequestBody>(text_payload.as_str());
+    if json_payload.is_err() {
+        return Result::Err(make_error(format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
+    }
+    let request_infos = json_payload.unwrap();
+
+    let root_path = String::from(shellexpand::tilde(request_infos.path.as_str()));
+    let root_path = Path::new(&root_path);
+
+    if !root_path.is_dir() {
+        return Result::Err(make_error(format!("Folder {} does not exist", root_path.to_str().unwrap()).as_str()));
+    }
+
+    let mut entries = vec![];
+    walk_folder(root_path, 0, request_infos.depth, &mut entries);
+
+    /* Biggest entries first */
+    entries.sort_by(|a, b| b.size_in_bytes.cmp(&a.size_in_bytes));
+    if let Some(top) = request_infos.top {
+        entries.truncate(top);
+    }
+
+    let response = DuCommandResponseBody {
+        path: String::from(root_path.to_str().unwrap()),
+        entries,
+        filesystem: get_filesystem_infos(root_path)
+    };
+
+    return match serde_json::to_value(response) {
+        Ok(response) => Result::Ok(response),
+        Err(e) => Result::Err(make_error(format!("Cannot serialize du response: {}", e.to_string()).as_str()))
+    };
+}
+
+/// Returns the total size and number of files inside a folder, and records
+/// one entry per sub-folder down to `max_depth`
+fn walk_folder(folder_path: &Path, depth: usize, max_depth: usize, entries: &mut Vec<DuEntry>) -> (u64, u64) {
+    let mut size_in_bytes = 0;
+    let mut file_count = 0;
+
+    match std::fs::read_dir(folder_path) {
+        Ok(files_in_folder) => {
+            for f in files_in_folder {
+                let f = match f {
+                    Err(_) => { continue },
+                    Ok(f) => f
+                };
+                /* Symbolic links are not followed */
+                let infos = match f.path().symlink_metadata() {
+                    Err(_) => { continue },
+                    Ok(infos) => infos
+                };
+                if infos.is_dir() {
+                    let (sub_size, sub_count) = walk_folder(&f.path(), depth + 1, max_depth, entries);
+                    size_in_bytes += sub_size;
+                    file_count += sub_count;
+                } else {
+                    size_in_bytes += infos.len();
+                    file_count += 1;
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!("During du, cannot read folder {:?}: {}", folder_path, e);
+        }
+    }
+
+    if depth <= max_depth {
+        entries.push(DuEntry {
+            name: match folder_path.to_str() {
+                Some(path) => String::from(path),
+                None => String::from("")
+            },
+            depth,
+            size_in_bytes,
+            file_count
+        });
+    }
+
+    return (size_in_bytes, file_count);
+}
+
+fn get_filesystem_infos(path: &Path) -> Option<FilesystemInfos> {
+    let c_path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => c_path,
+        Err(_) => { return None }
+    };
+
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        eprintln!("During du, cannot get filesystem infos of {:?}", path);
+        return None;
+    }
+
+    let block_size = stats.f_frsize as u64;
+    return Some(FilesystemInfos {
+        total_bytes: stats.f_blocks as u64 * block_size,
+        free_bytes: stats.f_bfree as u64 * block_size,
+        available_bytes: stats.f_bavail as u64 * block_size
+    });
+}
+
+pub fn process_du_response(response_payload: &[u8], format: OutputFormat) {
+    let response: DuCommandResponseBody = match serde_json::from_slice(response_payload) {
+        Ok(response) => response,
+        Err(_) => {
+            eprintln!("Failed to parse du response");
+            eprintln!("{}", String::from_utf8_lossy(response_payload));
+            return;
+        }
+    };
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+        },
+        _ => {
+            for entry in response.entries.iter() {
+                println!("{:>10} {:>8} {}", format_size(entry.size_in_bytes), entry.file_count, entry.name);
+            }
+            if let Some(filesystem) = response.filesystem {
+                println!(
+                    "Filesystem of {}: {} available, {} free, {} total",
+                    response.path,
+                    format_size(filesystem.available_bytes),
+                    format_size(filesystem.free_bytes),
+                    format_size(filesystem.total_bytes)
+                );
+            }
+        }
+    }
+}
+
+pub fn make_du_request(make_id: impl Fn() -> String, shell_id: &String, path: &String, depth: Option<usize>, top: Option<usize>) -> Request {
+    let du_request = DuCommandRequestBody {
+        path: path.clone(),
+        depth: depth.unwrap_or(DEFAULT_DEPTH),
+        top
+    };
+
+    let payload = serde_json::to_vec(&du_request).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    }
+}
+
+/// Formats a number of bytes with a binary unit (e.g. 1.5G)
+pub fn format_size(size_in_bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "K", "M", "G", "T", "P"];
+
+    let mut size = size_in_bytes as f64;
+    let mut unit_index = 0;
+    while size >= 1024.0 && unit_index < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit_index += 1;
+    }
+
+    if unit_index == 0 {
+        return format!("{}{}", size_in_bytes, UNITS[0]);
+    }
+    return format!("{:.1}{}", size, UNITS[unit_index]);
+}
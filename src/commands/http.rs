@@ -1,30 +1,207 @@
-use std::{collections::HashMap, io::Write};
+use std::{collections::HashMap, io::Write, time::Duration};
 
-use crate::constants::OutputFormat;
+use base64::engine::{Engine as _, general_purpose::STANDARD as BASE64};
 
-use super::{request_or_response::{maybe_string, make_shell_target, Request}, command_error::make_error_bytes};
+use crate::{constants::OutputFormat, args::{consume_arg, consume_arg_value}};
+
+use super::{request_or_response::{maybe_string, make_shell_target, Request}, command_error::make_error_bytes, json_output::make_json_output};
 
 pub const COMMAND_NAME: &str = "http";
 
+/* Same as curl --fail */
+const HTTP_FAILURE_EXIT_CODE: i32 = 22;
+/* The default of reqwest */
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+/* The default of reqwest blocking clients */
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+/* Requests run on the command thread of the shell, which they block until they end */
+const MAX_TIMEOUT_MS: u64 = 10 * 60 * 1000;
+
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "lowercase")]
-enum HttpVerb {
+pub enum HttpVerb {
     GET,
     POST,
     PUT,
-    DELETE
+    DELETE,
+    PATCH,
+    HEAD,
+    OPTIONS
+}
+
+impl HttpVerb {
+    pub fn maybe_from(v: &str) -> Option<Self> {
+        match v.to_lowercase().as_str() {
+            "get" => Some(HttpVerb::GET),
+            "post" => Some(HttpVerb::POST),
+            "put" => Some(HttpVerb::PUT),
+            "delete" => Some(HttpVerb::DELETE),
+            "patch" => Some(HttpVerb::PATCH),
+            "head" => Some(HttpVerb::HEAD),
+            "options" => Some(HttpVerb::OPTIONS),
+            _ => None
+        }
+    }
+
+    fn to_method(&self) -> reqwest::Method {
+        match self {
+            HttpVerb::GET => reqwest::Method::GET,
+            HttpVerb::POST => reqwest::Method::POST,
+            HttpVerb::PUT => reqwest::Method::PUT,
+            HttpVerb::DELETE => reqwest::Method::DELETE,
+            HttpVerb::PATCH => reqwest::Method::PATCH,
+            HttpVerb::HEAD => reqwest::Method::HEAD,
+            HttpVerb::OPTIONS => reqwest::Method::OPTIONS
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum TlsVerification {
+    #[default]
+    Full,
+    /* Accepts a valid certificate issued for another host */
+    SkipHostname,
+    /* Accepts any certificate, e.g. self-signed ones */
+    None
+}
+
+impl TlsVerification {
+    pub fn maybe_from(v: &str) -> Option<Self> {
+        match v.to_lowercase().as_str() {
+            "full" => Some(TlsVerification::Full),
+            "skip-hostname" => Some(TlsVerification::SkipHostname),
+            "none" => Some(TlsVerification::None),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyEncoding {
+    #[default]
+    Text,
+    Base64
+}
+
+/// A PKCS#12 file on the shell
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct HttpClientCertificate {
+    pub path: String,
+    #[serde(default)]
+    pub password: String
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct HttpCommandRequestBody {
-    verb: HttpVerb,
-    url: String,
-    headers: HashMap<String, String>,
-    body: Option<String>
+#[serde(rename_all = "camelCase")]
+pub struct HttpCommandRequestBody {
+    pub verb: HttpVerb,
+    pub url: String,
+    /* In this order, repeated names included */
+    #[serde(default, deserialize_with = "deserialize_headers")]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub body_encoding: BodyEncoding,
+    /* Added to the query string of the url, in this order */
+    #[serde(default)]
+    pub query: Vec<(String, String)>,
+    /* For the whole request, including the body: 30s by default, at most 10 minutes */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /* 0 to return redirections as they are, none for the default limit (10) */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_redirects: Option<usize>,
+    #[serde(default)]
+    pub tls_verification: TlsVerification,
+    /* PEM file on the shell with the certificates of additional authorities */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<HttpClientCertificate>
 }
 
+impl HttpCommandRequestBody {
+    pub fn new(verb: HttpVerb, url: String) -> HttpCommandRequestBody {
+        return HttpCommandRequestBody {
+            verb,
+            url,
+            headers: vec![],
+            body: None,
+            body_encoding: BodyEncoding::Text,
+            query: vec![],
+            timeout_ms: None,
+            max_redirects: None,
+            tls_verification: TlsVerification::Full,
+            ca_bundle: None,
+            client_cert: None
+        };
+    }
+
+    pub fn set_body_bytes(&mut self, body: Vec<u8>) {
+        match String::from_utf8(body) {
+            Ok(body) => {
+                self.body = Some(body);
+                self.body_encoding = BodyEncoding::Text;
+            },
+            Err(e) => {
+                self.body = Some(BASE64.encode(e.as_bytes()));
+                self.body_encoding = BodyEncoding::Base64;
+            }
+        }
+    }
+}
+
+/// Headers are a list of pairs, or a map for the requests of older clients
+fn deserialize_headers<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, String)>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Headers {
+        List(Vec<(String, String)>),
+        Map(HashMap<String, String>)
+    }
+    return match serde::Deserialize::deserialize(deserializer)? {
+        Headers::List(headers) => Ok(headers),
+        Headers::Map(headers) => Ok(headers.into_iter().collect())
+    };
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+pub struct HttpHeader {
+    pub name: String,
+    pub value: String
+}
+
+/// The response of the remote service, whatever its status
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HttpResponseEnvelope {
+    /* e.g. HTTP/1.1 */
+    #[serde(default)]
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    /* In the order of the response, possibly with the same name several times */
+    pub headers: Vec<HttpHeader>,
+    #[serde(with = "super::serde_base64")]
+    pub body: Vec<u8>
+}
+
+impl HttpResponseEnvelope {
+    pub fn get_header(&self, name: &str) -> Option<String> {
+        return self.headers.iter().find(|header| header.name.eq_ignore_ascii_case(name)).map(|header| header.value.clone());
+    }
+}
+
+/// Checks the url of each redirection before following it
+pub type RedirectCheck = Box<dyn Fn(&reqwest::Url) -> Result<(), String> + Send + Sync>;
+
 pub fn process_http_command(
-    payload: &[u8]
+    payload: &[u8],
+    redirect_check: Option<RedirectCheck>
 ) -> Result<Vec<u8>, Vec<u8>> {
     /* Decode the bytes payload to text */
     let text_payload = maybe_string(Some(payload));
@@ -40,96 +217,240 @@ pub fn process_http_command(
     }
     let request_infos = json_payload.unwrap();
     
-    let response = match request_infos.verb {
-        HttpVerb::GET => {
-            eprintln!("GET {}", request_infos.url.as_str());
-            reqwest::blocking::get(request_infos.url.as_str())
-        },
-        HttpVerb::POST => {
-            eprintln!("POST {}", request_infos.url.as_str());
-            reqwest::blocking::Client::new().post(request_infos.url.as_str())
-                .body(request_infos.body.unwrap_or("".to_string()))
-                .send()
-        },
-        _ => {
-            return Result::Err(make_error_bytes(format!("Unsupported http verb: {:?}", request_infos.verb).as_str()));
-        }
+    let method = request_infos.verb.to_method();
+    eprintln!("{} {}", method, request_infos.url.as_str());
+
+    let client = match make_http_client(&request_infos, redirect_check) {
+        Ok(client) => client,
+        Err(e) => { return Result::Err(make_error_bytes(e.as_str())) }
     };
 
-    match response {
-        Ok(response) => {
-            let contents = response.bytes();
+    let mut request = client.request(method, request_infos.url.as_str());
+    if !request_infos.query.is_empty() {
+        request = request.query(&request_infos.query);
+    }
+    for (name, value) in request_infos.headers.iter() {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    if let Some(body) = request_infos.body {
+        request = match request_infos.body_encoding {
+            BodyEncoding::Text => request.body(body),
+            BodyEncoding::Base64 => match BASE64.decode(body) {
+                Ok(body) => request.body(body),
+                Err(e) => { return Result::Err(make_error_bytes(format!("Invalid base64 body: {}", e).as_str())) }
+            }
+        };
+    }
+    let response = match request.send() {
+        Ok(response) => response,
+        Err(e) => { return Result::Err(make_error_bytes(format!("Cannot access url: {}", e).as_str())) }
+    };
 
-            match contents {
-                Ok(contents) => {
-                    return Result::Ok(contents.to_vec());
-                },
-                Err(_) => {
-                    return Result::Err(make_error_bytes("Cannot get response bytes"));
+    let status = response.status();
+    let response_version = response.version();
+    let headers = response.headers().iter().map(|(name, value)| HttpHeader {
+        name: name.to_string(),
+        value: String::from_utf8_lossy(value.as_bytes()).to_string()
+    }).collect();
+
+    let body = match response.bytes() {
+        Ok(body) => body.to_vec(),
+        Err(e) => { return Result::Err(make_error_bytes(format!("Cannot get response bytes: {}", e).as_str())) }
+    };
+
+    let envelope = HttpResponseEnvelope {
+        version: format!("{:?}", response_version),
+        status: status.as_u16(),
+        reason: status.canonical_reason().unwrap_or("").to_string(),
+        headers,
+        body
+    };
+
+    return Result::Ok(serde_json::to_vec(&envelope).unwrap());
+}
+
+fn make_http_client(request_infos: &HttpCommandRequestBody, redirect_check: Option<RedirectCheck>) -> Result<reqwest::blocking::Client, String> {
+    let mut builder = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_millis(request_infos.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS).min(MAX_TIMEOUT_MS)));
+
+    builder = match (request_infos.max_redirects, redirect_check) {
+        (Some(0), _) => builder.redirect(reqwest::redirect::Policy::none()),
+        (max_redirects, Some(redirect_check)) => {
+            let max_redirects = max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS);
+            builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > max_redirects {
+                    return attempt.error(format!("Too many redirects (more than {})", max_redirects));
                 }
-            }
+                return match redirect_check(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e)
+                };
+            }))
         },
-        Err(_) => {
-            return Result::Err(make_error_bytes("Cannot access url"));
-        }
+        (Some(max_redirects), None) => builder.redirect(reqwest::redirect::Policy::limited(max_redirects)),
+        (None, None) => builder
     };
-}
 
-pub fn process_http_response(response_payload: &[u8], format: OutputFormat) {
-    if format == OutputFormat::Raw {
-        std::io::stdout().write_all(response_payload).unwrap();
-        return;
-    }
+    builder = match request_infos.tls_verification {
+        TlsVerification::Full => builder,
+        TlsVerification::SkipHostname => builder.danger_accept_invalid_hostnames(true),
+        TlsVerification::None => builder.danger_accept_invalid_certs(true)
+    };
 
-    let text_payload = maybe_string(Some(response_payload));
+    if let Some(ca_bundle) = request_infos.ca_bundle.as_ref() {
+        let ca_bundle_path = String::from(shellexpand::tilde(ca_bundle));
+        let pem = std::fs::read_to_string(&ca_bundle_path).map_err(|e| format!("Cannot read CA bundle {}: {}", ca_bundle, e))?;
+        /* One certificate per PEM block */
+        const END_MARKER: &str = "-----END CERTIFICATE-----";
+        for block in pem.split_inclusive(END_MARKER).filter(|block| block.contains(END_MARKER)) {
+            let cert = reqwest::Certificate::from_pem(block.as_bytes()).map_err(|e| format!("Invalid certificate in {}: {}", ca_bundle, e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+    }
 
-    if text_payload.is_none() {
-        eprintln!("Cannot parse http payload bytes");
-        return;
+    if let Some(client_cert) = request_infos.client_cert.as_ref() {
+        let cert_path = String::from(shellexpand::tilde(&client_cert.path));
+        let der = std::fs::read(&cert_path).map_err(|e| format!("Cannot read client certificate {}: {}", client_cert.path, e))?;
+        let identity = reqwest::Identity::from_pkcs12_der(&der, &client_cert.password)
+            .map_err(|e| format!("Invalid client certificate {}: {}", client_cert.path, e))?;
+        builder = builder.identity(identity);
     }
-    let text_payload = text_payload.unwrap();
 
-    // let splitted_payload: Vec<&str> = text_payload.splitn(2, "\n\n").collect();
-    // if splitted_payload.len() != 2 {
-    //     eprintln!("Cannot split http payload");
-    //     return;
-    // }
-    // let body_payload = splitted_payload[1];
+    return builder.build().map_err(|e| format!("Cannot create http client: {}", e));
+}
 
-    if format == OutputFormat::Text {
-        println!("{}", text_payload);
-        return;
-    }
+/// Prints the body of the response, or its headers and body with `include_headers` (like curl -i).
+/// Exits with code 22 when the http status is an error (like curl --fail).
+pub fn process_http_response(response_payload: &[u8], include_headers: bool, format: OutputFormat) {
+    let envelope = match serde_json::from_slice::<HttpResponseEnvelope>(response_payload) {
+        Ok(envelope) => envelope,
+        Err(_) => {
+            eprintln!("Cannot parse http response");
+            std::process::exit(-1);
+        }
+    };
 
-    if format == OutputFormat::Json {
-        let body_json = serde_json::from_str::<serde_json::Value>(&text_payload);
-        if body_json.is_err() {
-            eprintln!("Cannot parse http payload to json");
-            return;
+    match format {
+        OutputFormat::Json => {
+            let (body, body_encoding) = make_json_output(&envelope.body, &envelope.get_header("content-type"), true);
+            println!("{}", serde_json::json!({
+                "status": envelope.status,
+                "reason": envelope.reason,
+                "headers": envelope.headers,
+                "body": body,
+                "bodyEncoding": body_encoding
+            }));
+        },
+        OutputFormat::Text | OutputFormat::Raw => {
+            let mut stdout = std::io::stdout();
+            if include_headers {
+                write!(stdout, "{} {} {}\r\n", envelope.version, envelope.status, envelope.reason).unwrap();
+                for header in envelope.headers.iter() {
+                    write!(stdout, "{}: {}\r\n", header.name, header.value).unwrap();
+                }
+                write!(stdout, "\r\n").unwrap();
+            }
+            stdout.write_all(&envelope.body).unwrap();
+            stdout.flush().unwrap();
         }
-        let body_json = body_json.unwrap();
-        println!("{}", body_json);
-        return;
     }
 
-    eprintln!("Unsupported output format");
-
+    if envelope.status >= 400 {
+        eprintln!("Http request failed: {} {}", envelope.status, envelope.reason);
+        std::process::exit(HTTP_FAILURE_EXIT_CODE);
+    }
 }
 
+/// Parses `<verb> <url> [<body>] [-H 'Name: value']... [--query name=value]... [--data <body>|@<file>] [--data-binary <body>|@<file>]
+///     [--timeout <seconds>] [--max-redirects <n>] [-k|--insecure] [--tls-verify full|skip-hostname|none]
+///     [--cacert <shell path>] [--cert <shell path>] [--cert-password <password>]`
 pub fn make_http_request(make_id: impl Fn() -> String, shell_id: &String, args: &Vec<String>) -> Request{
-    let verb = args[0].clone();
-    let url = args[1].clone();
+    let mut args = args.clone();
 
-    let body = if args.len() > 2 { Some(args[2].clone()) } else { None };
+    let exit_with_error = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        std::process::exit(-1);
+    };
 
-    let request_body = HttpCommandRequestBody {
-        verb: serde::Deserialize::deserialize(serde_json::Value::String(verb)).unwrap(),
-        url,
-        headers: HashMap::new(),
-        body
+    let mut headers = vec![];
+    while let Some(header) = consume_arg_value(&mut args, "-H") {
+        match header.split_once(':') {
+            Some((name, value)) => { headers.push((name.trim().to_string(), value.trim().to_string())); },
+            None => {
+                eprintln!("Invalid header {}: expected 'Name: value'", header);
+                std::process::exit(-1);
+            }
+        }
+    }
+    let mut query = vec![];
+    while let Some(param) = consume_arg_value(&mut args, "--query") {
+        match param.split_once('=') {
+            Some((name, value)) => query.push((name.to_string(), value.to_string())),
+            None => exit_with_error(format!("Invalid query parameter {}: expected name=value", param))
+        }
+    }
+    let read_data = |data: String| -> Vec<u8> {
+        match data.strip_prefix('@') {
+            Some(file_path) => std::fs::read(file_path).unwrap_or_else(|e| exit_with_error(format!("Cannot read body file {}: {}", file_path, e))),
+            None => data.into_bytes()
+        }
+    };
+    let data = consume_arg_value(&mut args, "--data").map(read_data);
+    let binary_data = consume_arg_value(&mut args, "--data-binary").map(read_data);
+
+    let timeout_ms = consume_arg_value(&mut args, "--timeout").map(|timeout| match timeout.parse::<f64>() {
+        Ok(timeout) if timeout > 0.0 => (timeout * 1000.0) as u64,
+        _ => exit_with_error(format!("Invalid timeout: {}", timeout))
+    });
+    let max_redirects = consume_arg_value(&mut args, "--max-redirects").map(|max_redirects| {
+        max_redirects.parse::<usize>().unwrap_or_else(|_| exit_with_error(format!("Invalid max redirects: {}", max_redirects)))
+    });
+    let insecure = consume_arg(&mut args, "-k") || consume_arg(&mut args, "--insecure");
+    let tls_verification = match consume_arg_value(&mut args, "--tls-verify") {
+        Some(mode) => TlsVerification::maybe_from(&mode).unwrap_or_else(|| exit_with_error(format!("Invalid tls verification: {}, expected full, skip-hostname or none", mode))),
+        None if insecure => TlsVerification::None,
+        None => TlsVerification::Full
     };
+    let ca_bundle = consume_arg_value(&mut args, "--cacert");
+    let cert_password = consume_arg_value(&mut args, "--cert-password");
+    let client_cert = consume_arg_value(&mut args, "--cert").map(|path| HttpClientCertificate {
+        path,
+        password: cert_password.unwrap_or_default()
+    });
+
+    if args.len() < 2 {
+        eprintln!("Please specify the verb and the url");
+        std::process::exit(-1);
+    }
+    let verb = HttpVerb::maybe_from(&args[0]).unwrap_or_else(|| {
+        eprintln!("Unsupported http verb: {}", args[0]);
+        std::process::exit(-1);
+    });
+    let url = args[1].clone();
+
+    let mut request_body = HttpCommandRequestBody::new(verb, url);
+    request_body.headers = headers;
+    request_body.query = query;
+    request_body.timeout_ms = timeout_ms;
+    request_body.max_redirects = max_redirects;
+    request_body.tls_verification = tls_verification;
+    request_body.ca_bundle = ca_bundle;
+    request_body.client_cert = client_cert;
+
+    if args.len() > 2 {
+        request_body.body = Some(args[2].clone());
+    } else if let Some(data) = data {
+        request_body.set_body_bytes(data);
+    } else if let Some(binary_data) = binary_data {
+        request_body.body = Some(BASE64.encode(binary_data));
+        request_body.body_encoding = BodyEncoding::Base64;
+    }
+
+    return make_http_command_request(make_id, shell_id, &request_body);
+}
 
-    let payload = serde_json::to_vec(&request_body).unwrap();
+pub fn make_http_command_request(make_id: impl Fn() -> String, shell_id: &String, request_body: &HttpCommandRequestBody) -> Request {
+    let payload = serde_json::to_vec(request_body).unwrap();
 
     return Request {
         cmd: "http".to_string(),
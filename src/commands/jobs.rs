@@ -0,0 +1,574 @@
+/**
+ * hopo command <shell_id> jobs start <script_name> [--cwd <folder>] [--env <key=value>]... [--param <name=value>]... [-- <args>...]
+ * hopo command <shell_id> jobs list
+ * hopo command <shell_id> jobs status <job_id>
+ * hopo command <shell_id> jobs output <job_id> [--follow] [--offset <bytes>]
+ * hopo command <shell_id> jobs cancel <job_id>
+ *
+ * Jobs are scripts that run in the background, without timeout.
+ * Everything about a job is kept in <hoposhell folder>/jobs/<job_id>/:
+ *   - job.json: the status of the job
+ *   - output.log: the stdout and stderr of the script
+ *   - stdin: the stdin given when starting the job, if any
+ */
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{Child, Stdio},
+    os::unix::process::ExitStatusExt,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime}
+};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    make_random_id,
+    args::{consume_arg, consume_arg_value},
+    constants::{OutputFormat, JOBS_FOLDER_NAME, SCRIPTS_FOLDER_NAME, STREAM_POLL_INTERVAL_MS, STREAM_HEARTBEAT_INTERVAL_MS}
+};
+use super::{
+    request_or_response::{maybe_string, Request, make_shell_target, StatusCode},
+    response_stream::ResponseStream,
+    scripts::{self, ScriptsCommandRequestBody},
+    command_error::make_error_bytes
+};
+
+pub const COMMAND_NAME: &str = "jobs";
+
+const JOB_FILE_NAME: &str = "job.json";
+const OUTPUT_FILE_NAME: &str = "output.log";
+const STDIN_FILE_NAME: &str = "stdin";
+const JOB_ID_LENGTH: usize = 12;
+const OUTPUT_READ_SIZE: usize = 1024 * 64;
+
+/* The jobs that are waited for by this process. Also guards the writes of the job files. */
+static WAITED_JOBS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobsOperation {
+    Start,
+    List,
+    Status,
+    Output,
+    Cancel
+}
+
+impl JobsOperation {
+    pub fn maybe_from(v: &str) -> Option<Self> {
+        match v {
+            "start" => Some(JobsOperation::Start),
+            "list" => Some(JobsOperation::List),
+            "status" => Some(JobsOperation::Status),
+            "output" => Some(JobsOperation::Output),
+            "cancel" => Some(JobsOperation::Cancel),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobsCommandRequestBody {
+    pub operation: JobsOperation,
+    /* All the operations but start and list */
+    #[serde(default)]
+    pub job_id: Option<String>,
+    /* Start only. Its timeout is ignored. */
+    #[serde(default)]
+    pub script: Option<ScriptsCommandRequestBody>,
+    /* Output only */
+    #[serde(default)]
+    pub follow: bool,
+    #[serde(default)]
+    pub offset: u64
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    /* The script ended by itself, see the exit code */
+    Exited,
+    /* The script was killed by a signal, possibly after a cancel */
+    Killed,
+    /* The shell stopped while the job was running, so its end is unknown */
+    Lost
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfos {
+    pub id: String,
+    pub script: String,
+    pub args: Vec<String>,
+    pub state: JobState,
+    pub pid: u32,
+    pub started_at: u64,
+    pub ended_at: Option<u64>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub cancelled: bool,
+    /* Size of the output log */
+    #[serde(default)]
+    pub output_size: u64
+}
+
+impl JobInfos {
+    /// The exit code of the client, following the shell convention for signals
+    pub fn get_exit_code(&self) -> i32 {
+        match (self.exit_code, self.signal) {
+            (Some(exit_code), _) => exit_code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1
+        }
+    }
+}
+
+/// The pieces of the output of a job, followed by its status
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JobOutputEvent {
+    Output {
+        #[serde(with = "super::serde_base64")]
+        data: Vec<u8>
+    },
+    /* Always the last event */
+    Status {
+        job: JobInfos
+    }
+}
+
+pub fn process_jobs_command(
+    payload: &[u8],
+    hoposhell_folder: &String,
+    response_stream: ResponseStream
+) -> Result<Option<Vec<u8>>, Vec<u8>> {
+    let text_payload = maybe_string(Some(payload));
+    if text_payload.is_none() {
+        return Result::Err(make_error_bytes("No text payload provided"));
+    }
+    let text_payload = text_payload.unwrap();
+
+    let json_payload = serde_json::from_str::<JobsCommandRequestBody>(text_payload.as_str());
+    if json_payload.is_err() {
+        return Result::Err(make_error_bytes(format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
+    }
+    let request_infos = json_payload.unwrap();
+
+    let hoposhell_folder = Path::new(hoposhell_folder);
+    let jobs_folder_path = hoposhell_folder.join(JOBS_FOLDER_NAME);
+
+    let result = match request_infos.operation {
+        JobsOperation::Start => match request_infos.script.as_ref() {
+            Some(script) => start_job(hoposhell_folder, script).map(|job| Some(serde_json::to_vec(&job).unwrap())),
+            None => Err(String::from("No script provided"))
+        },
+        JobsOperation::List => list_jobs(&jobs_folder_path).map(|jobs| Some(serde_json::to_vec(&jobs).unwrap())),
+        JobsOperation::Status => get_job_folder_path(&jobs_folder_path, &request_infos.job_id)
+            .and_then(|job_folder_path| read_job(&job_folder_path))
+            .map(|job| Some(serde_json::to_vec(&job).unwrap())),
+        JobsOperation::Cancel => get_job_folder_path(&jobs_folder_path, &request_infos.job_id)
+            .and_then(|job_folder_path| cancel_job(&job_folder_path))
+            .map(|job| Some(serde_json::to_vec(&job).unwrap())),
+        JobsOperation::Output => get_job_folder_path(&jobs_folder_path, &request_infos.job_id).map(|job_folder_path| {
+            let follow = request_infos.follow;
+            let offset = request_infos.offset;
+            thread::spawn(move || {
+                stream_job_output(&job_folder_path, offset, follow, response_stream);
+            });
+            None
+        })
+    };
+
+    return result.map_err(|e| make_error_bytes(e.as_str()));
+}
+
+/// Starts a script of the scripts folder as a background job
+pub fn start_job(hoposhell_folder: &Path, script: &ScriptsCommandRequestBody) -> Result<JobInfos, String> {
+    /* The output size limit of the sandbox applies to the output file */
+    let (mut script_cmd, _) = scripts::make_script_command(&hoposhell_folder.join(SCRIPTS_FOLDER_NAME), script)?;
+
+    let jobs_folder_path = hoposhell_folder.join(JOBS_FOLDER_NAME);
+    let job_id = make_random_id(JOB_ID_LENGTH);
+    let job_folder_path = jobs_folder_path.join(&job_id);
+    std::fs::create_dir_all(&job_folder_path).map_err(|e| format!("Cannot create job folder: {}", e))?;
+
+    let output = File::create(job_folder_path.join(OUTPUT_FILE_NAME)).map_err(|e| format!("Cannot create job output: {}", e))?;
+    let output_clone = output.try_clone().map_err(|e| format!("Cannot create job output: {}", e))?;
+    script_cmd.stdout(Stdio::from(output)).stderr(Stdio::from(output_clone));
+
+    match script.stdin.as_ref() {
+        Some(stdin) => {
+            let stdin_path = job_folder_path.join(STDIN_FILE_NAME);
+            std::fs::write(&stdin_path, stdin).map_err(|e| format!("Cannot write job stdin: {}", e))?;
+            let stdin = File::open(&stdin_path).map_err(|e| format!("Cannot read job stdin: {}", e))?;
+            script_cmd.stdin(Stdio::from(stdin));
+        },
+        None => { script_cmd.stdin(Stdio::null()); }
+    }
+
+    let child = script_cmd.spawn().map_err(|e| format!("Error while executing script: {}", e))?;
+
+    let job = JobInfos {
+        id: job_id.clone(),
+        script: script.name.clone(),
+        args: script.args.clone(),
+        state: JobState::Running,
+        pid: child.id(),
+        started_at: now_secs(),
+        ended_at: None,
+        exit_code: None,
+        signal: None,
+        cancelled: false,
+        output_size: 0
+    };
+
+    {
+        let mut waited_jobs = WAITED_JOBS.lock().unwrap();
+        write_job(&job_folder_path, &job)?;
+        waited_jobs.push(job_id.clone());
+    }
+    eprintln!("Started job {} (pid {})", job_id, job.pid);
+
+    thread::spawn(move || {
+        wait_for_job(child, job_folder_path, job_id);
+    });
+
+    return Ok(job);
+}
+
+pub fn get_job(hoposhell_folder: &Path, job_id: &String) -> Result<JobInfos, String> {
+    let job_folder_path = get_job_folder_path(&hoposhell_folder.join(JOBS_FOLDER_NAME), &Some(job_id.clone()))?;
+    return read_job(&job_folder_path);
+}
+
+fn wait_for_job(mut child: Child, job_folder_path: PathBuf, job_id: String) {
+    let status = child.wait();
+
+    let mut waited_jobs = WAITED_JOBS.lock().unwrap();
+    waited_jobs.retain(|x| x != &job_id);
+
+    /* Re-read, as the job may have been cancelled meanwhile */
+    let mut job = match read_job_file(&job_folder_path) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("Job {} ended but its status is lost: {}", job_id, e);
+            return;
+        }
+    };
+    job.ended_at = Some(now_secs());
+    match status {
+        Ok(status) => {
+            job.state = if status.signal().is_some() { JobState::Killed } else { JobState::Exited };
+            job.exit_code = status.code();
+            job.signal = status.signal();
+        },
+        Err(e) => {
+            eprintln!("Error while waiting for job {}: {}", job_id, e);
+            job.state = JobState::Lost;
+        }
+    }
+    eprintln!("Job {} ended: {:?} (exit code: {:?}, signal: {:?})", job_id, job.state, job.exit_code, job.signal);
+
+    if let Err(e) = write_job(&job_folder_path, &job) {
+        eprintln!("{}", e);
+    }
+}
+
+fn cancel_job(job_folder_path: &Path) -> Result<JobInfos, String> {
+    let waited_jobs = WAITED_JOBS.lock().unwrap();
+
+    let mut job = read_job_file(job_folder_path)?;
+    if job.state != JobState::Running {
+        return Err(format!("Job {} is not running", job.id));
+    }
+    /* After a restart of the shell, the pid may belong to another process by now */
+    if !waited_jobs.contains(&job.id) {
+        job.state = JobState::Lost;
+        write_job(job_folder_path, &job)?;
+        return Err(format!("Job {} was not started by this shell process, its end is unknown", job.id));
+    }
+
+    /* The script and its children */
+    eprintln!("Cancel job {} (pid {})", job.id, job.pid);
+    unsafe { libc::kill(-(job.pid as libc::pid_t), libc::SIGTERM) };
+    job.cancelled = true;
+    write_job(job_folder_path, &job)?;
+
+    return Ok(job);
+}
+
+fn list_jobs(jobs_folder_path: &Path) -> Result<Vec<JobInfos>, String> {
+    if !jobs_folder_path.exists() {
+        return Ok(vec![]);
+    }
+    let files_in_folder = std::fs::read_dir(jobs_folder_path).map_err(|e| format!("Cannot read jobs folder: {}", e))?;
+
+    let mut jobs = vec![];
+    for f in files_in_folder {
+        let f = match f {
+            Err(_) => { continue },
+            Ok(f) => f
+        };
+        if !f.path().is_dir() { continue; }
+        match read_job(&f.path()) {
+            Ok(job) => jobs.push(job),
+            Err(e) => eprintln!("Skip job folder {:?}: {}", f.path(), e)
+        }
+    }
+    jobs.sort_by_key(|job| job.started_at);
+
+    return Ok(jobs);
+}
+
+/// Reads the status of a job, detecting the ones that were running when the shell stopped
+fn read_job(job_folder_path: &Path) -> Result<JobInfos, String> {
+    let waited_jobs = WAITED_JOBS.lock().unwrap();
+
+    let mut job = read_job_file(job_folder_path)?;
+    if job.state == JobState::Running && !waited_jobs.contains(&job.id) && !is_process_alive(job.pid) {
+        job.state = JobState::Lost;
+        write_job(job_folder_path, &job)?;
+    }
+    job.output_size = job_folder_path.join(OUTPUT_FILE_NAME).metadata().map(|x| x.len()).unwrap_or(0);
+
+    return Ok(job);
+}
+
+fn stream_job_output(job_folder_path: &Path, offset: u64, follow: bool, response_stream: ResponseStream) {
+    let mut output = match File::open(job_folder_path.join(OUTPUT_FILE_NAME)) {
+        Ok(output) => output,
+        Err(e) => {
+            response_stream.fail(StatusCode::InternalError, format!("Cannot read job output: {}", e).as_str());
+            return;
+        }
+    };
+    if let Err(e) = output.seek(SeekFrom::Start(offset)) {
+        response_stream.fail(StatusCode::IncorrectParams, format!("Cannot read job output at offset {}: {}", offset, e).as_str());
+        return;
+    }
+
+    let mut buffer = vec![0u8; OUTPUT_READ_SIZE];
+    let mut last_heartbeat = Instant::now();
+    /* Reads until the end of the output once the job is over, so that nothing is missed */
+    let mut is_job_over = !follow;
+
+    let job = loop {
+        if response_stream.is_cancelled() {
+            break read_job(job_folder_path);
+        }
+
+        match output.read(&mut buffer) {
+            Ok(0) => {
+                if is_job_over {
+                    break read_job(job_folder_path);
+                }
+                match read_job(job_folder_path) {
+                    Ok(job) => { is_job_over = job.state != JobState::Running },
+                    Err(e) => { break Err(e) }
+                }
+                if !is_job_over {
+                    /* Keeps the client waiting */
+                    if last_heartbeat.elapsed() > Duration::from_millis(STREAM_HEARTBEAT_INTERVAL_MS) {
+                        response_stream.send_heartbeat();
+                        last_heartbeat = Instant::now();
+                    }
+                    thread::sleep(Duration::from_millis(STREAM_POLL_INTERVAL_MS));
+                }
+            },
+            Ok(n) => {
+                let event = JobOutputEvent::Output { data: buffer[..n].to_vec() };
+                response_stream.send_event(&serde_json::to_value(&event).unwrap());
+                last_heartbeat = Instant::now();
+            },
+            Err(e) => { break Err(format!("Cannot read job output: {}", e)) }
+        }
+    };
+
+    match job {
+        Ok(job) => response_stream.finish(Some(&serde_json::to_value(&JobOutputEvent::Status { job }).unwrap())),
+        Err(e) => response_stream.fail(StatusCode::InternalError, e.as_str())
+    };
+}
+
+/// Job ids are checked, so that they cannot point outside the jobs folder
+fn get_job_folder_path(jobs_folder_path: &Path, job_id: &Option<String>) -> Result<PathBuf, String> {
+    let job_id = match job_id {
+        Some(job_id) => job_id,
+        None => { return Err(String::from("No job id provided")) }
+    };
+    if job_id.is_empty() || !job_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid job id: {}", job_id));
+    }
+    let job_folder_path = jobs_folder_path.join(job_id);
+    if !job_folder_path.is_dir() {
+        return Err(format!("Unable to find a job with id: {}", job_id));
+    }
+    return Ok(job_folder_path);
+}
+
+fn read_job_file(job_folder_path: &Path) -> Result<JobInfos, String> {
+    let contents = std::fs::read(job_folder_path.join(JOB_FILE_NAME)).map_err(|e| format!("Cannot read job file: {}", e))?;
+    return serde_json::from_slice::<JobInfos>(&contents).map_err(|e| format!("Invalid job file: {}", e));
+}
+
+/// Writes to a temporary file first, so that readers never see a partial job file
+fn write_job(job_folder_path: &Path, job: &JobInfos) -> Result<(), String> {
+    let job_file_path = job_folder_path.join(JOB_FILE_NAME);
+    let tmp_job_file_path = job_folder_path.join(format!("{}.tmp", JOB_FILE_NAME));
+    std::fs::write(&tmp_job_file_path, serde_json::to_vec_pretty(job).unwrap()).map_err(|e| format!("Cannot write job file: {}", e))?;
+    return std::fs::rename(&tmp_job_file_path, &job_file_path).map_err(|e| format!("Cannot write job file: {}", e));
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    return unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+}
+
+fn now_secs() -> u64 {
+    return SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+}
+
+/***************** */
+
+/// Parses `start <script args>`, `list`, `status <job_id>`, `output <job_id> [--follow] [--offset <bytes>]` or `cancel <job_id>`
+pub fn parse_jobs_args(mut jobs_args: Vec<String>) -> JobsCommandRequestBody {
+    if jobs_args.is_empty() {
+        eprintln!("Please specify a jobs operation: start, list, status, output or cancel");
+        std::process::exit(-1);
+    }
+    let operation = jobs_args.remove(0);
+    let operation = JobsOperation::maybe_from(&operation).unwrap_or_else(|| {
+        eprintln!("Unknown jobs operation: {}", operation);
+        std::process::exit(-1);
+    });
+
+    let mut request_infos = JobsCommandRequestBody {
+        operation,
+        job_id: None,
+        script: None,
+        follow: false,
+        offset: 0
+    };
+
+    match operation {
+        JobsOperation::Start => {
+            request_infos.script = Some(scripts::parse_scripts_args(jobs_args, true));
+        },
+        JobsOperation::List => {},
+        _ => {
+            request_infos.follow = consume_arg(&mut jobs_args, "--follow");
+            request_infos.offset = consume_arg_value(&mut jobs_args, "--offset").map(|x| x.parse::<u64>().unwrap_or_else(|_| {
+                eprintln!("Invalid offset: {}", x);
+                std::process::exit(-1);
+            })).unwrap_or(0);
+            if jobs_args.is_empty() {
+                eprintln!("Please specify the id of the job");
+                std::process::exit(-1);
+            }
+            request_infos.job_id = Some(jobs_args.remove(0));
+        }
+    }
+
+    return request_infos;
+}
+
+pub fn make_jobs_request(make_id: impl Fn() -> String, shell_id: &String, jobs_request: &JobsCommandRequestBody) -> Request {
+    let payload = serde_json::to_vec(jobs_request).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    };
+}
+
+pub fn process_jobs_response(response_payload: &[u8], operation: JobsOperation, format: OutputFormat) {
+    if format == OutputFormat::Json {
+        println!("{}", String::from_utf8_lossy(response_payload));
+        return;
+    }
+
+    if operation == JobsOperation::List {
+        let jobs = match serde_json::from_slice::<Vec<JobInfos>>(response_payload) {
+            Ok(jobs) => jobs,
+            Err(_) => {
+                eprintln!("Failed to parse jobs list response");
+                return;
+            }
+        };
+        for job in jobs {
+            println!("{}", format_job(&job));
+        }
+        return;
+    }
+
+    let job = match serde_json::from_slice::<JobInfos>(response_payload) {
+        Ok(job) => job,
+        Err(_) => {
+            eprintln!("Failed to parse job response");
+            return;
+        }
+    };
+    match operation {
+        JobsOperation::Start => {
+            eprintln!("Started job {} (pid {})", job.id, job.pid);
+            println!("{}", job.id);
+        },
+        JobsOperation::Cancel => {
+            eprintln!("Cancelled job {}", job.id);
+        },
+        _ => {
+            println!("{}", format_job(&job));
+        }
+    }
+}
+
+fn format_job(job: &JobInfos) -> String {
+    let state = serde_json::to_value(job.state).unwrap();
+    let state = state.as_str().unwrap_or("");
+    let status = match job.state {
+        JobState::Exited => format!(" (exit code {})", job.get_exit_code()),
+        JobState::Killed => format!(" (signal {})", job.signal.unwrap_or(0)),
+        _ => String::from("")
+    };
+    let cancelled = if job.cancelled { " cancelled" } else { "" };
+    return format!("{} {}{}{} started at {} {} {}", job.id, state, status, cancelled, job.started_at, job.script, job.args.join(" "));
+}
+
+/// Prints the output of a job as it arrives. When following, exits with the status of the job.
+pub fn process_job_output_event(event: serde_json::Value, follow: bool, format: OutputFormat) {
+    let job_event = match serde_json::from_value::<JobOutputEvent>(event) {
+        Ok(job_event) => job_event,
+        Err(_) => {
+            eprintln!("Invalid response payload");
+            std::process::exit(-1);
+        }
+    };
+
+    match job_event {
+        JobOutputEvent::Output { data } => {
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::json!({ "type": "output", "data": String::from_utf8_lossy(&data) }));
+            } else {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&data).unwrap();
+                stdout.flush().unwrap();
+            }
+        },
+        JobOutputEvent::Status { job } => {
+            if format == OutputFormat::Json {
+                println!("{}", serde_json::json!({ "type": "status", "job": job }));
+            }
+            if follow && job.state != JobState::Running {
+                eprintln!("{}", format_job(&job));
+                std::process::exit(job.get_exit_code());
+            }
+        }
+    }
+}
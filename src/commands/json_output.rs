@@ -0,0 +1,45 @@
+use base64::engine::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+/// Converts some output (of a script, of an http response) for the json output format, according to its content type:
+/// - binary content types are base64-encoded
+/// - valid json is embedded as is, unless another content type is declared
+/// - other text is a string, or base64-encoded when it is not valid utf-8
+/// Returns the encoding along with the value: base64, json or text.
+pub fn make_json_output(data: &[u8], content_type: &Option<String>, can_be_json: bool) -> (serde_json::Value, &'static str) {
+    let base64_output = || (serde_json::Value::String(BASE64.encode(data)), "base64");
+
+    let content_type = content_type.as_ref().map(|x| x.split(';').next().unwrap_or("").trim().to_lowercase());
+    let is_json = content_type.as_ref().map_or(true, |x| x.ends_with("/json") || x.ends_with("+json"));
+    let is_text = content_type.as_ref().map_or(true, |x| {
+        x.starts_with("text/") || x.ends_with("/json") || x.ends_with("+json") || x.ends_with("/xml") || x.ends_with("+xml")
+    });
+
+    if !is_text {
+        return base64_output();
+    }
+    if is_json && can_be_json {
+        if let Ok(value) = serde_json::from_slice::<serde_json::Value>(data) {
+            return (value, "json");
+        }
+    }
+    return match std::str::from_utf8(data) {
+        Ok(text) => (serde_json::Value::String(text.to_string()), "text"),
+        Err(_) => base64_output()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::make_json_output;
+
+    #[test]
+    fn test_make_json_output() {
+        assert_eq!(make_json_output(b"{\"a\": 1}\n", &None, true), (serde_json::json!({ "a": 1 }), "json"));
+        assert_eq!(make_json_output(b"{\"a\": 1}\n", &None, false), (serde_json::json!("{\"a\": 1}\n"), "text"));
+        assert_eq!(make_json_output(b"not json", &Some("application/json".to_string()), true), (serde_json::json!("not json"), "text"));
+        assert_eq!(make_json_output(b"[1]", &Some("text/plain; charset=utf-8".to_string()), true), (serde_json::json!("[1]"), "text"));
+        assert_eq!(make_json_output(&[0xff, 0x00], &None, true), (serde_json::json!("/wA="), "base64"));
+        assert_eq!(make_json_output(b"abc", &Some("image/png".to_string()), true), (serde_json::json!("YWJj"), "base64"));
+    }
+}
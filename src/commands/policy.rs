@@ -0,0 +1,409 @@
+/*
+ * Which requests the shell accepts, declared in <hoposhell folder>/policy.toml,
+ * per requesting shell or for all the others with "*":
+ *     [shells.laptop]
+ *     commands = ["ls", "download", "tcp", "tunnel", "cancel", "scripts"]
+ *     paths = ["/srv/app", "~/logs"]
+ *     hosts = ["localhost:5432", "*.internal", "unix:/var/run/docker.sock"]
+ *     scripts = ["deploy.sh"]
+ *
+ *     [shells."*"]
+ *     commands = ["ls", "du"]
+ *     paths = ["/var/log"]
+ * The requesting shell is the prefix of the message id (<shell id>:<random>). It is chosen by the client that sends
+ * the request and nothing authenticates it: any client that can reach the shell can claim to be another shell.
+ * Without constraint, all the paths, hosts or scripts are allowed. Hosts are matched as requested, before any resolution,
+ * and without a port they allow all the ports. They also apply to the redirections of http requests, and to the bind
+ * address and port of the tunnels that listen on the shell, which also need ALLOW_REMOTE_BIND=yes.
+ * Paths apply to the CA bundle and client certificate files of http requests, and to the working folders of scripts, jobs and schedules too.
+ * Without policy file all the requests are accepted, and with an invalid one all the requests are denied.
+ */
+
+use std::{collections::HashMap, path::{Component, Path, PathBuf}, time::SystemTime};
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use super::{
+    request_or_response::{Request, maybe_string, requester_shell_id},
+    glob, ls, download, http, tcp, udp, tunnel, scripts, jobs, schedule, du, watch
+};
+
+const POLICY_FILE_NAME: &str = "policy.toml";
+const WILDCARD: &str = "*";
+const GLOB_CHARS: [char; 3] = ['*', '?', '['];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellPolicy {
+    /* "*" allows all the commands */
+    #[serde(default)]
+    pub commands: Vec<String>,
+    /* Roots of the files and folders of ls, glob, download, du and watch */
+    #[serde(default)]
+    pub paths: Option<Vec<String>>,
+    /* host, host:port or unix:<path> for http, tcp, udp and the tunnels */
+    #[serde(default)]
+    pub hosts: Option<Vec<String>>,
+    /* Names of the scripts run by scripts, jobs and schedule */
+    #[serde(default)]
+    pub scripts: Option<Vec<String>>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Policy {
+    #[serde(default)]
+    pub shells: HashMap<String, ShellPolicy>
+}
+
+impl Policy {
+    /// Returns the policy of the requesting shell, or fails with the reason of the denial
+    pub fn authorize(&self, req: &Request) -> Result<&ShellPolicy, String> {
+        let shell_id = requester_shell_id(&req.message_id);
+        let shell_policy = match self.shells.get(shell_id).or_else(|| self.shells.get(WILDCARD)) {
+            Some(shell_policy) => shell_policy,
+            None => { return Err(format!("No policy for shell {}", shell_id)) }
+        };
+        if !shell_policy.commands.iter().any(|cmd| cmd == WILDCARD || cmd == &req.cmd) {
+            return Err(format!("Command {} is not allowed for shell {}", req.cmd, shell_id));
+        }
+        shell_policy.check_constraints(&req.cmd, &req.payload)?;
+        return Ok(shell_policy);
+    }
+}
+
+impl ShellPolicy {
+    fn check_constraints(&self, cmd: &str, payload: &[u8]) -> Result<(), String> {
+        let json_payload = || serde_json::from_slice::<Value>(payload).map_err(|e| format!("Invalid json payload: {}", e));
+        let get_str = |json: &Value, key: &str| json[key].as_str().map(String::from);
+        /* The folder that the script of a request runs in, when it gives one */
+        let check_working_dir = |json: &Value| match get_str(json, "workingDir") {
+            Some(working_dir) => self.check_path(&working_dir),
+            None => Ok(())
+        };
+
+        return match cmd {
+            ls::COMMAND_NAME | glob::COMMAND_NAME | download::COMMAND_NAME => {
+                self.check_path(&maybe_string(Some(payload)).unwrap_or_default())
+            },
+            du::COMMAND_NAME | watch::COMMAND_NAME => {
+                self.check_path(&get_str(&json_payload()?, "path").unwrap_or_default())
+            },
+            http::COMMAND_NAME => {
+                let json = json_payload()?;
+                if let Some(ca_bundle) = get_str(&json, "caBundle") {
+                    self.check_path(&ca_bundle)?;
+                }
+                if let Some(client_cert_path) = get_str(&json["clientCert"], "path") {
+                    self.check_path(&client_cert_path)?;
+                }
+                let url = get_str(&json, "url").unwrap_or_default();
+                match reqwest::Url::parse(&url) {
+                    Ok(url) => self.check_url(&url),
+                    Err(e) => Err(format!("Invalid url {}: {}", url, e))
+                }
+            },
+            tcp::COMMAND_NAME | udp::COMMAND_NAME => {
+                let json = json_payload()?;
+                self.check_host(&get_str(&json, "host").unwrap_or_default(), json["port"].as_u64().map(|port| port as u16))
+            },
+            tunnel::COMMAND_NAME => {
+                /* The inputs go to a tunnel that was already allowed */
+                let json = json_payload()?;
+                match get_str(&json, "operation").as_deref() {
+                    Some("open") => self.check_host(&get_str(&json, "host").unwrap_or_default(), json["port"].as_u64().map(|port| port as u16)),
+                    Some("listen") => {
+                        let bind_address = get_str(&json, "host").unwrap_or(String::from(tunnel::DEFAULT_BIND_ADDRESS));
+                        self.check_host(&bind_address, json["port"].as_u64().map(|port| port as u16))
+                    },
+                    _ => Ok(())
+                }
+            },
+            scripts::COMMAND_NAME => {
+                let json = json_payload()?;
+                match get_str(&json, "operation").as_deref() {
+                    Some("list") => Ok(()),
+                    _ => {
+                        self.check_script(&get_str(&json, "name").unwrap_or_default())?;
+                        check_working_dir(&json)
+                    }
+                }
+            },
+            jobs::COMMAND_NAME => {
+                let json = json_payload()?;
+                match get_str(&json, "operation").as_deref() {
+                    Some("start") => {
+                        self.check_script(&get_str(&json["script"], "name").unwrap_or_default())?;
+                        check_working_dir(&json["script"])
+                    },
+                    _ => Ok(())
+                }
+            },
+            schedule::COMMAND_NAME => {
+                /* Triggering runs a schedule that was added with an allowed script */
+                let json = json_payload()?;
+                match get_str(&json, "operation").as_deref() {
+                    Some("add") => {
+                        self.check_script(&get_str(&json["schedule"], "name").unwrap_or_default())?;
+                        check_working_dir(&json["schedule"])
+                    },
+                    _ => Ok(())
+                }
+            },
+            _ => Ok(())
+        };
+    }
+
+    fn check_path(&self, path: &String) -> Result<(), String> {
+        let roots = match self.paths.as_ref() {
+            Some(roots) => roots,
+            None => { return Ok(()) }
+        };
+        let expanded_path = String::from(shellexpand::tilde(path.as_str()));
+        let expanded_path = Path::new(&expanded_path);
+        /* Relative paths depend on the folder of the shell */
+        if !expanded_path.is_absolute() || expanded_path.components().any(|c| c == Component::ParentDir) {
+            return Err(format!("Path {} is not allowed: only absolute paths without .. are", path));
+        }
+        let real_roots: Vec<PathBuf> = roots.iter().map(|root| resolve_path(Path::new(shellexpand::tilde(root.as_str()).as_ref()))).collect();
+        let is_allowed = |path: &Path| real_roots.iter().any(|root| resolve_path(path).starts_with(root));
+        if !is_allowed(expanded_path) {
+            return Err(format!("Path {} is not allowed", path));
+        }
+
+        /* The wildcards of ls and glob could match links to files outside of the roots */
+        if expanded_path.to_str().map_or(false, |x| x.contains(GLOB_CHARS)) {
+            let matches = ::glob::glob(expanded_path.to_str().unwrap()).map_err(|e| format!("Invalid pattern {}: {}", path, e))?;
+            if let Some(outside_path) = matches.filter_map(|x| x.ok()).find(|x| !is_allowed(x)) {
+                return Err(format!("Path {} is not allowed: it matches {}, outside of the allowed paths", path, outside_path.display()));
+            }
+        }
+        return Ok(());
+    }
+
+    fn check_url(&self, url: &reqwest::Url) -> Result<(), String> {
+        return self.check_host(url.host_str().unwrap_or(""), url.port_or_known_default());
+    }
+
+    /// Checks the redirections of the http requests, when the hosts are constrained
+    pub fn make_redirect_check(&self) -> Option<http::RedirectCheck> {
+        if self.hosts.is_none() {
+            return None;
+        }
+        let shell_policy = self.clone();
+        return Some(Box::new(move |url| shell_policy.check_url(url)));
+    }
+
+    fn check_host(&self, host: &str, port: Option<u16>) -> Result<(), String> {
+        let allowed_hosts = match self.hosts.as_ref() {
+            Some(allowed_hosts) => allowed_hosts,
+            None => { return Ok(()) }
+        };
+        if allowed_hosts.iter().any(|allowed_host| is_host_allowed(allowed_host, host, port)) {
+            return Ok(());
+        }
+        return Err(match port {
+            Some(port) if !host.starts_with(super::socket_stream::UNIX_SOCKET_PREFIX) => format!("Host {}:{} is not allowed", host, port),
+            _ => format!("Host {} is not allowed", host)
+        });
+    }
+
+    fn check_script(&self, name: &String) -> Result<(), String> {
+        return match self.scripts.as_ref() {
+            Some(allowed_scripts) if !allowed_scripts.contains(name) => Err(format!("Script {} is not allowed", name)),
+            _ => Ok(())
+        };
+    }
+}
+
+/// The real path of the longest existing part of a path, before any wildcard,
+/// so that symbolic links cannot escape the roots
+fn resolve_path(path: &Path) -> PathBuf {
+    let components: Vec<Component> = path.components().collect();
+    let literal_len = components.iter()
+        .position(|c| c.as_os_str().to_str().map_or(true, |x| x.contains(GLOB_CHARS)))
+        .unwrap_or(components.len());
+    for prefix_len in (1..=literal_len).rev() {
+        let prefix: PathBuf = components[..prefix_len].iter().collect();
+        if let Ok(real_prefix) = prefix.canonicalize() {
+            return components[prefix_len..].iter().fold(real_prefix, |real_path, c| real_path.join(c));
+        }
+    }
+    return path.to_path_buf();
+}
+
+/// Matches `host`, `host:port`, `*`, `*.domain` with or without port, and `unix:<path>`
+fn is_host_allowed(allowed_host: &str, host: &str, port: Option<u16>) -> bool {
+    if let Some(allowed_path) = super::socket_stream::unix_socket_path(allowed_host) {
+        return super::socket_stream::unix_socket_path(host) == Some(allowed_path);
+    }
+    if super::socket_stream::unix_socket_path(host).is_some() {
+        return false;
+    }
+    /* IPv6 addresses are written [::1]:port */
+    let (allowed_name, allowed_port) = match allowed_host.rsplit_once(':') {
+        Some((name, allowed_port)) if !name.ends_with(':') && (allowed_port == WILDCARD || allowed_port.parse::<u16>().is_ok()) => (name, Some(allowed_port)),
+        _ => (allowed_host, None)
+    };
+    let allowed_name = allowed_name.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+
+    let is_name_allowed = allowed_name == WILDCARD || allowed_name == host || match allowed_name.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => false
+    };
+    let is_port_allowed = match allowed_port {
+        None | Some(WILDCARD) => true,
+        Some(allowed_port) => port.map(|port| port.to_string()) == Some(allowed_port.to_string())
+    };
+    return is_name_allowed && is_port_allowed;
+}
+
+/// The policy of the hoposhell folder, read again when the file changes
+pub struct PolicyFile {
+    modified: Option<SystemTime>,
+    policy: Result<Option<Policy>, String>
+}
+
+impl PolicyFile {
+    pub fn new() -> PolicyFile {
+        return PolicyFile { modified: None, policy: Ok(None) };
+    }
+
+    /// Returns the policy of the requesting shell, none without policy file
+    pub fn authorize(&mut self, req: &Request, hoposhell_folder: &String) -> Result<Option<ShellPolicy>, String> {
+        let policy_path = Path::new(hoposhell_folder).join(POLICY_FILE_NAME);
+        let modified = std::fs::metadata(&policy_path).and_then(|metadata| metadata.modified()).ok();
+        if modified != self.modified {
+            self.modified = modified;
+            self.policy = read_policy(&policy_path);
+            match &self.policy {
+                Ok(Some(_)) => eprintln!("Loaded the policy file {}", policy_path.display()),
+                Ok(None) => eprintln!("No policy file, all the requests are accepted"),
+                Err(e) => eprintln!("{}: all the requests are denied", e)
+            }
+        }
+
+        return match &self.policy {
+            Ok(Some(policy)) => policy.authorize(req).map(|shell_policy| Some(shell_policy.clone())),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e.clone())
+        };
+    }
+}
+
+fn read_policy(policy_path: &Path) -> Result<Option<Policy>, String> {
+    if !policy_path.exists() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(policy_path).map_err(|e| format!("Cannot read policy file: {}", e))?;
+    return toml::from_str::<Policy>(&contents)
+        .map(Some)
+        .map_err(|e| format!("Invalid policy file {}: {}", POLICY_FILE_NAME, e));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, is_host_allowed};
+    use crate::commands::request_or_response::Request;
+
+    fn make_request(message_id: &str, cmd: &str, payload: &str) -> Request {
+        return Request {
+            cmd: cmd.to_string(),
+            message_id: message_id.to_string(),
+            target: "shell:target".to_string(),
+            payload: payload.as_bytes().to_vec()
+        };
+    }
+
+    #[test]
+    fn test_is_host_allowed() {
+        assert!(is_host_allowed("localhost", "localhost", Some(5432)));
+        assert!(is_host_allowed("localhost:5432", "LOCALHOST", Some(5432)));
+        assert!(!is_host_allowed("localhost:5432", "localhost", Some(5433)));
+        assert!(is_host_allowed("*.internal:*", "db.internal", Some(80)));
+        assert!(!is_host_allowed("*.internal", "internal.evil.com", Some(80)));
+        assert!(is_host_allowed("[::1]:22", "[::1]", Some(22)));
+        assert!(is_host_allowed("::1", "::1", Some(22)));
+        assert!(is_host_allowed("unix:/var/run/docker.sock", "unix:/var/run/docker.sock", None));
+        assert!(!is_host_allowed("*", "unix:/var/run/docker.sock", None));
+    }
+
+    #[test]
+    fn test_policy_authorize() {
+        let policy = toml::from_str::<Policy>(r#"
+            [shells.laptop]
+            commands = ["ls", "tcp", "scripts"]
+            paths = ["/tmp"]
+            hosts = ["localhost:5432"]
+            scripts = ["deploy.sh"]
+
+            [shells."*"]
+            commands = ["du"]
+        "#).unwrap();
+
+        assert!(policy.authorize(&make_request("laptop:abc", "ls", "/tmp/x")).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "ls", "/tmp/../etc")).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "ls", "/etc")).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "tcp", r#"{"host":"localhost","port":5432,"payload":[]}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "tcp", r#"{"host":"localhost","port":22,"payload":[]}"#)).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "scripts", r#"{"name":"deploy.sh"}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "scripts", r#"{"name":"rm.sh"}"#)).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "scripts", r#"{"operation":"list"}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "download", "/tmp/x")).is_err());
+        assert!(policy.authorize(&make_request("other:abc", "du", r#"{"path":"/","depth":1}"#)).is_ok());
+        assert!(policy.authorize(&make_request("other:abc", "ls", "/tmp")).is_err());
+    }
+
+    #[test]
+    fn test_policy_symbolic_links() {
+        let folder_path = std::env::temp_dir().join(format!("hoposhell-test-policy-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(folder_path.join("allowed")).unwrap();
+        std::fs::write(folder_path.join("allowed").join("file"), "").unwrap();
+        std::os::unix::fs::symlink("/etc", folder_path.join("allowed").join("link")).unwrap();
+        let policy = toml::from_str::<Policy>(&format!(r#"
+            [shells."*"]
+            commands = ["ls", "glob", "http", "tunnel"]
+            paths = ["{}"]
+            hosts = ["localhost:8080", "example.com"]
+        "#, folder_path.join("allowed").display())).unwrap();
+        let allowed = folder_path.join("allowed").display().to_string();
+
+        assert!(policy.authorize(&make_request("laptop:abc", "ls", &format!("{}/file", allowed))).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "ls", &format!("{}/link", allowed))).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "ls", &format!("{}/link/*", allowed))).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "glob", &format!("{}/*", allowed))).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "glob", &format!("{}/f*", allowed))).is_ok());
+
+        let http_request = |body: &str| make_request("laptop:abc", "http", body);
+        assert!(policy.authorize(&http_request(r#"{"verb":"get","url":"https://example.com/a"}"#)).is_ok());
+        assert!(policy.authorize(&http_request(r#"{"verb":"get","url":"https://example.com/a","caBundle":"/etc/ssl/ca.pem"}"#)).is_err());
+        assert!(policy.authorize(&http_request(r#"{"verb":"get","url":"https://example.com/a","clientCert":{"path":"/etc/cert.p12"}}"#)).is_err());
+        let redirect_check = policy.authorize(&http_request(r#"{"verb":"get","url":"https://example.com/a"}"#)).unwrap().make_redirect_check().unwrap();
+        assert!(redirect_check(&reqwest::Url::parse("http://example.com/b").unwrap()).is_ok());
+        assert!(redirect_check(&reqwest::Url::parse("http://169.254.169.254/").unwrap()).is_err());
+
+        assert!(policy.authorize(&make_request("laptop:abc", "tunnel", r#"{"operation":"listen","port":8080}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "tunnel", r#"{"operation":"listen","host":"0.0.0.0","port":8080}"#)).is_err());
+
+        std::fs::remove_dir_all(&folder_path).unwrap();
+    }
+
+    #[test]
+    fn test_policy_script_working_dirs() {
+        let policy = toml::from_str::<Policy>(r#"
+            [shells."*"]
+            commands = ["scripts", "jobs", "schedule"]
+            paths = ["/tmp"]
+            scripts = ["deploy.sh"]
+        "#).unwrap();
+
+        assert!(policy.authorize(&make_request("laptop:abc", "scripts", r#"{"operation":"run","name":"deploy.sh","workingDir":"/tmp/app"}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "scripts", r#"{"operation":"run","name":"deploy.sh","workingDir":"/etc"}"#)).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "jobs", r#"{"operation":"start","script":{"name":"deploy.sh","workingDir":"/tmp"}}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "jobs", r#"{"operation":"start","script":{"name":"deploy.sh","workingDir":"/tmp/.."}}"#)).is_err());
+        assert!(policy.authorize(&make_request("laptop:abc", "schedule", r#"{"operation":"add","schedule":{"name":"deploy.sh","workingDir":"/tmp"}}"#)).is_ok());
+        assert!(policy.authorize(&make_request("laptop:abc", "schedule", r#"{"operation":"add","schedule":{"name":"deploy.sh","workingDir":"/root"}}"#)).is_err());
+    }
+}
@@ -32,6 +32,9 @@ impl ReqOrRes {
 pub enum StatusCode {
     Ok,
     IncorrectParams,
+    /* Denied by the policy of the shell */
+    Forbidden,
+    Timeout,
     InternalError,
 }
 
@@ -43,6 +46,8 @@ impl StatusCode {
                 Ok(code) => match code {
                     200 => Some(StatusCode::Ok),
                     400 => Some(StatusCode::IncorrectParams),
+                    403 => Some(StatusCode::Forbidden),
+                    408 => Some(StatusCode::Timeout),
                     500 => Some(StatusCode::InternalError),
                     _ => None
                 },
@@ -55,6 +60,8 @@ impl StatusCode {
         match self {
             StatusCode::Ok => b"200".to_vec(),
             StatusCode::IncorrectParams => b"400".to_vec(),
+            StatusCode::Forbidden => b"403".to_vec(),
+            StatusCode::Timeout => b"408".to_vec(),
             StatusCode::InternalError => b"500".to_vec()
         }
     }
@@ -196,6 +203,22 @@ pub struct Response {
 
 impl Response {
     pub fn chunk(self) -> Vec<ChunkedResponse> {
+        let message_id = self.message_id.clone();
+        let mut all_chunked_responses = self.chunk_partial();
+
+        eprintln!("[{}] #chunks: {:?}", message_id, all_chunked_responses.len());
+
+        let last_res = all_chunked_responses.last_mut();
+
+        if let Some(last_res) = last_res {
+            last_res.chunk_type = ChunkType::Last
+        }
+
+        return all_chunked_responses;
+    }
+
+    /// Chunks a piece of a streamed response: none of the chunks is the last one
+    pub fn chunk_partial(self) -> Vec<ChunkedResponse> {
         // Homework:
         // - return an iterator instead
         // - use a slice for the payload to avoid copying data
@@ -208,8 +231,6 @@ impl Response {
             _ => self.payload.chunks(COMMAND_PAYLOAD_SIZE).collect::<Vec<&[u8]>>()
         };
 
-        eprintln!("[{}] #chunks: {:?}", self.message_id, payload_chunks.len());
-
         for chunk in payload_chunks {
             all_chunked_responses.push(ChunkedResponse {
                 creation_timestamp: self.creation_timestamp,
@@ -221,12 +242,6 @@ impl Response {
             });
         }
 
-        let last_res = all_chunked_responses.last_mut();
-
-        if let Some(last_res) = last_res {
-            last_res.chunk_type = ChunkType::Last
-        }
-
         return all_chunked_responses;
     }
 }
@@ -436,4 +451,12 @@ mod tests {
 
 pub fn make_shell_target(shell_id: &String) -> String {
     return format!("shell:{}", shell_id)
+}
+
+/// Message ids are made of the id of the requesting shell and a random string
+pub fn requester_shell_id(message_id: &str) -> &str {
+    return match message_id.rsplit_once(':') {
+        Some((shell_id, _)) => shell_id,
+        None => ""
+    };
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    sync::mpsc::Sender,
+    time::SystemTime
+};
+
+use crate::message::{Message, MessageTypeToStream};
+
+use super::{request_or_response::{Request, Response, StatusCode}, command_error::make_error_bytes};
+
+pub type StreamSender = Arc<Mutex<Sender<Message<MessageTypeToStream>>>>;
+
+/// Cancellation flags of the requests that are still being processed, by message id
+pub type RunningRequests = Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>;
+
+pub fn send_response(tx_to_stream: &StreamSender, res: Response, partial: bool) {
+    let chunks = if partial { res.chunk_partial() } else { res.chunk() };
+    for chunk in chunks {
+        // eprintln!("- send response chunk: {} {} {:?}", chunk.cmd, chunk.message_id, chunk.chunk_type);
+        let msg = Message {
+            mtype: MessageTypeToStream::COMMAND,
+            content: Some(chunk.to_message_payload())
+        };
+        tx_to_stream.lock().unwrap().send(msg).unwrap();
+    }
+}
+
+/// A response that is sent back piece by piece while the request is being processed.
+/// Each piece is a line of json compressed in its own zstd frame: all the pieces but
+/// the last one are sent as `not-last` chunks, so that clients that wait for the whole
+/// response still get a valid (concatenated) payload.
+pub struct ResponseStream {
+    cmd: String,
+    message_id: String,
+    tx_to_stream: StreamSender,
+    cancelled: Arc<AtomicBool>,
+    running_requests: RunningRequests
+}
+
+impl ResponseStream {
+    pub fn new(req: &Request, tx_to_stream: StreamSender, running_requests: RunningRequests) -> ResponseStream {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        running_requests.lock().unwrap().insert(req.message_id.clone(), cancelled.clone());
+
+        return ResponseStream {
+            cmd: req.cmd.clone(),
+            message_id: req.message_id.clone(),
+            tx_to_stream,
+            cancelled,
+            running_requests
+        };
+    }
+
+    pub fn message_id(&self) -> &str {
+        return self.message_id.as_str();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::SeqCst);
+    }
+
+    pub fn send_event(&self, event: &serde_json::Value) {
+        self.send_compressed(format!("{}\n", event).as_bytes(), false);
+    }
+
+    /// Sends an empty piece, so that the client does not time out while nothing happens
+    pub fn send_heartbeat(&self) {
+        self.send_compressed(&[], false);
+    }
+
+    pub fn finish(self, event: Option<&serde_json::Value>) {
+        match event {
+            Some(event) => self.send_compressed(format!("{}\n", event).as_bytes(), true),
+            None => self.send_compressed(&[], true)
+        }
+    }
+
+    pub fn fail(self, status_code: StatusCode, msg: &str) {
+        eprintln!("[{}] Stream of command {:?} failed: {}", self.message_id, self.cmd, msg);
+        let res = self.make_response(status_code, make_error_bytes(msg));
+        send_response(&self.tx_to_stream, res, false);
+    }
+
+    fn send_compressed(&self, payload: &[u8], is_last: bool) {
+        match zstd::encode_all(payload, 4) {
+            Ok(payload) => {
+                let res = self.make_response(StatusCode::Ok, payload);
+                send_response(&self.tx_to_stream, res, !is_last);
+            },
+            Err(_) => {
+                eprintln!("[{}] Failed to compress response payload.", self.message_id);
+            }
+        }
+    }
+
+    fn make_response(&self, status_code: StatusCode, payload: Vec<u8>) -> Response {
+        return Response {
+            creation_timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+            cmd: self.cmd.clone(),
+            message_id: self.message_id.clone(),
+            status_code,
+            payload
+        };
+    }
+}
+
+impl Drop for ResponseStream {
+    fn drop(&mut self) {
+        self.running_requests.lock().unwrap().remove(&self.message_id);
+    }
+}
@@ -0,0 +1,149 @@
+/**
+ * Limits of the scripts run by the shell, declared in the scripts manifest,
+ * for all the scripts and/or per script:
+ *     [sandbox]
+ *     cpuSeconds = 60
+ *     maxOutputBytes = 10485760
+ *     envAllowlist = ["PATH", "HOME", "LANG"]
+ *
+ *     [scripts."deploy.sh".sandbox]
+ *     memoryBytes = 1073741824
+ *     workingDir = "/srv/app"
+ *     user = "deploy"
+ */
+
+use std::{collections::HashMap, ffi::CString, os::unix::process::CommandExt, process::Command};
+
+use serde::{Serialize, Deserialize};
+
+/* Always passed, so that parameters can be given in the environment */
+const ALLOWED_ENV_VAR_PREFIX: &str = "HOPO_PARAM_";
+/* Without an allowlist, requests cannot set the variables that change which programs
+   or libraries are run, or how shells and interpreters start */
+const DENIED_ENV_VARS: [&str; 19] = [
+    "PATH", "IFS", "ENV", "BASH_ENV", "SHELLOPTS", "BASHOPTS", "PS4", "CDPATH", "GLOBIGNORE",
+    "GCONV_PATH", "LOCPATH", "NLSPATH", "HOSTALIASES", "RESOLV_HOST_CONF",
+    "PERLLIB", "CLASSPATH", "JAVA_TOOL_OPTIONS", "_JAVA_OPTIONS", "GIT_EXEC_PATH"
+];
+const DENIED_ENV_VAR_PREFIXES: [&str; 8] = ["LD_", "DYLD_", "BASH_FUNC_", "PYTHON", "PERL5", "RUBY", "NODE_", "MALLOC_"];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptSandbox {
+    /* RLIMIT_CPU */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+    /* RLIMIT_AS */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /* RLIMIT_NOFILE */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+    /* RLIMIT_FSIZE for the files written by the script (including the output of the jobs),
+       and checked by the shell for the output sent back */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+    /* Only these variables of the shell and of the request are passed, when set.
+       Otherwise requests cannot set PATH, LD_* and the like. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_allowlist: Option<Vec<String>>,
+    /* The working folder of the script, that requests cannot change */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    /* Name or uid of the user that runs the script. Needs the shell to run as root. */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>
+}
+
+impl ScriptSandbox {
+    /// The settings of the script, completed by the defaults of the manifest
+    pub fn or(&self, defaults: &ScriptSandbox) -> ScriptSandbox {
+        return ScriptSandbox {
+            cpu_seconds: self.cpu_seconds.or(defaults.cpu_seconds),
+            memory_bytes: self.memory_bytes.or(defaults.memory_bytes),
+            open_files: self.open_files.or(defaults.open_files),
+            max_output_bytes: self.max_output_bytes.or(defaults.max_output_bytes),
+            env_allowlist: self.env_allowlist.clone().or_else(|| defaults.env_allowlist.clone()),
+            working_dir: self.working_dir.clone().or_else(|| defaults.working_dir.clone()),
+            user: self.user.clone().or_else(|| defaults.user.clone())
+        };
+    }
+
+    /// Sets the environment, working folder, user and limits of a script command.
+    /// Fails when the request asks for something that the sandbox forbids.
+    pub fn apply(&self, script_cmd: &mut Command, env: &HashMap<String, String>, working_dir: &Option<String>) -> Result<(), String> {
+        match self.env_allowlist.as_ref() {
+            Some(env_allowlist) => {
+                let is_allowed = |key: &String| key.starts_with(ALLOWED_ENV_VAR_PREFIX) || env_allowlist.contains(key);
+                if let Some(key) = env.keys().find(|key| !is_allowed(key)) {
+                    return Err(format!("Environment variable {} is not allowed for this script", key));
+                }
+                script_cmd.env_clear();
+                script_cmd.envs(std::env::vars().filter(|(key, _)| env_allowlist.contains(key)));
+                script_cmd.envs(env);
+            },
+            None => {
+                let is_denied = |key: &String| !key.starts_with(ALLOWED_ENV_VAR_PREFIX)
+                    && (DENIED_ENV_VARS.contains(&key.as_str()) || DENIED_ENV_VAR_PREFIXES.iter().any(|prefix| key.starts_with(prefix)));
+                if let Some(key) = env.keys().find(|key| is_denied(key)) {
+                    return Err(format!("Environment variable {} can only be set for scripts with an envAllowlist", key));
+                }
+                script_cmd.envs(env);
+            }
+        }
+
+        match (self.working_dir.as_ref(), working_dir.as_ref()) {
+            (Some(fixed_working_dir), Some(working_dir)) if fixed_working_dir != working_dir => {
+                return Err(format!("The working folder of this script cannot be changed from {}", fixed_working_dir));
+            },
+            (Some(working_dir), _) | (None, Some(working_dir)) => {
+                script_cmd.current_dir(String::from(shellexpand::tilde(working_dir.as_str())));
+            },
+            (None, None) => {}
+        }
+
+        if let Some(user) = self.user.as_ref() {
+            let (uid, gid) = get_user_ids(user)?;
+            script_cmd.uid(uid).gid(gid);
+        }
+
+        let limits: Vec<(libc::__rlimit_resource_t, u64)> = [
+            (libc::RLIMIT_CPU, self.cpu_seconds),
+            (libc::RLIMIT_AS, self.memory_bytes),
+            (libc::RLIMIT_NOFILE, self.open_files),
+            (libc::RLIMIT_FSIZE, self.max_output_bytes)
+        ].into_iter().filter_map(|(resource, limit)| limit.map(|limit| (resource, limit))).collect();
+
+        if !limits.is_empty() {
+            /* Runs in the child, between fork and exec: only async-signal-safe calls */
+            unsafe {
+                script_cmd.pre_exec(move || {
+                    for (resource, limit) in limits.iter() {
+                        let rlimit = libc::rlimit { rlim_cur: *limit as libc::rlim_t, rlim_max: *limit as libc::rlim_t };
+                        if libc::setrlimit(*resource, &rlimit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    return Ok(());
+                });
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Accepts a user name or a numeric uid
+fn get_user_ids(user: &String) -> Result<(u32, u32), String> {
+    let user_name = CString::new(user.as_str()).map_err(|_| format!("Invalid user: {}", user))?;
+    let passwd = unsafe {
+        match user.parse::<u32>() {
+            Ok(uid) => libc::getpwuid(uid),
+            Err(_) => libc::getpwnam(user_name.as_ptr())
+        }
+    };
+    if passwd.is_null() {
+        return Err(format!("Unknown user: {}", user));
+    }
+    return unsafe { Ok(((*passwd).pw_uid, (*passwd).pw_gid)) };
+}
@@ -0,0 +1,485 @@
+/**
+ * hopo command <shell_id> schedule list
+ * hopo command <shell_id> schedule add <schedule_id> <cron_expression> <script_name> [--cwd <folder>] [--env <key=value>]... [--param <name=value>]... [-- <args>...]
+ * hopo command <shell_id> schedule remove <schedule_id>
+ * hopo command <shell_id> schedule trigger <schedule_id>
+ *
+ * The connected shell runs the scripts of <hoposhell folder>/schedule.json at the times given by their cron expression:
+ *     { "schedules": [{ "id": "backup", "cron": "0 3 * * *", "name": "backup.sh", "args": ["--full"] }] }
+ * Each run starts a background job (see jobs.rs), so that its output and its status can be checked later.
+ * The last run of each schedule is kept in schedule.status.json, and all the runs are logged in schedule.log.
+ */
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime}
+};
+
+use serde::{Serialize, Deserialize};
+
+use crate::constants::{OutputFormat, SCRIPTS_FOLDER_NAME};
+
+use super::{
+    request_or_response::{maybe_string, Request, make_shell_target},
+    cron::{CronExpression, local_time},
+    jobs::{self, JobInfos, JobState},
+    scripts::{self, ScriptsCommandRequestBody},
+    script_catalog,
+    command_error::make_error_bytes
+};
+
+pub const COMMAND_NAME: &str = "schedule";
+
+const SCHEDULE_FILE_NAME: &str = "schedule.json";
+const SCHEDULE_STATUS_FILE_NAME: &str = "schedule.status.json";
+const SCHEDULE_LOG_FILE_NAME: &str = "schedule.log";
+
+/* Guards the schedule files, that are changed both by the scheduler and by the commands */
+static SCHEDULE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleOperation {
+    List,
+    Add,
+    Remove,
+    Trigger
+}
+
+impl ScheduleOperation {
+    pub fn maybe_from(v: &str) -> Option<Self> {
+        match v {
+            "list" => Some(ScheduleOperation::List),
+            "add" => Some(ScheduleOperation::Add),
+            "remove" => Some(ScheduleOperation::Remove),
+            "trigger" => Some(ScheduleOperation::Trigger),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledScript {
+    pub id: String,
+    pub cron: String,
+    /* The name of the script in the scripts folder */
+    pub name: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<String>
+}
+
+impl ScheduledScript {
+    fn make_script_request(&self) -> ScriptsCommandRequestBody {
+        return ScriptsCommandRequestBody {
+            name: self.name.clone(),
+            args: self.args.clone(),
+            params: self.params.clone(),
+            env: self.env.clone(),
+            working_dir: self.working_dir.clone(),
+            ..Default::default()
+        };
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ScheduleFile {
+    #[serde(default)]
+    schedules: Vec<ScheduledScript>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleTrigger {
+    Cron,
+    Manual
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleRun {
+    pub at: u64,
+    pub trigger: ScheduleTrigger,
+    /* None when the job could not be started */
+    pub job_id: Option<String>,
+    pub error: Option<String>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleInfos {
+    #[serde(flatten)]
+    pub schedule: ScheduledScript,
+    pub last_run: Option<ScheduleRun>,
+    /* The job of the last run, with its current status */
+    pub last_job: Option<JobInfos>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleCommandRequestBody {
+    pub operation: ScheduleOperation,
+    /* Remove and trigger */
+    #[serde(default)]
+    pub id: Option<String>,
+    /* Add */
+    #[serde(default)]
+    pub schedule: Option<ScheduledScript>
+}
+
+/// Runs the schedules of the hoposhell folder, at the beginning of each minute
+pub fn start_scheduler(hoposhell_folder: &String) {
+    let hoposhell_folder = hoposhell_folder.clone();
+
+    thread::spawn(move || {
+        let hoposhell_folder = Path::new(&hoposhell_folder);
+        let mut last_minute = now_secs() / 60;
+
+        loop {
+            let now = now_secs();
+            thread::sleep(Duration::from_secs(60 - now % 60));
+
+            let now = now_secs();
+            if now / 60 == last_minute { continue; }
+            last_minute = now / 60;
+
+            let schedule_file = {
+                let _lock = SCHEDULE_LOCK.lock().unwrap();
+                read_schedule_file(hoposhell_folder)
+            };
+            let schedule_file = match schedule_file {
+                Ok(schedule_file) => schedule_file,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+
+            let time = local_time(now);
+            for schedule in schedule_file.schedules.iter() {
+                match CronExpression::parse(&schedule.cron) {
+                    Ok(cron) => {
+                        if cron.matches(&time) {
+                            run_schedule(hoposhell_folder, schedule, ScheduleTrigger::Cron);
+                        }
+                    },
+                    Err(e) => eprintln!("Skip schedule {}: {}", schedule.id, e)
+                }
+            }
+        }
+    });
+}
+
+/// Starts the job of a schedule, unless the one of its last run is still running
+fn run_schedule(hoposhell_folder: &Path, schedule: &ScheduledScript, trigger: ScheduleTrigger) -> ScheduleRun {
+    let _lock = SCHEDULE_LOCK.lock().unwrap();
+
+    let mut status = read_schedule_status(hoposhell_folder);
+    let last_job = status.get(&schedule.id).and_then(|last_run| last_run.job_id.as_ref()).and_then(|job_id| jobs::get_job(hoposhell_folder, job_id).ok());
+
+    let result = match last_job {
+        Some(last_job) if last_job.state == JobState::Running => Err(format!("The job {} of the last run is still running", last_job.id)),
+        _ => jobs::start_job(hoposhell_folder, &schedule.make_script_request())
+    };
+
+    let run = ScheduleRun {
+        at: now_secs(),
+        trigger,
+        job_id: result.as_ref().ok().map(|job| job.id.clone()),
+        error: result.err()
+    };
+
+    match (&run.job_id, &run.error) {
+        (Some(job_id), _) => log_schedule_run(hoposhell_folder, &schedule.id, format!("started job {} ({:?})", job_id, trigger).as_str()),
+        (_, Some(error)) => log_schedule_run(hoposhell_folder, &schedule.id, format!("not started ({:?}): {}", trigger, error).as_str()),
+        _ => {}
+    }
+
+    status.insert(schedule.id.clone(), run.clone());
+    if let Err(e) = write_schedule_status(hoposhell_folder, &status) {
+        eprintln!("{}", e);
+    }
+
+    return run;
+}
+
+pub fn process_schedule_command(payload: &[u8], hoposhell_folder: &String) -> Result<serde_json::Value, Vec<u8>> {
+    let text_payload = maybe_string(Some(payload));
+    if text_payload.is_none() {
+        return Result::Err(make_error_bytes("No text payload provided"));
+    }
+    let text_payload = text_payload.unwrap();
+
+    let json_payload = serde_json::from_str::<ScheduleCommandRequestBody>(text_payload.as_str());
+    if json_payload.is_err() {
+        return Result::Err(make_error_bytes(format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
+    }
+    let request_infos = json_payload.unwrap();
+
+    let hoposhell_folder = Path::new(hoposhell_folder);
+
+    let result = match request_infos.operation {
+        ScheduleOperation::List => list_schedules(hoposhell_folder).map(|schedules| serde_json::to_value(schedules).unwrap()),
+        ScheduleOperation::Add => match request_infos.schedule {
+            Some(schedule) => add_schedule(hoposhell_folder, schedule).map(|schedule| serde_json::to_value(schedule).unwrap()),
+            None => Err(String::from("No schedule provided"))
+        },
+        ScheduleOperation::Remove => remove_schedule(hoposhell_folder, &request_infos.id).map(|schedule| serde_json::to_value(schedule).unwrap()),
+        ScheduleOperation::Trigger => trigger_schedule(hoposhell_folder, &request_infos.id).map(|run| serde_json::to_value(run).unwrap())
+    };
+
+    return result.map_err(|e| make_error_bytes(e.as_str()));
+}
+
+fn list_schedules(hoposhell_folder: &Path) -> Result<Vec<ScheduleInfos>, String> {
+    let _lock = SCHEDULE_LOCK.lock().unwrap();
+
+    let schedule_file = read_schedule_file(hoposhell_folder)?;
+    let mut status = read_schedule_status(hoposhell_folder);
+
+    return Ok(schedule_file.schedules.into_iter().map(|schedule| {
+        let last_run = status.remove(&schedule.id);
+        let last_job = last_run.as_ref().and_then(|last_run| last_run.job_id.as_ref()).and_then(|job_id| jobs::get_job(hoposhell_folder, job_id).ok());
+        ScheduleInfos { schedule, last_run, last_job }
+    }).collect());
+}
+
+fn add_schedule(hoposhell_folder: &Path, schedule: ScheduledScript) -> Result<ScheduledScript, String> {
+    if schedule.id.is_empty() || !schedule.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Invalid schedule id {}: use letters, digits, - and _", schedule.id));
+    }
+    CronExpression::parse(&schedule.cron)?;
+
+    /* Checks the script now, rather than at the first run */
+    let scripts = script_catalog::list_scripts(&hoposhell_folder.join(SCRIPTS_FOLDER_NAME))?;
+    match scripts.iter().find(|script| script.name == schedule.name) {
+        Some(script) => { script.resolve_params(&schedule.args, &schedule.params)?; },
+        None => { return Err(format!("Unable to find a script with name: {}", schedule.name)) }
+    }
+
+    let _lock = SCHEDULE_LOCK.lock().unwrap();
+
+    let mut schedule_file = read_schedule_file(hoposhell_folder)?;
+    if schedule_file.schedules.iter().any(|x| x.id == schedule.id) {
+        return Err(format!("Schedule {} already exists", schedule.id));
+    }
+    schedule_file.schedules.push(schedule.clone());
+    write_schedule_file(hoposhell_folder, &schedule_file)?;
+    log_schedule_run(hoposhell_folder, &schedule.id, format!("added: {} {}", schedule.cron, schedule.name).as_str());
+
+    return Ok(schedule);
+}
+
+fn remove_schedule(hoposhell_folder: &Path, id: &Option<String>) -> Result<ScheduledScript, String> {
+    let id = id.as_ref().ok_or_else(|| String::from("No schedule id provided"))?;
+
+    let _lock = SCHEDULE_LOCK.lock().unwrap();
+
+    let mut schedule_file = read_schedule_file(hoposhell_folder)?;
+    let index = schedule_file.schedules.iter().position(|x| &x.id == id).ok_or_else(|| format!("Unable to find a schedule with id: {}", id))?;
+    let schedule = schedule_file.schedules.remove(index);
+    write_schedule_file(hoposhell_folder, &schedule_file)?;
+
+    let mut status = read_schedule_status(hoposhell_folder);
+    if status.remove(id).is_some() {
+        write_schedule_status(hoposhell_folder, &status)?;
+    }
+    log_schedule_run(hoposhell_folder, id, "removed");
+
+    return Ok(schedule);
+}
+
+fn trigger_schedule(hoposhell_folder: &Path, id: &Option<String>) -> Result<ScheduleRun, String> {
+    let id = id.as_ref().ok_or_else(|| String::from("No schedule id provided"))?;
+
+    let schedule_file = {
+        let _lock = SCHEDULE_LOCK.lock().unwrap();
+        read_schedule_file(hoposhell_folder)?
+    };
+    let schedule = schedule_file.schedules.iter().find(|x| &x.id == id).ok_or_else(|| format!("Unable to find a schedule with id: {}", id))?;
+
+    let run = run_schedule(hoposhell_folder, schedule, ScheduleTrigger::Manual);
+    return match run.error {
+        Some(error) => Err(error),
+        None => Ok(run)
+    };
+}
+
+fn read_schedule_file(hoposhell_folder: &Path) -> Result<ScheduleFile, String> {
+    let schedule_file_path = hoposhell_folder.join(SCHEDULE_FILE_NAME);
+    if !schedule_file_path.exists() {
+        return Ok(ScheduleFile::default());
+    }
+    let contents = std::fs::read(&schedule_file_path).map_err(|e| format!("Cannot read schedule file: {}", e))?;
+    return serde_json::from_slice::<ScheduleFile>(&contents).map_err(|e| format!("Invalid schedule file {}: {}", SCHEDULE_FILE_NAME, e));
+}
+
+fn write_schedule_file(hoposhell_folder: &Path, schedule_file: &ScheduleFile) -> Result<(), String> {
+    let contents = serde_json::to_vec_pretty(schedule_file).unwrap();
+    return std::fs::write(hoposhell_folder.join(SCHEDULE_FILE_NAME), contents).map_err(|e| format!("Cannot write schedule file: {}", e));
+}
+
+/// The status is only informative: it is empty when it cannot be read
+fn read_schedule_status(hoposhell_folder: &Path) -> HashMap<String, ScheduleRun> {
+    return std::fs::read(hoposhell_folder.join(SCHEDULE_STATUS_FILE_NAME)).ok()
+        .and_then(|contents| serde_json::from_slice::<HashMap<String, ScheduleRun>>(&contents).ok())
+        .unwrap_or_default();
+}
+
+fn write_schedule_status(hoposhell_folder: &Path, status: &HashMap<String, ScheduleRun>) -> Result<(), String> {
+    let contents = serde_json::to_vec_pretty(status).unwrap();
+    return std::fs::write(hoposhell_folder.join(SCHEDULE_STATUS_FILE_NAME), contents).map_err(|e| format!("Cannot write schedule status file: {}", e));
+}
+
+fn log_schedule_run(hoposhell_folder: &Path, id: &String, msg: &str) {
+    let line = format!("{} [{}] {}", now_secs(), id, msg);
+    eprintln!("Schedule {}", line);
+
+    let log_file = std::fs::OpenOptions::new().create(true).append(true).open(hoposhell_folder.join(SCHEDULE_LOG_FILE_NAME));
+    match log_file {
+        Ok(mut log_file) => {
+            if let Err(e) = writeln!(log_file, "{}", line) {
+                eprintln!("Cannot write schedule log: {}", e);
+            }
+        },
+        Err(e) => eprintln!("Cannot open schedule log: {}", e)
+    }
+}
+
+fn now_secs() -> u64 {
+    return SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+}
+
+/***************** */
+
+/// Parses `list`, `add <schedule_id> <cron_expression> <script args>`, `remove <schedule_id>` or `trigger <schedule_id>`
+pub fn parse_schedule_args(mut schedule_args: Vec<String>) -> ScheduleCommandRequestBody {
+    if schedule_args.is_empty() {
+        eprintln!("Please specify a schedule operation: list, add, remove or trigger");
+        std::process::exit(-1);
+    }
+    let operation = schedule_args.remove(0);
+    let operation = ScheduleOperation::maybe_from(&operation).unwrap_or_else(|| {
+        eprintln!("Unknown schedule operation: {}", operation);
+        std::process::exit(-1);
+    });
+
+    let mut request_infos = ScheduleCommandRequestBody {
+        operation,
+        id: None,
+        schedule: None
+    };
+
+    if operation == ScheduleOperation::List {
+        return request_infos;
+    }
+
+    if schedule_args.is_empty() {
+        eprintln!("Please specify the id of the schedule");
+        std::process::exit(-1);
+    }
+    let id = schedule_args.remove(0);
+
+    if operation == ScheduleOperation::Add {
+        if schedule_args.is_empty() {
+            eprintln!("Please specify the cron expression of the schedule, e.g. \"0 3 * * *\"");
+            std::process::exit(-1);
+        }
+        let cron = schedule_args.remove(0);
+        if let Err(e) = CronExpression::parse(&cron) {
+            eprintln!("{}", e);
+            std::process::exit(-1);
+        }
+        /* Scheduled scripts have no stdin */
+        let script = scripts::parse_scripts_args(schedule_args, false);
+        request_infos.schedule = Some(ScheduledScript {
+            id,
+            cron,
+            name: script.name,
+            args: script.args,
+            params: script.params,
+            env: script.env,
+            working_dir: script.working_dir
+        });
+    } else {
+        request_infos.id = Some(id);
+    }
+
+    return request_infos;
+}
+
+pub fn make_schedule_request(make_id: impl Fn() -> String, shell_id: &String, schedule_request: &ScheduleCommandRequestBody) -> Request {
+    let payload = serde_json::to_vec(schedule_request).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    };
+}
+
+pub fn process_schedule_response(response_payload: &[u8], operation: ScheduleOperation, format: OutputFormat) {
+    if format == OutputFormat::Json {
+        println!("{}", String::from_utf8_lossy(response_payload));
+        return;
+    }
+
+    match operation {
+        ScheduleOperation::List => {
+            let schedules = match serde_json::from_slice::<Vec<ScheduleInfos>>(response_payload) {
+                Ok(schedules) => schedules,
+                Err(_) => {
+                    eprintln!("Failed to parse schedule list response");
+                    return;
+                }
+            };
+            for infos in schedules {
+                let schedule = &infos.schedule;
+                println!("{} \"{}\" {} {}", schedule.id, schedule.cron, schedule.name, schedule.args.join(" "));
+                let last_run = match infos.last_run {
+                    Some(last_run) => last_run,
+                    None => {
+                        println!("    never run");
+                        continue;
+                    }
+                };
+                match (last_run.job_id, last_run.error, infos.last_job) {
+                    (Some(job_id), _, Some(job)) => {
+                        let state = serde_json::to_value(job.state).unwrap();
+                        let status = match job.state {
+                            JobState::Exited => format!(" (exit code {})", job.get_exit_code()),
+                            JobState::Killed => format!(" (signal {})", job.signal.unwrap_or(0)),
+                            _ => String::from("")
+                        };
+                        println!("    last run at {}: job {} {}{}", last_run.at, job_id, state.as_str().unwrap_or(""), status);
+                    },
+                    (Some(job_id), _, None) => println!("    last run at {}: job {} (unknown)", last_run.at, job_id),
+                    (None, Some(error), _) => println!("    last run at {}: not started: {}", last_run.at, error),
+                    (None, None, _) => println!("    last run at {}", last_run.at)
+                }
+            }
+        },
+        ScheduleOperation::Add => eprintln!("Added schedule"),
+        ScheduleOperation::Remove => eprintln!("Removed schedule"),
+        ScheduleOperation::Trigger => {
+            match serde_json::from_slice::<ScheduleRun>(response_payload).ok().and_then(|run| run.job_id) {
+                Some(job_id) => {
+                    eprintln!("Started job {}", job_id);
+                    println!("{}", job_id);
+                },
+                None => eprintln!("Unexpected schedule response")
+            }
+        }
+    }
+}
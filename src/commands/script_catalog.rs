@@ -0,0 +1,449 @@
+/*
+ * Describes the scripts of the scripts folder, either with a header comment:
+ *     #!/bin/sh
+ *     # @description Deploys the application
+ *     # @param environment The target environment
+ *     # @param [version] The version to deploy (latest by default)
+ *     # @content-type application/json
+ * or with a sidecar manifest next to the script (e.g. deploy.sh.json):
+ *     { "description": "...", "params": [{ "name": "environment", "description": "...", "required": true }] }
+ * or in the manifest of the scripts folder (scripts.toml or scripts.json), which can also type the parameters:
+ *     [scripts."deploy.sh"]
+ *     passAs = "env"
+ *     params = [
+ *         { name = "environment", enum = ["staging", "production"] },
+ *         { name = "version", required = false, pattern = "[0-9]+\\.[0-9]+" },
+ *         { name = "replicas", type = "integer", default = "1" }
+ *     ]
+ * The manifest also limits what the scripts can do, see sandbox.rs
+ */
+
+use std::{collections::HashMap, io::{BufRead, BufReader}, os::unix::prelude::PermissionsExt, path::{Path, PathBuf}};
+
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use super::sandbox::ScriptSandbox;
+
+const SIDECAR_EXTENSION: &str = "json";
+const MANIFEST_TOML_FILE_NAME: &str = "scripts.toml";
+const MANIFEST_JSON_FILE_NAME: &str = "scripts.json";
+const MAX_HEADER_LINES: usize = 50;
+/* Parameters passed in the environment are prefixed, so that they cannot override PATH and co */
+pub const PARAM_ENV_VAR_PREFIX: &str = "HOPO_PARAM_";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PassAs {
+    /* Positional arguments, in the order of the declaration */
+    #[default]
+    Args,
+    /* Environment variables, e.g. HOPO_PARAM_VERSION */
+    Env
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptParam {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default = "default_required")]
+    pub required: bool,
+    #[serde(default, rename = "type")]
+    pub param_type: ParamType,
+    #[serde(default, rename = "enum")]
+    pub enum_values: Option<Vec<String>>,
+    /* Must match the whole value */
+    #[serde(default)]
+    pub pattern: Option<String>,
+    #[serde(default)]
+    pub default: Option<String>
+}
+
+fn default_required() -> bool {
+    return true;
+}
+
+impl ScriptParam {
+    pub fn get_env_var_name(&self) -> String {
+        let name: String = self.name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+        return format!("{}{}", PARAM_ENV_VAR_PREFIX, name);
+    }
+
+    fn validate_value(&self, value: &String) -> Result<(), String> {
+        let is_valid_type = match self.param_type {
+            ParamType::String => true,
+            ParamType::Integer => value.parse::<i64>().is_ok(),
+            ParamType::Number => value.parse::<f64>().is_ok(),
+            ParamType::Boolean => value == "true" || value == "false"
+        };
+        if !is_valid_type {
+            return Err(format!("Parameter {} must be of type {:?}, got: {}", self.name, self.param_type, value));
+        }
+
+        if let Some(enum_values) = self.enum_values.as_ref() {
+            if !enum_values.contains(value) {
+                return Err(format!("Parameter {} must be one of {}, got: {}", self.name, enum_values.join(", "), value));
+            }
+        }
+
+        if let Some(pattern) = self.pattern.as_ref() {
+            let regex = match Regex::new(format!("^(?:{})$", pattern).as_str()) {
+                Ok(regex) => regex,
+                Err(_) => { return Err(format!("Parameter {} has an invalid pattern in the scripts manifest", self.name)) }
+            };
+            if !regex.is_match(value) {
+                return Err(format!("Parameter {} must match {}, got: {}", self.name, pattern, value));
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScriptDeclaration {
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    params: Vec<ScriptParam>,
+    #[serde(default)]
+    pass_as: PassAs,
+    /* Of the stdout of the script */
+    #[serde(default)]
+    content_type: Option<String>,
+    #[serde(default)]
+    sandbox: ScriptSandbox
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct ScriptsManifest {
+    /* Applies to all the scripts, unless they override it */
+    #[serde(default)]
+    sandbox: ScriptSandbox,
+    #[serde(default)]
+    scripts: HashMap<String, ScriptDeclaration>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptInfos {
+    pub name: String,
+    pub description: Option<String>,
+    pub params: Vec<ScriptParam>,
+    #[serde(default)]
+    pub pass_as: PassAs,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub sandbox: ScriptSandbox,
+    pub executable: bool
+}
+
+impl ScriptInfos {
+    /// Matches the arguments of a call (positional ones first, in the order of the declaration,
+    /// then named ones) with the declared parameters, and validates their values.
+    /// Returns None when no parameter is declared: the arguments are then passed as is.
+    pub fn resolve_params(&self, args: &Vec<String>, named_params: &HashMap<String, String>) -> Result<Option<Vec<(ScriptParam, Option<String>)>>, String> {
+        if self.params.is_empty() {
+            if !named_params.is_empty() {
+                return Err(format!("Script {} does not declare any parameter", self.name));
+            }
+            return Ok(None);
+        }
+        if args.len() > self.params.len() {
+            return Err(format!("Script {} takes at most {} parameters, got {}", self.name, self.params.len(), args.len()));
+        }
+        if let Some(unknown_name) = named_params.keys().find(|name| !self.params.iter().any(|p| &p.name == *name)) {
+            return Err(format!("Script {} has no parameter {}", self.name, unknown_name));
+        }
+
+        let mut missing = vec![];
+        let mut resolved_params = vec![];
+        for (i, param) in self.params.iter().enumerate() {
+            let value = match (args.get(i), named_params.get(&param.name)) {
+                (Some(_), Some(_)) => { return Err(format!("Parameter {} is given twice", param.name)) },
+                (Some(value), None) | (None, Some(value)) => Some(value.clone()),
+                (None, None) => param.default.clone()
+            };
+            match value.as_ref() {
+                Some(value) => param.validate_value(value)?,
+                None => if param.required { missing.push(param.name.as_str()); }
+            }
+            resolved_params.push((param.clone(), value));
+        }
+
+        if !missing.is_empty() {
+            return Err(format!("Script {} is missing parameters: {}", self.name, missing.join(", ")));
+        }
+        return Ok(Some(resolved_params));
+    }
+}
+
+pub fn list_scripts(script_folder_path: &Path) -> Result<Vec<ScriptInfos>, String> {
+    let files_in_folder = match std::fs::read_dir(script_folder_path) {
+        Ok(files_in_folder) => files_in_folder,
+        Err(e) => { return Err(format!("Cannot read scripts folder: {}", e)) }
+    };
+
+    let mut all_file_paths: Vec<PathBuf> = vec![];
+    for f in files_in_folder {
+        let f = match f {
+            Err(_) => { continue },
+            Ok(f) => f
+        };
+        let file_path = f.path();
+        if !file_path.is_file() { continue; }
+        let file_name = match file_path.file_name().and_then(|x| x.to_str()) {
+            Some(file_name) => file_name,
+            None => { continue }
+        };
+        if file_name.starts_with('.') || file_name == MANIFEST_TOML_FILE_NAME || file_name == MANIFEST_JSON_FILE_NAME { continue; }
+        all_file_paths.push(file_path);
+    }
+
+    let manifest = read_manifest(script_folder_path)?;
+
+    let mut scripts = vec![];
+    for file_path in all_file_paths.iter() {
+        if is_sidecar(file_path, &all_file_paths) { continue; }
+        scripts.push(make_script_infos(file_path, &manifest));
+    }
+    scripts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    return Ok(scripts);
+}
+
+pub fn get_script_infos(script_path: &Path) -> Result<ScriptInfos, String> {
+    let manifest = match script_path.parent() {
+        Some(script_folder_path) => read_manifest(script_folder_path)?,
+        None => ScriptsManifest::default()
+    };
+    return Ok(make_script_infos(script_path, &manifest));
+}
+
+fn make_script_infos(script_path: &Path, manifest: &ScriptsManifest) -> ScriptInfos {
+    let name = String::from(script_path.file_name().and_then(|x| x.to_str()).unwrap_or(""));
+    let executable = match script_path.metadata() {
+        Ok(infos) => infos.permissions().mode() & 0o111 != 0,
+        Err(_) => false
+    };
+
+    let declaration = match manifest.scripts.get(&name) {
+        Some(declaration) => declaration.clone(),
+        None => read_sidecar(script_path).unwrap_or_else(|| read_header(script_path))
+    };
+
+    return ScriptInfos {
+        name,
+        description: declaration.description,
+        params: declaration.params,
+        pass_as: declaration.pass_as,
+        content_type: declaration.content_type,
+        sandbox: declaration.sandbox.or(&manifest.sandbox),
+        executable
+    };
+}
+
+/// A broken manifest is an error, so that the constraints it declares are never ignored
+fn read_manifest(script_folder_path: &Path) -> Result<ScriptsManifest, String> {
+    let toml_path = script_folder_path.join(MANIFEST_TOML_FILE_NAME);
+    let json_path = script_folder_path.join(MANIFEST_JSON_FILE_NAME);
+
+    if toml_path.is_file() {
+        let contents = std::fs::read_to_string(&toml_path).map_err(|e| format!("Cannot read scripts manifest: {}", e))?;
+        return toml::from_str::<ScriptsManifest>(&contents).map_err(|e| format!("Invalid scripts manifest {}: {}", MANIFEST_TOML_FILE_NAME, e));
+    }
+    if json_path.is_file() {
+        let contents = std::fs::read(&json_path).map_err(|e| format!("Cannot read scripts manifest: {}", e))?;
+        return serde_json::from_slice::<ScriptsManifest>(&contents).map_err(|e| format!("Invalid scripts manifest {}: {}", MANIFEST_JSON_FILE_NAME, e));
+    }
+    return Ok(ScriptsManifest::default());
+}
+
+fn get_sidecar_path(script_path: &Path) -> PathBuf {
+    let mut sidecar_path = script_path.as_os_str().to_os_string();
+    sidecar_path.push(".");
+    sidecar_path.push(SIDECAR_EXTENSION);
+    return PathBuf::from(sidecar_path);
+}
+
+fn is_sidecar(file_path: &Path, all_file_paths: &Vec<PathBuf>) -> bool {
+    return all_file_paths.iter().any(|script_path| get_sidecar_path(script_path) == file_path);
+}
+
+fn read_sidecar(script_path: &Path) -> Option<ScriptDeclaration> {
+    let sidecar_path = get_sidecar_path(script_path);
+    if !sidecar_path.is_file() {
+        return None;
+    }
+    let contents = std::fs::read(&sidecar_path).ok()?;
+    match serde_json::from_slice::<ScriptDeclaration>(&contents) {
+        Ok(declaration) => Some(declaration),
+        Err(e) => {
+            eprintln!("Invalid script manifest {:?}: {}", sidecar_path, e);
+            None
+        }
+    }
+}
+
+fn read_header(script_path: &Path) -> ScriptDeclaration {
+    let mut declaration = ScriptDeclaration::default();
+
+    let file = match std::fs::File::open(script_path) {
+        Ok(file) => file,
+        Err(_) => { return declaration }
+    };
+
+    for line in BufReader::new(file).lines().take(MAX_HEADER_LINES) {
+        /* Stops at the first binary or non-comment line */
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => { break }
+        };
+        let line = line.trim();
+        if line.starts_with("#!") || line.is_empty() { continue; }
+        if !line.starts_with('#') { break; }
+
+        let comment = line.trim_start_matches('#').trim();
+        if let Some(description) = comment.strip_prefix("@description") {
+            declaration.description = Some(description.trim().to_string());
+        } else if let Some(content_type) = comment.strip_prefix("@content-type") {
+            declaration.content_type = Some(content_type.trim().to_string());
+        } else if let Some(param) = comment.strip_prefix("@param") {
+            let param = param.trim();
+            let (name, description) = match param.split_once(char::is_whitespace) {
+                Some((name, description)) => (name, Some(description.trim().to_string())),
+                None => (param, None)
+            };
+            let required = !(name.starts_with('[') && name.ends_with(']'));
+            declaration.params.push(ScriptParam {
+                name: name.trim_start_matches('[').trim_end_matches(']').to_string(),
+                description,
+                required,
+                param_type: ParamType::String,
+                enum_values: None,
+                pattern: None,
+                default: None
+            });
+        }
+    }
+
+    return declaration;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    fn make_scripts_folder(name: &str) -> std::path::PathBuf {
+        let folder_path = std::env::temp_dir().join(format!("hoposhell-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        return folder_path;
+    }
+
+    #[test]
+    fn test_script_infos_from_header() {
+        let folder_path = make_scripts_folder("header");
+        std::fs::write(folder_path.join("deploy.sh"), "#!/bin/sh\n# @description Deploys the app\n# @param environment The target environment\n# @param [version]\n# @content-type application/json\necho ok\n# @param ignored\n").unwrap();
+
+        let script = super::get_script_infos(&folder_path.join("deploy.sh")).unwrap();
+
+        assert_eq!(script.name, "deploy.sh");
+        assert_eq!(script.description, Some("Deploys the app".to_string()));
+        assert_eq!(script.params.len(), 2);
+        assert_eq!(script.params[0].name, "environment");
+        assert_eq!(script.params[0].description, Some("The target environment".to_string()));
+        assert!(script.params[0].required);
+        assert_eq!(script.params[1].name, "version");
+        assert!(!script.params[1].required);
+        assert_eq!(script.content_type, Some("application/json".to_string()));
+        assert!(!script.executable);
+
+        let no_params = HashMap::new();
+        assert!(script.resolve_params(&vec![], &no_params).is_err());
+        assert!(script.resolve_params(&vec!["prod".to_string()], &no_params).is_ok());
+        assert!(script.resolve_params(&vec!["prod".to_string(), "1.0".to_string()], &no_params).is_ok());
+        assert!(script.resolve_params(&vec!["prod".to_string(), "1.0".to_string(), "x".to_string()], &no_params).is_err());
+
+        std::fs::remove_dir_all(&folder_path).unwrap();
+    }
+
+    #[test]
+    fn test_list_scripts_with_sidecar() {
+        let folder_path = make_scripts_folder("sidecar");
+        std::fs::write(folder_path.join("backup"), "#!/bin/sh\n# @description From the header\n").unwrap();
+        std::fs::write(folder_path.join("backup.json"), r#"{ "description": "From the sidecar", "params": [{ "name": "target" }] }"#).unwrap();
+        std::fs::write(folder_path.join(".hidden"), "").unwrap();
+
+        let scripts = super::list_scripts(&folder_path).unwrap();
+
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(scripts[0].name, "backup");
+        assert_eq!(scripts[0].description, Some("From the sidecar".to_string()));
+        assert_eq!(scripts[0].params.len(), 1);
+        assert!(scripts[0].params[0].required);
+
+        std::fs::remove_dir_all(&folder_path).unwrap();
+    }
+
+    #[test]
+    fn test_script_params_from_manifest() {
+        let folder_path = make_scripts_folder("manifest");
+        std::fs::write(folder_path.join("deploy.sh"), "#!/bin/sh\n# @param ignored\n").unwrap();
+        std::fs::write(folder_path.join("scripts.toml"), r#"
+            [sandbox]
+            cpuSeconds = 60
+            envAllowlist = ["PATH"]
+
+            [scripts."deploy.sh".sandbox]
+            cpuSeconds = 600
+
+            [scripts."deploy.sh"]
+            passAs = "env"
+            params = [
+                { name = "environment", enum = ["staging", "production"] },
+                { name = "version", required = false, pattern = "[0-9]+\\.[0-9]+" },
+                { name = "replicas", type = "integer", default = "1" }
+            ]
+        "#).unwrap();
+
+        let scripts = super::list_scripts(&folder_path).unwrap();
+        assert_eq!(scripts.len(), 1);
+        let script = &scripts[0];
+        assert_eq!(script.pass_as, super::PassAs::Env);
+        assert_eq!(script.params.len(), 3);
+        assert_eq!(script.params[1].get_env_var_name(), "HOPO_PARAM_VERSION");
+        assert_eq!(script.sandbox.cpu_seconds, Some(600));
+        assert_eq!(script.sandbox.env_allowlist, Some(vec!["PATH".to_string()]));
+
+        let named = |params: &[(&str, &str)]| params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<String, String>>();
+        let resolved = script.resolve_params(&vec!["staging".to_string()], &named(&[("version", "1.2")])).unwrap().unwrap();
+        let values: Vec<Option<String>> = resolved.into_iter().map(|(_, value)| value).collect();
+        assert_eq!(values, vec![Some("staging".to_string()), Some("1.2".to_string()), Some("1".to_string())]);
+
+        assert!(script.resolve_params(&vec!["dev".to_string()], &named(&[])).is_err());
+        assert!(script.resolve_params(&vec!["staging".to_string()], &named(&[("version", "1.2.3")])).is_err());
+        assert!(script.resolve_params(&vec!["staging".to_string()], &named(&[("replicas", "two")])).is_err());
+        assert!(script.resolve_params(&vec!["staging".to_string()], &named(&[("environment", "staging")])).is_err());
+        assert!(script.resolve_params(&vec!["staging".to_string()], &named(&[("unknown", "x")])).is_err());
+
+        std::fs::write(folder_path.join("scripts.toml"), "[scripts").unwrap();
+        assert!(super::list_scripts(&folder_path).is_err());
+
+        std::fs::remove_dir_all(&folder_path).unwrap();
+    }
+}
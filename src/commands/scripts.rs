@@ -1,18 +1,183 @@
-use std::{io::Write};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    process::{Child, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant}
+};
 
-use crate::constants::{OutputFormat};
-use super::{request_or_response::{Request, make_shell_target, maybe_string}, command_error::make_error_bytes_with_prefix};
+use crate::{
+    args::{consume_arg, consume_arg_value},
+    constants::{OutputFormat, STREAM_POLL_INTERVAL_MS, STREAM_HEARTBEAT_INTERVAL_MS}
+};
+use super::{
+    request_or_response::{Request, make_shell_target, maybe_string, StatusCode},
+    response_stream::ResponseStream,
+    command_processor::CommandSettings,
+    script_catalog::{self, ScriptInfos, PassAs, ParamType},
+    json_output::make_json_output,
+    command_error::make_error_bytes
+};
 
 pub const COMMAND_NAME: &str = "scripts";
 
+#[derive(Debug, serde::Deserialize, serde::Serialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptsOperation {
+    #[default]
+    Run,
+    List
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptsCommandRequestBody {
+    #[serde(default)]
+    pub operation: ScriptsOperation,
+    /* Unused when listing the scripts */
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /* Parameters given by name, validated against the declaration of the script */
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+    /* Added to the environment of the shell */
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    #[serde(default, with = "super::serde_base64::option")]
+    pub stdin: Option<Vec<u8>>,
+    /* The default timeout of the shell when None */
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /* Sends the output while the script runs, as ScriptEvent, instead of a ScriptResult at the end */
+    #[serde(default)]
+    pub stream: bool
+}
+
+/// What a script did, as sent back to the client
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptResult {
+    #[serde(with = "super::serde_base64")]
+    pub stdout: Vec<u8>,
+    #[serde(with = "super::serde_base64")]
+    pub stderr: Vec<u8>,
+    /* None when the script was killed by a signal */
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+    /* Of stdout, as declared by the script */
+    #[serde(default)]
+    pub content_type: Option<String>
+}
+
+/// What a script does, as sent back to the client in streaming mode
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct ScriptsCommandRequestBody {
-    name: String
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ScriptEvent {
+    #[serde(rename_all = "camelCase")]
+    Stdout {
+        #[serde(with = "super::serde_base64")]
+        data: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>
+    },
+    Stderr {
+        #[serde(with = "super::serde_base64")]
+        data: Vec<u8>
+    },
+    /* Always the last event */
+    #[serde(rename_all = "camelCase")]
+    Exit {
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+        duration_ms: u64
+    }
 }
 
-pub fn make_scripts_request(make_id: impl Fn() -> String, shell_id: &String, name: String) -> Request{
-    let scripts_request = ScriptsCommandRequestBody { name };
+enum ScriptOutput {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>)
+}
 
+impl ScriptResult {
+    /// The exit code of the client, following the shell convention for signals
+    pub fn get_exit_code(&self) -> i32 {
+        match (self.exit_code, self.signal) {
+            (Some(exit_code), _) => exit_code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1
+        }
+    }
+}
+
+/// Parses `<name> [--cwd <folder>] [--env <key=value>]... [--param <name=value>]... [--timeout <ms>] [--stream] [--no-stdin] [-- <args>...]`
+pub fn parse_scripts_args(mut scripts_args: Vec<String>, can_read_stdin: bool) -> ScriptsCommandRequestBody {
+    let args = match scripts_args.iter().position(|x| x == "--") {
+        Some(separator_index) => {
+            let args = scripts_args.split_off(separator_index + 1);
+            scripts_args.pop();
+            args
+        },
+        None => vec![]
+    };
+    let working_dir = consume_arg_value(&mut scripts_args, "--cwd");
+    let timeout_ms = consume_arg_value(&mut scripts_args, "--timeout").map(|x| x.parse::<u64>().unwrap_or_else(|_| {
+        eprintln!("Invalid timeout: {}", x);
+        std::process::exit(-1);
+    }));
+    let stream = consume_arg(&mut scripts_args, "--stream");
+    let mut env = HashMap::new();
+    while let Some(env_var) = consume_arg_value(&mut scripts_args, "--env") {
+        match env_var.split_once('=') {
+            Some((key, value)) => { env.insert(key.to_string(), value.to_string()); },
+            None => {
+                eprintln!("Invalid environment variable {}: expected key=value", env_var);
+                std::process::exit(-1);
+            }
+        }
+    }
+    let mut params = HashMap::new();
+    while let Some(param) = consume_arg_value(&mut scripts_args, "--param") {
+        match param.split_once('=') {
+            Some((name, value)) => { params.insert(name.to_string(), value.to_string()); },
+            None => {
+                eprintln!("Invalid parameter {}: expected name=value", param);
+                std::process::exit(-1);
+            }
+        }
+    }
+    /* Forward stdin when it is piped */
+    let stdin = if consume_arg(&mut scripts_args, "--no-stdin") || !can_read_stdin || unsafe { libc::isatty(0) } == 1 { None } else {
+        let mut stdin = vec![];
+        std::io::stdin().read_to_end(&mut stdin).unwrap();
+        Some(stdin)
+    };
+
+    if scripts_args.is_empty() {
+        eprintln!("Please specify the name of the script");
+        std::process::exit(-1);
+    }
+
+    return ScriptsCommandRequestBody {
+        operation: ScriptsOperation::Run,
+        name: scripts_args.remove(0),
+        args,
+        params,
+        env,
+        working_dir,
+        stdin,
+        timeout_ms,
+        stream
+    };
+}
+
+pub fn make_scripts_request(make_id: impl Fn() -> String, shell_id: &String, scripts_request: ScriptsCommandRequestBody) -> Request{
     let payload = serde_json::to_vec(&scripts_request).unwrap();
 
     return Request {
@@ -26,80 +191,434 @@ pub fn make_scripts_request(make_id: impl Fn() -> String, shell_id: &String, nam
 pub fn process_scripts_command(
     payload: &[u8],
     hoposhell_folder: &String,
-) -> Result<Vec<u8>, Vec<u8>> {
+    settings: &CommandSettings,
+    response_stream: ResponseStream
+) -> Result<Option<Vec<u8>>, Vec<u8>> {
     let text_payload = maybe_string(Some(payload));
     if text_payload.is_none() {
-        return Result::Err(make_error_bytes_with_prefix(vec![0,0,0,1], "No text payload provided"));
+        return Result::Err(make_error_bytes("No text payload provided"));
     }
     let text_payload = text_payload.unwrap();
     
     /* Decode and validate the text payload to json */
     let json_payload = serde_json::from_str::<ScriptsCommandRequestBody>(text_payload.as_str());
     if json_payload.is_err() {
-        return Result::Err(make_error_bytes_with_prefix(vec![0,0,0,1], format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
+        return Result::Err(make_error_bytes(format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
     }
     let request_infos = json_payload.unwrap();
 
+    let script_folder_path = std::path::Path::new(hoposhell_folder).join(crate::constants::SCRIPTS_FOLDER_NAME);
+
+    if request_infos.operation == ScriptsOperation::List {
+        return match script_catalog::list_scripts(&script_folder_path) {
+            Ok(scripts) => Result::Ok(Some(serde_json::to_vec(&scripts).unwrap())),
+            Err(e) => Result::Err(make_error_bytes(e.as_str()))
+        };
+    }
+
+    let (mut script_cmd, script_infos) = match make_script_command(&script_folder_path, &request_infos) {
+        Ok(script_cmd) => script_cmd,
+        Err(e) => { return Result::Err(make_error_bytes(e.as_str())) }
+    };
+
+    /* Execute script and get stdout */
+    let start_time = Instant::now();
+    script_cmd.stdin(if request_infos.stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+    script_cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let timeout = match request_infos.timeout_ms {
+        Some(timeout_ms) => Duration::from_millis(timeout_ms).min(settings.script_max_timeout),
+        None => settings.script_timeout
+    };
+
+    let child = script_cmd.spawn();
+    if let Err(e) = child {
+        return Result::Err(make_error_bytes(format!("Error while executing script: {}", e.to_string()).as_str()));
+    }
+    let mut child = child.unwrap();
+
+    /* Feed stdin from another thread, so that the script can write its output meanwhile */
+    if let (Some(stdin), Some(mut child_stdin)) = (request_infos.stdin, child.stdin.take()) {
+        std::thread::spawn(move || {
+            if let Err(e) = child_stdin.write_all(&stdin) {
+                eprintln!("Error while writing script stdin: {}", e);
+            }
+        });
+    }
+
+    let stream = request_infos.stream;
+    thread::spawn(move || {
+        wait_for_script(child, start_time, timeout, script_infos, stream, response_stream);
+    });
+
+    return Result::Ok(None);
+}
+
+/// Finds a script of the scripts folder and prepares its execution with the parameters of a request,
+/// within the limits of its sandbox.
+/// The script runs in its own process group, so that it can be killed along with its children.
+pub fn make_script_command(script_folder_path: &std::path::Path, request_infos: &ScriptsCommandRequestBody) -> Result<(std::process::Command, ScriptInfos), String> {
     /* List files in scripts folder */
     /* I don't path.join for security reason */
-    let script_folder_path = std::path::Path::new(hoposhell_folder).join(crate::constants::SCRIPTS_FOLDER_NAME);
-    let script_path = find_file_in_folder(script_folder_path, &request_infos.name);
+    let script_path = find_file_in_folder(script_folder_path.to_path_buf(), &request_infos.name);
 
     if script_path.is_none() {
-        return Result::Err(make_error_bytes_with_prefix(vec![0,0,0,1], format!("Unable to find a script with name: {}", request_infos.name).as_str()));
+        return Result::Err(format!("Unable to find a script with name: {}", request_infos.name));
     }
     let script_path = script_path.unwrap();
 
-    eprintln!("Executing script: {}", script_path.to_str().unwrap());
-    
-    /* Execute script and get stdout */
-    let script_cmd = std::process::Command::new(script_path).output();
-    if let Err(e) = script_cmd {
-        return Result::Err(make_error_bytes_with_prefix(vec![0,0,0,1], format!("Error while executing script: {}", e.to_string()).as_str()));
-    }
-    let script_cmd = script_cmd.unwrap();
-    let mut output = script_cmd.stdout;
-    let code = script_cmd.status.code().unwrap_or_else(|| {
-        if script_cmd.status.success() { 0 } else { 1 }
-    });
-    let mut response = vec![];
-    response.append(&mut code.to_ne_bytes().to_vec());
-    response.append(&mut output);
+    /* Parameters are only given by name, so that they are validated */
+    if let Some(key) = request_infos.env.keys().find(|key| key.starts_with(script_catalog::PARAM_ENV_VAR_PREFIX)) {
+        return Result::Err(format!("Environment variable {} is reserved for the parameters of the script", key));
+    }
+
+    let script_infos = script_catalog::get_script_infos(&script_path)?;
+    let resolved_params = script_infos.resolve_params(&request_infos.args, &request_infos.params)?;
+
+    let mut script_args = vec![];
+    let mut script_env = request_infos.env.clone();
+    match resolved_params {
+        None => { script_args = request_infos.args.clone(); },
+        Some(resolved_params) => match script_infos.pass_as {
+            PassAs::Args => {
+                /* Missing optional parameters in the middle are passed as empty strings to keep the positions */
+                let last_index = resolved_params.iter().rposition(|(_, value)| value.is_some());
+                for (_, value) in resolved_params.into_iter().take(last_index.map_or(0, |i| i + 1)) {
+                    script_args.push(value.unwrap_or_default());
+                }
+            },
+            PassAs::Env => {
+                for (param, value) in resolved_params {
+                    if let Some(value) = value {
+                        script_env.insert(param.get_env_var_name(), value);
+                    }
+                }
+            }
+        }
+    }
+
+    eprintln!("Executing script: {} {:?}", script_path.to_str().unwrap(), script_args);
+
+    let mut script_cmd = std::process::Command::new(script_path);
+    script_cmd.args(&script_args);
+    script_infos.sandbox.apply(&mut script_cmd, &script_env, &request_infos.working_dir)?;
+    script_cmd.process_group(0);
+
+    return Result::Ok((script_cmd, script_infos));
+}
+
+fn wait_for_script(
+    mut child: Child,
+    start_time: Instant,
+    timeout: Duration,
+    script_infos: ScriptInfos,
+    stream: bool,
+    response_stream: ResponseStream
+) {
+    let max_output_bytes = script_infos.sandbox.max_output_bytes;
+    let content_type = script_infos.content_type;
+
+    let (tx_output, rx_output) = mpsc::channel::<ScriptOutput>();
+    let stdout_reader = read_output(child.stdout.take(), tx_output.clone(), ScriptOutput::Stdout);
+    let stderr_reader = read_output(child.stderr.take(), tx_output, ScriptOutput::Stderr);
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut output_size: u64 = 0;
+    /* Returns false once the output is too big: the rest is dropped */
+    let mut forward_output = |output: ScriptOutput| -> bool {
+        let (ScriptOutput::Stdout(data) | ScriptOutput::Stderr(data)) = &output;
+        output_size += data.len() as u64;
+        if max_output_bytes.map_or(false, |max_output_bytes| output_size > max_output_bytes) {
+            return false;
+        }
+
+        if stream {
+            let event = match output {
+                ScriptOutput::Stdout(data) => ScriptEvent::Stdout { data, content_type: content_type.clone() },
+                ScriptOutput::Stderr(data) => ScriptEvent::Stderr { data }
+            };
+            response_stream.send_event(&serde_json::to_value(&event).unwrap());
+        } else {
+            match output {
+                ScriptOutput::Stdout(data) => stdout.extend(data),
+                ScriptOutput::Stderr(data) => stderr.extend(data)
+            }
+        }
+        return true;
+    };
+
+    let mut last_heartbeat = Instant::now();
+    let mut has_timed_out = false;
+    let mut has_too_much_output = false;
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => { break Ok(status) },
+            Ok(None) => {},
+            Err(e) => { break Err(e) }
+        }
+
+        has_timed_out = start_time.elapsed() > timeout;
+        if has_timed_out || has_too_much_output || response_stream.is_cancelled() {
+            eprintln!("[{}] Kill script (timed out: {}, too much output: {})", response_stream.message_id(), has_timed_out, has_too_much_output);
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            break child.wait();
+        }
+
+        /* Keeps the client waiting */
+        if last_heartbeat.elapsed() > Duration::from_millis(STREAM_HEARTBEAT_INTERVAL_MS) {
+            response_stream.send_heartbeat();
+            last_heartbeat = Instant::now();
+        }
+        /* Waits for some output rather than sleeping */
+        match rx_output.recv_timeout(Duration::from_millis(STREAM_POLL_INTERVAL_MS)) {
+            Ok(output) => {
+                has_too_much_output = !forward_output(output);
+                last_heartbeat = Instant::now();
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            /* Both outputs are closed */
+            Err(RecvTimeoutError::Disconnected) => { thread::sleep(Duration::from_millis(STREAM_POLL_INTERVAL_MS)) }
+        }
+    };
+
+    /* Processes left in the background would keep the outputs open */
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+
+    /* The rest of the output, until the readers are done or the script times out */
+    let drain_timeout = timeout.saturating_sub(start_time.elapsed());
+    if drain_output(&rx_output, &mut forward_output, drain_timeout) {
+        let _ = stdout_reader.join();
+        let _ = stderr_reader.join();
+    } else {
+        eprintln!("[{}] Script outputs still open after its exit", response_stream.message_id());
+        has_timed_out = true;
+    }
+
+    if let Err(e) = status {
+        response_stream.fail(StatusCode::InternalError, format!("Error while executing script: {}", e.to_string()).as_str());
+        return;
+    }
+    let status = status.unwrap();
 
-    return Result::Ok(response);
+    if has_timed_out {
+        response_stream.fail(StatusCode::Timeout, format!("Script timed out after {} ms", timeout.as_millis()).as_str());
+        return;
+    }
+    if let Some(max_output_bytes) = max_output_bytes.filter(|max_output_bytes| output_size > *max_output_bytes) {
+        response_stream.fail(StatusCode::InternalError, format!("Script output exceeded {} bytes", max_output_bytes).as_str());
+        return;
+    }
 
+    let duration_ms = start_time.elapsed().as_millis() as u64;
+    let response = if stream {
+        serde_json::to_value(&ScriptEvent::Exit {
+            exit_code: status.code(),
+            signal: status.signal(),
+            duration_ms
+        })
+    } else {
+        serde_json::to_value(&ScriptResult {
+            stdout,
+            stderr,
+            exit_code: status.code(),
+            signal: status.signal(),
+            duration_ms,
+            content_type
+        })
+    };
+
+    match response {
+        Ok(response) => response_stream.finish(Some(&response)),
+        Err(e) => response_stream.fail(StatusCode::InternalError, format!("Cannot serialize script result: {}", e.to_string()).as_str())
+    };
 }
 
-pub fn process_script_response(response_payload: &[u8], format: OutputFormat) {
-    if response_payload.len() < 4 {
+/// Returns false if the outputs are still open after the timeout
+fn drain_output(rx_output: &Receiver<ScriptOutput>, forward_output: &mut impl FnMut(ScriptOutput) -> bool, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match rx_output.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(output) => { forward_output(output); },
+            Err(RecvTimeoutError::Disconnected) => { return true },
+            Err(RecvTimeoutError::Timeout) => { return false }
+        }
+    }
+}
+
+/// Sends the output of the script piece by piece, as soon as it is written
+fn read_output<R: Read + Send + 'static>(
+    output: Option<R>,
+    tx_output: mpsc::Sender<ScriptOutput>,
+    make_output: fn(Vec<u8>) -> ScriptOutput
+) -> JoinHandle<()> {
+    return thread::spawn(move || {
+        let mut output = match output {
+            Some(output) => output,
+            None => { return }
+        };
+        let mut buffer = [0u8; 4096];
+        loop {
+            match output.read(&mut buffer) {
+                Ok(0) => { break },
+                Ok(n) => {
+                    if tx_output.send(make_output(buffer[..n].to_vec())).is_err() { break; }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => { continue },
+                Err(e) => {
+                    eprintln!("Error while reading script output: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+pub fn process_script_response(response: serde_json::Value, format: OutputFormat) {
+    let script_result = serde_json::from_value::<ScriptResult>(response);
+    if script_result.is_err() {
         eprintln!("Invalid response payload");
         std::process::exit(-1);
     }
-    let mut code_bytes = [0u8; 4];
-    code_bytes.copy_from_slice(&response_payload[0..4]);
-    let code = i32::from_ne_bytes(code_bytes);
+    let script_result = script_result.unwrap();
 
-    if code != 0 {
-        eprintln!("Script exited with code {}", code);
-    }
-    
-    let script_output = &response_payload[4..];
-    
     match format {
         OutputFormat::Raw => {
-            std::io::stdout().write_all(script_output).unwrap();
+            std::io::stdout().write_all(&script_result.stdout).unwrap();
+            std::io::stderr().write_all(&script_result.stderr).unwrap();
         },
         OutputFormat::Text => {
-            let script_output_text = String::from_utf8(script_output.to_vec()).unwrap();
-            println!("{}", script_output_text);
+            println!("{}", String::from_utf8_lossy(&script_result.stdout));
+            std::io::stderr().write_all(&script_result.stderr).unwrap();
         },
         OutputFormat::Json => {
-            let script_output_text = String::from_utf8(script_output.to_vec()).unwrap();
-            println!("{}", serde_json::to_string(&script_output_text).unwrap());
+            let (stdout, stdout_encoding) = make_json_output(&script_result.stdout, &script_result.content_type, true);
+            println!("{}", serde_json::json!({
+                "stdout": stdout,
+                "stdoutEncoding": stdout_encoding,
+                "contentType": script_result.content_type,
+                "stderr": String::from_utf8_lossy(&script_result.stderr),
+                "exitCode": script_result.exit_code,
+                "signal": script_result.signal,
+                "durationMs": script_result.duration_ms
+            }));
         }
     }
+
+    if let Some(signal) = script_result.signal {
+        eprintln!("Script was killed by signal {}", signal);
+    } else if script_result.get_exit_code() != 0 {
+        eprintln!("Script exited with code {}", script_result.get_exit_code());
+    }
+    std::process::exit(script_result.get_exit_code());
 }
 
+/// Prints the output of a streamed script as it arrives, and exits with its status at the end
+pub fn process_script_event(event: serde_json::Value, format: OutputFormat) {
+    let script_event = match serde_json::from_value::<ScriptEvent>(event) {
+        Ok(script_event) => script_event,
+        Err(_) => {
+            eprintln!("Invalid response payload");
+            std::process::exit(-1);
+        }
+    };
+
+    if let OutputFormat::Json = format {
+        let json_event = match &script_event {
+            ScriptEvent::Stdout { data, content_type } => {
+                /* Pieces of json are not valid json */
+                let (data, encoding) = make_json_output(data, content_type, false);
+                serde_json::json!({ "type": "stdout", "data": data, "encoding": encoding })
+            },
+            ScriptEvent::Stderr { data } => serde_json::json!({ "type": "stderr", "data": String::from_utf8_lossy(data) }),
+            ScriptEvent::Exit { .. } => serde_json::to_value(&script_event).unwrap()
+        };
+        println!("{}", json_event);
+    }
+
+    match script_event {
+        ScriptEvent::Stdout { data, .. } => {
+            if !matches!(format, OutputFormat::Json) {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&data).unwrap();
+                stdout.flush().unwrap();
+            }
+        },
+        ScriptEvent::Stderr { data } => {
+            if !matches!(format, OutputFormat::Json) {
+                std::io::stderr().write_all(&data).unwrap();
+            }
+        },
+        ScriptEvent::Exit { exit_code, signal, duration_ms } => {
+            let script_result = ScriptResult { stdout: vec![], stderr: vec![], exit_code, signal, duration_ms, content_type: None };
+            if let Some(signal) = script_result.signal {
+                eprintln!("Script was killed by signal {}", signal);
+            } else if script_result.get_exit_code() != 0 {
+                eprintln!("Script exited with code {}", script_result.get_exit_code());
+            }
+            std::process::exit(script_result.get_exit_code());
+        }
+    }
+}
+
+pub fn process_scripts_list_response(response_payload: &[u8], format: OutputFormat) {
+    let scripts = match serde_json::from_slice::<Vec<ScriptInfos>>(response_payload) {
+        Ok(scripts) => scripts,
+        Err(_) => {
+            eprintln!("Failed to parse scripts list response");
+            eprintln!("{}", String::from_utf8_lossy(response_payload));
+            return;
+        }
+    };
+
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&scripts).unwrap());
+        },
+        _ => {
+            for script in scripts {
+                let params: Vec<String> = script.params.iter().map(|p| {
+                    let name = match p.param_type {
+                        ParamType::String => p.name.clone(),
+                        param_type => format!("{}:{}", p.name, serde_json::to_value(param_type).unwrap().as_str().unwrap_or(""))
+                    };
+                    if p.required { name } else { format!("[{}]", name) }
+                }).collect();
+                let executable = if script.executable { "" } else { " (not executable)" };
+                println!("{} {}{}", script.name, params.join(" "), executable);
+                if let Some(description) = script.description {
+                    println!("    {}", description);
+                }
+            }
+        }
+    }
+}
+
+/// Checks that a script exists in a scripts list response, and that it accepts some arguments
+pub fn validate_script_call(list_response_payload: &[u8], name: &String, args: &Vec<String>, params: &HashMap<String, String>) -> Result<(), String> {
+    let scripts = match serde_json::from_slice::<Vec<ScriptInfos>>(list_response_payload) {
+        Ok(scripts) => scripts,
+        Err(_) => { return Err(String::from("Failed to parse scripts list response")) }
+    };
+
+    return match scripts.iter().find(|script| &script.name == name) {
+        Some(script) => {
+            if !script.executable {
+                return Err(format!("Script {} is not executable", name));
+            }
+            script.resolve_params(args, params).map(|_| ())
+        },
+        None => Err(format!("Unable to find a script with name: {}", name))
+    };
+}
+
+pub fn make_scripts_list_request(make_id: impl Fn() -> String, shell_id: &String) -> Request {
+    return make_scripts_request(make_id, shell_id, ScriptsCommandRequestBody {
+        operation: ScriptsOperation::List,
+        ..Default::default()
+    });
+}
 
 /***************** */
 
@@ -129,4 +648,65 @@ fn find_file_in_folder(folder_path: std::path::PathBuf, target_file_name: &Strin
         }
     }
     return None;
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{ScriptsCommandRequestBody, make_script_command};
+
+    #[test]
+    fn test_param_env_vars_are_reserved() {
+        let folder_path = std::env::temp_dir().join(format!("hoposhell-test-param-env-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        std::fs::write(folder_path.join("deploy.sh"), "#!/bin/sh\necho ok\n").unwrap();
+        std::fs::write(folder_path.join("scripts.toml"), r#"
+            [scripts."deploy.sh"]
+            passAs = "env"
+            params = [{ name = "environment", required = false, enum = ["staging", "production"] }]
+        "#).unwrap();
+
+        let mut request_infos = ScriptsCommandRequestBody { name: "deploy.sh".to_string(), ..Default::default() };
+        request_infos.params = HashMap::from([("environment".to_string(), "staging".to_string())]);
+        assert!(make_script_command(&folder_path, &request_infos).is_ok());
+
+        request_infos.params = HashMap::new();
+        request_infos.env = HashMap::from([("HOPO_PARAM_ENVIRONMENT".to_string(), "anything".to_string())]);
+        let error = make_script_command(&folder_path, &request_infos).err().unwrap();
+        assert!(error.contains("HOPO_PARAM_ENVIRONMENT"));
+
+        std::fs::remove_dir_all(&folder_path).unwrap();
+    }
+
+    #[test]
+    fn test_loader_env_vars_need_an_allowlist() {
+        let folder_path = std::env::temp_dir().join(format!("hoposhell-test-loader-env-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        std::fs::write(folder_path.join("build.sh"), "#!/bin/sh\necho ok\n").unwrap();
+        std::fs::write(folder_path.join("scripts.toml"), r#"
+            [scripts."build.sh"]
+            [scripts."release.sh".sandbox]
+            envAllowlist = ["PATH"]
+        "#).unwrap();
+        std::fs::write(folder_path.join("release.sh"), "#!/bin/sh\necho ok\n").unwrap();
+
+        let mut request_infos = ScriptsCommandRequestBody { name: "build.sh".to_string(), ..Default::default() };
+        request_infos.env = HashMap::from([("TARGET".to_string(), "release".to_string())]);
+        assert!(make_script_command(&folder_path, &request_infos).is_ok());
+
+        for key in ["PATH", "LD_PRELOAD", "IFS", "BASH_ENV", "PYTHONPATH"] {
+            request_infos.env = HashMap::from([(key.to_string(), "/tmp".to_string())]);
+            let error = make_script_command(&folder_path, &request_infos).err().unwrap();
+            assert!(error.contains(key));
+        }
+
+        request_infos.name = "release.sh".to_string();
+        request_infos.env = HashMap::from([("PATH".to_string(), "/tmp".to_string())]);
+        assert!(make_script_command(&folder_path, &request_infos).is_ok());
+
+        std::fs::remove_dir_all(&folder_path).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use std::{io::{Read, Write}, net::TcpStream, thread};
+use std::{io::{Read, Write}, net::TcpStream, thread, time::Duration, sync::atomic::{AtomicBool, Ordering}};
 
 use openssl::ssl::SslConnector;
 use serde_json::{Value};
@@ -16,11 +16,12 @@ use crate::{
     message::{
         MessageTypeToStream, Message, MessageTypeToCmd
     },
-    args::Args,
+    args::{Args, consume_arg, consume_arg_value},
+    constants::STREAM_POLL_INTERVAL_MS,
     make_random_id
 };
 
-use super::{download, tcp, ls, http, glob, scripts, request_or_response::{Request, ChunkedRequestOrResponse}};
+use super::{download, tcp, udp, ls, http, glob, scripts, jobs, schedule, du, watch, cancel, request_or_response::{Request, ChunkedRequestOrResponse, requester_shell_id}};
 
 pub fn main_command(args: Args) {
     let target_shell_id = &args.extra_args[0];
@@ -83,32 +84,141 @@ pub fn send_command(
             });
         },
         http::COMMAND_NAME => {
-            // hopo command <shell_id> http <verb> <url>
-            req = Some(http::make_http_request(make_id, &target_shell_id, &command_args));
-            process_res = Box::new(|res: Response| {
-                http::process_http_response(&res.payload, args.format);
+            // hopo command <shell_id> http <verb> <url> [<body>] [-H 'Name: value']... [--data <body>|@<file>] [-i] [<client options>]
+            let mut http_args = command_args.clone();
+            let include_headers = consume_arg(&mut http_args, "-i") || consume_arg(&mut http_args, "--include");
+            req = Some(http::make_http_request(make_id, &target_shell_id, &http_args));
+            process_res = Box::new(move |res: Response| {
+                http::process_http_response(&res.payload, include_headers, args.format);
             });
         },
         tcp::COMMAND_NAME => {
-            // hopo command <shell_id> tcp host port payload
-            let host = &command_args[0];
-            let port = &command_args[1];
-            let payload = &command_args[2];
-
-            let port: u16 = port.parse().unwrap();
-            
-            req = Some(tcp::make_tcp_request(make_id, &target_shell_id, host.clone(), port, payload.clone().as_bytes().to_vec()));
+            // hopo command <shell_id> tcp <host> <port>|unix:<path> [<payload>] [--data <payload>|@<file>|@-] [--connect-timeout <seconds>] [--timeout <seconds>] [--until <delimiter>|--bytes <n>|--half-close]
+            req = Some(tcp::make_tcp_request(make_id, &target_shell_id, &command_args));
             process_res = Box::new(|res: Response| {
                 tcp::process_tcp_response(&res.payload, args.format);
             });
         },
+        udp::COMMAND_NAME => {
+            // hopo command <shell_id> udp <host> <port> [<payload>] [--data <payload>|@<file>] [--timeout <seconds>] [--no-response]
+            req = Some(udp::make_udp_request(make_id, &target_shell_id, &command_args));
+            process_res = Box::new(|res: Response| {
+                udp::process_udp_response(&res.payload, args.format);
+            });
+        },
         scripts::COMMAND_NAME => {
-            // hopo command <shell_id> tcp host port payload
-            let script_name = &command_args[0];
-            
-            req = Some(scripts::make_scripts_request(make_id, &target_shell_id, script_name.clone()));
+            // hopo command <shell_id> scripts --list
+            // hopo command <shell_id> scripts <name> [--cwd <folder>] [--env <key=value>]... [--param <name=value>]... [--timeout <ms>] [--stream] [--no-stdin] [--no-check] [-- <args>...]
+            let mut scripts_args = command_args.clone();
+            if consume_arg(&mut scripts_args, "--list") {
+                req = Some(scripts::make_scripts_list_request(make_id, &target_shell_id));
+                process_res = Box::new(|res: Response| {
+                    scripts::process_scripts_list_response(&res.payload, args.format);
+                });
+            } else {
+                let should_check = !consume_arg(&mut scripts_args, "--no-check");
+                let scripts_request = scripts::parse_scripts_args(scripts_args, true);
+
+                if should_check {
+                    /* Checks the arguments against the scripts catalog of the shell */
+                    let list_req = scripts::make_scripts_list_request(make_id, &target_shell_id);
+                    match send_command_request(args, &list_req) {
+                        Ok(res) => {
+                            if let Err(e) = scripts::validate_script_call(&res.payload, &scripts_request.name, &scripts_request.args, &scripts_request.params) {
+                                eprintln!("{}", e);
+                                std::process::exit(-1);
+                            }
+                        },
+                        Err(e) => {
+                            eprintln!("[{}] Unable to get the list of scripts: {}", list_req.message_id, e);
+                            std::process::exit(get_error_exit_code(&e));
+                        }
+                    }
+                }
+
+                let stream = scripts_request.stream;
+                let req = scripts::make_scripts_request(make_id, &target_shell_id, scripts_request);
+                /* Streamed, so that the script can be cancelled */
+                if stream {
+                    stream_command(args, &req, |event| scripts::process_script_event(event, args.format));
+                } else {
+                    stream_command(args, &req, |res| scripts::process_script_response(res, args.format));
+                }
+                return;
+            }
+        },
+        jobs::COMMAND_NAME => {
+            // hopo command <shell_id> jobs start <script_name> [--cwd <folder>] [--env <key=value>]... [--param <name=value>]... [-- <args>...]
+            // hopo command <shell_id> jobs list|status <job_id>|cancel <job_id>
+            // hopo command <shell_id> jobs output <job_id> [--follow] [--offset <bytes>]
+            let jobs_request = jobs::parse_jobs_args(command_args.clone());
+            let operation = jobs_request.operation;
+            if operation == jobs::JobsOperation::Output {
+                let follow = jobs_request.follow;
+                let req = jobs::make_jobs_request(make_id, &target_shell_id, &jobs_request);
+                stream_command(args, &req, |event| jobs::process_job_output_event(event, follow, args.format));
+                return;
+            }
+            req = Some(jobs::make_jobs_request(make_id, &target_shell_id, &jobs_request));
+            process_res = Box::new(move |res: Response| {
+                jobs::process_jobs_response(&res.payload, operation, args.format);
+            });
+        },
+        schedule::COMMAND_NAME => {
+            // hopo command <shell_id> schedule list|remove <schedule_id>|trigger <schedule_id>
+            // hopo command <shell_id> schedule add <schedule_id> <cron_expression> <script_name> [--cwd <folder>] [--env <key=value>]... [--param <name=value>]... [-- <args>...]
+            let schedule_request = schedule::parse_schedule_args(command_args.clone());
+            let operation = schedule_request.operation;
+            req = Some(schedule::make_schedule_request(make_id, &target_shell_id, &schedule_request));
+            process_res = Box::new(move |res: Response| {
+                schedule::process_schedule_response(&res.payload, operation, args.format);
+            });
+        },
+        du::COMMAND_NAME => {
+            // hopo command <shell_id> du <folder_path> [--depth <n>] [--top <n>]
+            let mut du_args = command_args.clone();
+            let parse_count = |name: &str, x: String| x.parse::<usize>().unwrap_or_else(|_| {
+                eprintln!("Invalid {}: {}", name, x);
+                std::process::exit(-1);
+            });
+            let depth = consume_arg_value(&mut du_args, "--depth").map(|x| parse_count("depth", x));
+            let top = consume_arg_value(&mut du_args, "--top").map(|x| parse_count("top", x));
+            let folder_path = &du_args[0];
+
+            req = Some(du::make_du_request(make_id, &target_shell_id, folder_path, depth, top));
             process_res = Box::new(|res: Response| {
-                scripts::process_script_response(&res.payload, args.format);
+                du::process_du_response(&res.payload, args.format);
+            });
+        },
+        watch::COMMAND_NAME => {
+            // hopo command <shell_id> watch <path> [--recursive] [--debounce <ms>] [--events <types>] [--for <seconds>]
+            let mut watch_args = command_args.clone();
+            let recursive = consume_arg(&mut watch_args, "--recursive");
+            let parse_number = |name: &str, x: String| x.parse::<u64>().unwrap_or_else(|_| {
+                eprintln!("Invalid {}: {}", name, x);
+                std::process::exit(-1);
+            });
+            let debounce_ms = consume_arg_value(&mut watch_args, "--debounce").map(|x| parse_number("debounce", x));
+            let duration_secs = consume_arg_value(&mut watch_args, "--for").map(|x| parse_number("duration", x));
+            let events = match consume_arg_value(&mut watch_args, "--events") {
+                Some(events) => events.split(',').map(|x| watch::WatchEventType::maybe_from(x).unwrap_or_else(|| {
+                    eprintln!("Unknown watch event type: {}", x);
+                    std::process::exit(-1);
+                })).collect(),
+                None => vec![]
+            };
+            let path = &watch_args[0];
+
+            let req = watch::make_watch_request(make_id, &target_shell_id, path, recursive, debounce_ms, events, duration_secs);
+            stream_command(args, &req, |event| watch::process_watch_event(event, args.format));
+            return;
+        },
+        cancel::COMMAND_NAME => {
+            // hopo command <shell_id> cancel <message_id>
+            let target_message_id = &command_args[0];
+            req = Some(cancel::make_cancel_request(make_id, &target_shell_id, target_message_id));
+            process_res = Box::new(|res: Response| {
+                cancel::process_cancel_response(&res.payload, args.format);
             });
         },
         _ => {
@@ -163,6 +273,19 @@ pub fn connect_to_hoposhell(args: &Args) -> (Option<SslConnector>, TcpStream) {
     return (ssl_connector, tcp_stream);
 }
 
+/// Connects to the hoposhell server, and sends a request whose response is needed before going on
+pub fn send_command_request(args: &Args, req: &Request) -> Result<Response, std::io::Error> {
+    let (ssl_connector, tcp_stream) = connect_to_hoposhell(args);
+
+    if let Some(ref ssl_connector) = ssl_connector {
+        let hostname = compute_hostname(&args.server_url);
+        let ssl_stream = ssl_connector.connect(hostname, tcp_stream).unwrap();
+        return send_request_and_get_response(args, ssl_stream, req, args.verbose);
+    } else {
+        return send_request_and_get_response(args, tcp_stream, req, args.verbose);
+    };
+}
+
 fn handle_command_connection(
     args: &Args,
     mut stream: impl Read + Write,
@@ -178,17 +301,33 @@ fn handle_command_connection(
         },
         Err(e) => {
             eprintln!("[{}] Unable to send request: {}", req.message_id, e);
-            std::process::exit(-1);
+            std::process::exit(get_error_exit_code(&e));
         }
     }
 }
 
-pub fn send_request_and_get_response(
+fn make_status_code_error(status_code: StatusCode) -> std::io::Error {
+    return match status_code {
+        StatusCode::Timeout => std::io::Error::new(std::io::ErrorKind::TimedOut, "The command timed out on the shell"),
+        StatusCode::Forbidden => std::io::Error::new(std::io::ErrorKind::PermissionDenied, "The command is not allowed by the policy of the shell"),
+        _ => std::io::Error::new(std::io::ErrorKind::Other, format!("Got a response with status {:?}", status_code))
+    };
+}
+
+/// Timeouts exit like the `timeout` command does
+fn get_error_exit_code(e: &std::io::Error) -> i32 {
+    return match e.kind() {
+        std::io::ErrorKind::TimedOut => 124,
+        _ => -1
+    };
+}
+
+pub fn send_request(
     args: &Args,
     mut stream: impl Read + Write,
     req: &Request,
     verbose: bool
-) -> Result<Response, std::io::Error> {
+) {
     let header_message = Message {
         mtype: MessageTypeToStream::HEADER,
         content: Some(format!("v{}/command", args.version).as_bytes().to_vec())
@@ -201,22 +340,37 @@ pub fn send_request_and_get_response(
         }
     }
 
-    let chunks = req.chunk();
-    eprintln!("[{}] Send request {} with #chunks: {}", req.message_id, req.cmd, chunks.len());
-    for chunk in chunks {
+    eprintln!("[{}] Send request {} with #chunks: {}", req.message_id, req.cmd, req.chunk().len());
+    if let Err(e) = send_request_chunks(&mut stream, req, verbose) {
+        eprintln!("[{}] Unable to send command message: {}", req.message_id, e);
+    }
+}
+
+/// Sends another request on a connection that already sent its header
+pub fn send_request_chunks(
+    mut stream: impl Write,
+    req: &Request,
+    verbose: bool
+) -> Result<(), std::io::Error> {
+    for chunk in req.chunk() {
         // eprintln!("- send: {} {} {:?}", chunk.cmd, chunk.message_id, chunk.chunk_type);
         let msg_payload = chunk.to_message_payload();
         let msg = Message {
             mtype: MessageTypeToStream::COMMAND,
             content: Some(msg_payload)
         };
-        match send_message_to_stream(&msg, &mut stream, verbose) {
-            Ok(_) => {},
-            Err(e) => {
-                eprintln!("[{}] Unable to send command message: {}", req.message_id, e);
-            }
-        }
+        send_message_to_stream(&msg, &mut stream, verbose)?;
     }
+    return Ok(());
+}
+
+pub fn send_request_and_get_response(
+    args: &Args,
+    mut stream: impl Read + Write,
+    req: &Request,
+    verbose: bool
+) -> Result<Response, std::io::Error> {
+    send_request(args, &mut stream, req, verbose);
 
     let mut buf_str = String::from("");
     let mut all_res: Vec<ChunkedResponse> = vec![];
@@ -244,6 +398,9 @@ pub fn send_request_and_get_response(
                     },
                     ParseCommandResponseResult::Error => {
                         return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to parse command response"));
+                    },
+                    ParseCommandResponseResult::ErrorStatus(status_code) => {
+                        return Err(make_status_code_error(status_code));
                     }
                 }
             },
@@ -286,6 +443,126 @@ pub fn send_request_and_get_response(
     });
 }
 
+/// Sends a request whose response is streamed by the shell, and calls `process_event`
+/// for each event as soon as it arrives. Hitting Ctrl-C cancels the request on the shell.
+pub fn stream_command(args: &Args, req: &Request, process_event: impl FnMut(Value)) {
+    let (ssl_connector, tcp_stream) = connect_to_hoposhell(args);
+
+    /* Wakes up regularly to check if the user wants to cancel */
+    tcp_stream.set_read_timeout(Some(Duration::from_millis(STREAM_POLL_INTERVAL_MS))).expect("Could not set the read timeout of the tcp stream");
+    catch_interrupt();
+
+    let res = if let Some(ref ssl_connector) = ssl_connector {
+        let hostname = compute_hostname(&args.server_url);
+        let ssl_stream = ssl_connector.connect(hostname, tcp_stream).unwrap();
+        send_request_and_stream_response(args, ssl_stream, req, process_event, args.verbose)
+    } else {
+        send_request_and_stream_response(args, tcp_stream, req, process_event, args.verbose)
+    };
+
+    if let Err(e) = res {
+        eprintln!("[{}] Unable to stream response: {}", req.message_id, e);
+        std::process::exit(get_error_exit_code(&e));
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// After this, the first Ctrl-C only sets a flag, so that the requests can be cancelled on the shell
+pub fn catch_interrupt() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub fn is_interrupted() -> bool {
+    return INTERRUPTED.load(Ordering::SeqCst);
+}
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        /* Second Ctrl-C: do not wait for the shell */
+        unsafe { libc::_exit(130) };
+    }
+}
+
+fn send_request_and_stream_response(
+    args: &Args,
+    mut stream: impl Read + Write,
+    req: &Request,
+    mut process_event: impl FnMut(Value),
+    verbose: bool
+) -> Result<(), std::io::Error> {
+    send_request(args, &mut stream, req, verbose);
+    eprintln!("[{}] Waiting for the response (Ctrl-C to cancel)", req.message_id);
+
+    let mut buf_str = String::from("");
+    let mut start_time = std::time::Instant::now();
+    let mut has_sent_cancel = false;
+
+    /* Each event is a line of json, and each piece of the response is a zstd frame */
+    let mut decoder = zstd::stream::write::Decoder::new(Vec::new())?;
+    let mut event_buf: Vec<u8> = vec![];
+
+    loop {
+        if is_interrupted() && !has_sent_cancel {
+            let shell_id = requester_shell_id(&req.message_id).to_string();
+            let cancel_req = cancel::make_cancel_request(
+                || format!("{}:{}", shell_id, make_random_id(8)),
+                &req.target.trim_start_matches("shell:").to_string(),
+                &req.message_id
+            );
+            send_request(args, &mut stream, &cancel_req, verbose);
+            has_sent_cancel = true;
+        }
+
+        if start_time.elapsed() > args.command_timeout {
+            eprintln!("[{}] Command timeout", req.message_id);
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Command timeout"));
+        }
+
+        let messages = match read_messages_from_stream(&mut stream, &mut buf_str, verbose) {
+            ReadMessageResult::Ok(messages) => messages,
+            ReadMessageResult::CanContinue => { continue },
+            ReadMessageResult::CannotContinue => {
+                eprint!("[{}] Got an error when reading the tcp stream.", req.message_id);
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to read tcp stream"));
+            }
+        };
+        start_time = std::time::Instant::now();
+
+        let mut new_res: Vec<ChunkedResponse> = vec![];
+        let parse_res = parse_command_response_message(&req, &messages, &mut new_res);
+        match parse_res {
+            ParseCommandResponseResult::Error => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to parse command response"));
+            },
+            ParseCommandResponseResult::ErrorStatus(status_code) => {
+                return Err(make_status_code_error(status_code));
+            },
+            _ => {}
+        }
+
+        for res in new_res.iter() {
+            decoder.write_all(&res.payload)?;
+        }
+        decoder.flush()?;
+        event_buf.append(decoder.get_mut());
+
+        while let Some(line_end) = event_buf.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = event_buf.drain(..=line_end).collect();
+            match serde_json::from_slice::<Value>(&line) {
+                Ok(event) => process_event(event),
+                Err(_) => eprintln!("[{}] Got an invalid event: {}", req.message_id, String::from_utf8_lossy(&line))
+            }
+        }
+
+        if let ParseCommandResponseResult::ReachedLastChunk = parse_res {
+            return Ok(());
+        }
+    }
+}
+
 fn parse_command_response_message(
     req: &Request,
     messages: &Vec<Message<MessageTypeToCmd>>,
@@ -314,7 +591,7 @@ fn parse_command_response_message(
                 // eprintln!("[{}] Got a response: {} (chunk type: {:?})", res.message_id, res.cmd, res.chunk_type);
                 if res.message_id != req.message_id {
                     eprintln!("[{}] Got a response with a unexpected message_id: {}. Ignore...", req.message_id, res.message_id);
-                    return ParseCommandResponseResult::CanContinue;
+                    continue;
                 }
                 if res.status_code != StatusCode::Ok {
                     eprintln!("[{}] Got a response with status {:?}: exit", res.message_id, res.status_code);
@@ -325,14 +602,14 @@ fn parse_command_response_message(
                         if let Ok(error_json) = error_json {
                             if let Some(error) = error_json.get("error") {
                                 eprintln!("[{}] {}", res.message_id, error.as_str().unwrap());
-                                return ParseCommandResponseResult::Error;
+                                return ParseCommandResponseResult::ErrorStatus(res.status_code);
                             }
                         } else {
                             // eprintln!("[{}] Error body was: {}", res.message_id, error_body);
                             eprintln!("[{}] {}", res.message_id, error_body);
                         }
                     }
-                    return ParseCommandResponseResult::Error;
+                    return ParseCommandResponseResult::ErrorStatus(res.status_code);
                 }
                 let chunk_type = res.chunk_type.clone();
                 all_res.push(res);
@@ -0,0 +1,34 @@
+/// Serializes bytes as a base64 string, with `#[serde(with = "super::serde_base64")]`
+
+use base64::engine::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer>(bytes: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+    return serializer.serialize_str(&BASE64.encode(bytes));
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let bytes_64 = String::deserialize(deserializer)?;
+    return BASE64.decode(bytes_64).map_err(serde::de::Error::custom);
+}
+
+/// For optional bytes, with `#[serde(default, with = "super::serde_base64::option")]`
+pub mod option {
+    use base64::engine::Engine as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+        return match bytes {
+            Some(bytes) => super::serialize(bytes, serializer),
+            None => serializer.serialize_none()
+        };
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+        return match Option::<String>::deserialize(deserializer)? {
+            Some(bytes_64) => super::BASE64.decode(bytes_64).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None)
+        };
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}},
+    time::Duration
+};
+
+/* Destinations like unix:/var/run/docker.sock are Unix sockets, that have no port */
+pub const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+pub fn unix_socket_path(host: &str) -> Option<&str> {
+    return host.strip_prefix(UNIX_SOCKET_PREFIX);
+}
+
+/// Formats a destination for the logs and the errors
+pub fn format_destination(host: &String, port: u16) -> String {
+    return match unix_socket_path(host) {
+        Some(_) => host.clone(),
+        None => format!("{}:{}", host, port)
+    };
+}
+
+/// A connection to a TCP port, or to a Unix socket
+pub enum SocketStream {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl SocketStream {
+    /// Connects to `host:port`, or to the Unix socket of a `unix:<path>` host
+    pub fn connect(host: &String, port: u16, timeout: Option<Duration>) -> Result<SocketStream, std::io::Error> {
+        if let Some(path) = unix_socket_path(host) {
+            /* Connecting to a Unix socket does not wait */
+            return Ok(SocketStream::Unix(UnixStream::connect(path)?));
+        }
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => { return Ok(SocketStream::Tcp(TcpStream::connect((host.as_str(), port))?)) }
+        };
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No address found");
+        for address in (host.as_str(), port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(stream) => { return Ok(SocketStream::Tcp(stream)) },
+                Err(e) => { last_error = e }
+            }
+        }
+        return Err(last_error);
+    }
+
+    pub fn try_clone(&self) -> Result<SocketStream, std::io::Error> {
+        return match self {
+            SocketStream::Tcp(stream) => stream.try_clone().map(SocketStream::Tcp),
+            SocketStream::Unix(stream) => stream.try_clone().map(SocketStream::Unix)
+        };
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), std::io::Error> {
+        return match self {
+            SocketStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            SocketStream::Unix(stream) => stream.set_read_timeout(timeout)
+        };
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), std::io::Error> {
+        return match self {
+            SocketStream::Tcp(stream) => stream.shutdown(how),
+            SocketStream::Unix(stream) => stream.shutdown(how)
+        };
+    }
+}
+
+impl Read for SocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return match self {
+            SocketStream::Tcp(stream) => stream.read(buf),
+            SocketStream::Unix(stream) => stream.read(buf)
+        };
+    }
+}
+
+impl Write for SocketStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        return match self {
+            SocketStream::Tcp(stream) => stream.write(buf),
+            SocketStream::Unix(stream) => stream.write(buf)
+        };
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return match self {
+            SocketStream::Tcp(stream) => stream.flush(),
+            SocketStream::Unix(stream) => stream.flush()
+        };
+    }
+}
+
+/// Listens on a TCP address like `localhost:8080`, or on a Unix socket with `unix:<path>`
+pub enum SocketListener {
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+impl SocketListener {
+    pub fn bind(address: &String) -> Result<SocketListener, std::io::Error> {
+        let path = match unix_socket_path(address) {
+            Some(path) => path,
+            None => { return Ok(SocketListener::Tcp(TcpListener::bind(address)?)) }
+        };
+        /* The socket file of a previous run is left behind, unless someone still listens on it */
+        let is_socket = std::fs::metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false);
+        if is_socket && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(SocketListener::Unix(UnixListener::bind(path)?));
+    }
+
+    /// Returns the connection and a description of its peer
+    pub fn accept(&self) -> Result<(SocketStream, String), std::io::Error> {
+        return match self {
+            SocketListener::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                Ok((SocketStream::Tcp(stream), peer.to_string()))
+            },
+            SocketListener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                /* The clients of Unix sockets usually have no address */
+                Ok((SocketStream::Unix(stream), String::from("a unix socket client")))
+            }
+        };
+    }
+}
@@ -1,28 +1,65 @@
-use std::{io::{Write, Read}, time::Duration};
+use std::{io::{Write, Read}, net::Shutdown, time::{Duration, Instant}};
 
-use crate::constants::{OutputFormat};
-use super::{request_or_response::{Request, make_shell_target, maybe_string}, command_error::make_error_bytes};
+use serde::{Serialize, Deserialize};
+
+use crate::{args::{consume_arg, consume_arg_value}, constants::{BUF_SIZE, OutputFormat}};
+use super::{
+    request_or_response::{Request, make_shell_target, maybe_string},
+    command_error::{make_error, make_error_bytes},
+    socket_stream::{SocketStream, format_destination, unix_socket_path}
+};
 
 pub const COMMAND_NAME: &str = "tcp";
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct TcpCommandRequestBody {
-    host: String,
-    port: u16,
-    payload: Vec<u8>
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_READ_TIMEOUT_MS: u64 = 1000;
+
+/// When the response is complete
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum TcpReadStrategy {
+    /* Until the remote host closes the connection */
+    #[default]
+    Eof,
+    /* Until the delimiter is received, the delimiter being part of the response */
+    Delimiter {
+        #[serde(with = "super::serde_base64")]
+        delimiter: Vec<u8>
+    },
+    Bytes {
+        count: usize
+    },
+    /* Shuts down the writing side after the payload, then reads until the end of stream */
+    HalfClose
 }
 
-pub fn make_tcp_request(make_id: impl Fn() -> String, shell_id: &String, host: String, port: u16, payload: Vec<u8>) -> Request{
-    let tcp_request = TcpCommandRequestBody { host, port, payload };
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TcpCommandRequestBody {
+    /* Or unix:<path> for a Unix socket, whose port is ignored */
+    pub host: String,
+    pub port: u16,
+    pub payload: Vec<u8>,
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /* How long to wait for the whole response */
+    #[serde(default)]
+    pub read_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub read: TcpReadStrategy
+}
 
-    let payload = serde_json::to_vec(&tcp_request).unwrap();
+/// Errors tell at which step the request failed: connect, write or read
+fn make_tcp_error(step: &str, e: &std::io::Error, msg: String, received_bytes: usize) -> Vec<u8> {
+    let mut error = make_error(msg.as_str());
+    error["step"] = serde_json::json!(step);
+    error["kind"] = serde_json::json!(format!("{:?}", e.kind()));
+    error["receivedBytes"] = serde_json::json!(received_bytes);
+    return error.to_string().as_bytes().to_vec();
+}
 
-    return Request {
-        cmd: COMMAND_NAME.to_string(),
-        message_id: make_id(),
-        target: make_shell_target(shell_id),
-        payload
-    };
+fn is_timeout(e: &std::io::Error) -> bool {
+    return e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut;
 }
 
 pub fn process_tcp_command(
@@ -40,32 +77,81 @@ pub fn process_tcp_command(
         return Result::Err(make_error_bytes(format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
     }
     let request_infos = json_payload.unwrap();
+    let address = format_destination(&request_infos.host, request_infos.port);
 
     /* Make TCP request at specified location */
-    let mut stream = std::net::TcpStream::connect(format!("{}:{}", request_infos.host, request_infos.port)).unwrap();
-    let write_res = stream.write(&request_infos.payload);
+    let connect_timeout = Duration::from_millis(request_infos.connect_timeout_ms.unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS).max(1));
+    let mut stream = match SocketStream::connect(&request_infos.host, request_infos.port, Some(connect_timeout)) {
+        Ok(stream) => stream,
+        Err(e) => { return Result::Err(make_tcp_error("connect", &e, format!("Cannot connect to {}: {}", address, e), 0)) }
+    };
 
-    /* Make sure write succeeded and the correct amount of bytes was written */
-    if write_res.is_err() {
-        return Result::Err(make_error_bytes(format!("Error while writing to tcp stream: {}", write_res.err().unwrap().to_string()).as_str()));
+    if let Err(e) = stream.write_all(&request_infos.payload) {
+        return Result::Err(make_tcp_error("write", &e, format!("Error while writing to {}: {}", address, e), 0));
     }
-    let write_res = write_res.unwrap();
-
-    if write_res != request_infos.payload.len() {
-        return Result::Err(make_error_bytes(format!("Wrong number of bytes written to tcp steam: {} bytes written instead of {} bytes", write_res, request_infos.payload.len()).as_str()));
+    if request_infos.read == TcpReadStrategy::HalfClose {
+        if let Err(e) = stream.shutdown(Shutdown::Write) {
+            return Result::Err(make_tcp_error("write", &e, format!("Cannot half-close the connection to {}: {}", address, e), 0));
+        }
     }
 
     /* Read response from stream */
-    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
-    
+    let read_timeout = Duration::from_millis(request_infos.read_timeout_ms.unwrap_or(DEFAULT_READ_TIMEOUT_MS).max(1));
+    let deadline = Instant::now() + read_timeout;
     let mut response = vec![];
-    let read_res = stream.read_to_end(&mut response);
-    
-    // let mut response = vec![0; crate::constants::BUF_SIZE];
-    // let read_res = stream.read(&mut response);
+    let mut buf = vec![0u8; BUF_SIZE];
+
+    loop {
+        let is_complete = match &request_infos.read {
+            TcpReadStrategy::Delimiter { delimiter } => {
+                !delimiter.is_empty() && response.windows(delimiter.len()).any(|x| x == delimiter.as_slice())
+            },
+            TcpReadStrategy::Bytes { count } => response.len() >= *count,
+            TcpReadStrategy::Eof | TcpReadStrategy::HalfClose => false
+        };
+        if is_complete {
+            break;
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let read_res = if remaining.is_zero() {
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"))
+        } else {
+            stream.set_read_timeout(Some(remaining)).unwrap();
+            /* Never reads past the expected bytes */
+            let max_len = match &request_infos.read {
+                TcpReadStrategy::Bytes { count } => (*count - response.len()).min(buf.len()),
+                _ => buf.len()
+            };
+            stream.read(&mut buf[..max_len])
+        };
+
+        match read_res {
+            Ok(0) => {
+                return match &request_infos.read {
+                    TcpReadStrategy::Eof | TcpReadStrategy::HalfClose => Result::Ok(response),
+                    _ => {
+                        let e = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "closed");
+                        Result::Err(make_tcp_error("read", &e, format!("Connection closed by {} before the end of the response", address), response.len()))
+                    }
+                };
+            },
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            Err(e) if is_timeout(&e) => {
+                let e = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+                return Result::Err(make_tcp_error("read", &e, format!("No complete response from {} after {}ms", address, read_timeout.as_millis()), response.len()));
+            },
+            Err(e) => {
+                return Result::Err(make_tcp_error("read", &e, format!("Error while reading from {}: {}", address, e), response.len()));
+            }
+        }
+    }
 
-    if read_res.is_err() {
-        return Result::Err(make_error_bytes(format!("Error while reading from tcp stream: {}", read_res.err().unwrap().to_string()).as_str()));
+    /* The bytes after the delimiter are not part of the response */
+    if let TcpReadStrategy::Delimiter { delimiter } = &request_infos.read {
+        if let Some(position) = response.windows(delimiter.len()).position(|x| x == delimiter.as_slice()) {
+            response.truncate(position + delimiter.len());
+        }
     }
 
     return Result::Ok(response);
@@ -79,12 +165,129 @@ pub fn process_tcp_response(response_payload: &[u8], format: OutputFormat) {
             std::io::stdout().write_all(response_payload).unwrap();
         },
         OutputFormat::Text => {
-            let response_text = String::from_utf8(response_payload.to_vec()).unwrap();
+            let response_text = String::from_utf8_lossy(response_payload);
             println!("{}", response_text);
         },
         OutputFormat::Json => {
-            let response_text = String::from_utf8(response_payload.to_vec()).unwrap();
+            let response_text = String::from_utf8_lossy(response_payload);
             println!("{}", serde_json::to_string(&response_text).unwrap());
         }
     }
-}
\ No newline at end of file
+}
+
+/// Replaces the escape sequences of a delimiter given on the command line: \n, \r, \t, \0, \\ and \xHH
+fn unescape_delimiter(delimiter: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = delimiter.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next()? {
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            '0' => bytes.push(0),
+            '\\' => bytes.push(b'\\'),
+            'x' => {
+                let hex: String = [chars.next()?, chars.next()?].iter().collect();
+                bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+            },
+            _ => { return None }
+        }
+    }
+    return Some(bytes);
+}
+
+/// Parses `<host> <port>|unix:<path> [<payload>] [--data <payload>|@<file>|@-] [--connect-timeout <seconds>] [--timeout <seconds>]
+///     [--until <delimiter>|--bytes <n>|--half-close]`
+pub fn make_tcp_request(make_id: impl Fn() -> String, shell_id: &String, args: &Vec<String>) -> Request{
+    let mut args = args.clone();
+
+    let exit_with_error = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        std::process::exit(-1);
+    };
+    let parse_timeout = |timeout: String| -> u64 {
+        match timeout.parse::<f64>() {
+            Ok(timeout) if timeout > 0.0 => (timeout * 1000.0) as u64,
+            _ => exit_with_error(format!("Invalid timeout: {}", timeout))
+        }
+    };
+
+    let data = consume_arg_value(&mut args, "--data").map(|data| match data.as_str() {
+        "@-" => {
+            let mut stdin_data = vec![];
+            std::io::stdin().read_to_end(&mut stdin_data).unwrap_or_else(|e| exit_with_error(format!("Cannot read stdin: {}", e)));
+            stdin_data
+        },
+        _ => match data.strip_prefix('@') {
+            Some(file_path) => std::fs::read(file_path).unwrap_or_else(|e| exit_with_error(format!("Cannot read payload file {}: {}", file_path, e))),
+            None => data.into_bytes()
+        }
+    });
+    let connect_timeout_ms = consume_arg_value(&mut args, "--connect-timeout").map(parse_timeout);
+    let read_timeout_ms = consume_arg_value(&mut args, "--timeout").map(parse_timeout);
+
+    let delimiter = consume_arg_value(&mut args, "--until");
+    let count = consume_arg_value(&mut args, "--bytes");
+    let half_close = consume_arg(&mut args, "--half-close");
+    let read = match (delimiter, count, half_close) {
+        (None, None, false) => TcpReadStrategy::Eof,
+        (Some(delimiter), None, false) => match unescape_delimiter(&delimiter) {
+            Some(delimiter) if !delimiter.is_empty() => TcpReadStrategy::Delimiter { delimiter },
+            _ => exit_with_error(format!("Invalid delimiter: {}", delimiter))
+        },
+        (None, Some(count), false) => TcpReadStrategy::Bytes {
+            count: count.parse::<usize>().unwrap_or_else(|_| exit_with_error(format!("Invalid number of bytes: {}", count)))
+        },
+        (None, None, true) => TcpReadStrategy::HalfClose,
+        _ => exit_with_error(String::from("Please specify only one of --until, --bytes and --half-close"))
+    };
+
+    if args.is_empty() {
+        exit_with_error(String::from("Please specify the host and the port"));
+    }
+    let host = args.remove(0);
+    /* Unix sockets have no port */
+    let port = match unix_socket_path(&host) {
+        Some(_) => 0,
+        None if args.is_empty() => exit_with_error(String::from("Please specify the port")),
+        None => {
+            let port = args.remove(0);
+            port.parse::<u16>().unwrap_or_else(|_| exit_with_error(format!("Invalid port: {}", port)))
+        }
+    };
+    let payload = match (args.first(), data) {
+        (Some(payload), _) => payload.clone().into_bytes(),
+        (None, Some(data)) => data,
+        (None, None) => exit_with_error(String::from("Please specify the payload"))
+    };
+
+    let tcp_request = TcpCommandRequestBody { host, port, payload, connect_timeout_ms, read_timeout_ms, read };
+
+    let payload = serde_json::to_vec(&tcp_request).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unescape_delimiter;
+
+    #[test]
+    fn test_unescape_delimiter() {
+        assert_eq!(unescape_delimiter("\\r\\n"), Some(b"\r\n".to_vec()));
+        assert_eq!(unescape_delimiter("END\\x00"), Some(b"END\0".to_vec()));
+        assert_eq!(unescape_delimiter("a\\\\b"), Some(b"a\\b".to_vec()));
+        assert_eq!(unescape_delimiter("\\q"), None);
+        assert_eq!(unescape_delimiter("\\x4"), None);
+    }
+}
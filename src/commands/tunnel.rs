@@ -0,0 +1,495 @@
+/**
+ * TCP tunnels between a client and the connections opened by the shell, used by `hopo forward-tcp`.
+ * The host can be `unix:<path>` to connect to a Unix socket of the shell (the port is then ignored).
+ * UDP tunnels (`"protocol": "udp"`) are used by `hopo forward-udp`: each `data` request and event is one datagram.
+ *
+ * A tunnel is opened by an `open` request, whose message id is the id of the tunnel.
+ * Its response is streamed until the tunnel is closed:
+ *   {"type": "connected"}: always the first event
+ *   {"type": "data", "data": "<base64>"}: bytes received from the remote host
+ *   {"type": "eof"}: the remote host will not send anything more
+ *   {"type": "closed", "error": null}: always the last event
+ * The client sends the bytes for the remote host with `data` requests, and ends the tunnel
+ * with `eof` (half-close: the remote host can still answer) or `close`.
+ * These requests only get a response when they fail.
+ *
+ * A `listen` request makes the shell listen on a port, when the shell allows remote binds
+ * (ALLOW_REMOTE_BIND=yes), until it is cancelled. Its response streams:
+ *   {"type": "listening", "port": 8080}
+ *   {"type": "accepted", "tunnelId": "<id>", "peer": "127.0.0.1:53422"}: a new tunnel
+ *   and the events of all the accepted tunnels, with their `tunnelId`
+ *   {"type": "stopped", "error": null}: always the last event
+ */
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{Shutdown, TcpListener},
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}, mpsc::{self, Sender, Receiver, TryRecvError}},
+    thread,
+    time::{Duration, Instant}
+};
+
+use serde::{Serialize, Deserialize};
+
+use crate::constants::{BUF_SIZE, STREAM_POLL_INTERVAL_MS, STREAM_HEARTBEAT_INTERVAL_MS};
+use super::{
+    request_or_response::{maybe_string, Request, make_shell_target, requester_shell_id, StatusCode},
+    response_stream::ResponseStream,
+    command_processor::CommandSettings,
+    udp::{connect_udp_socket, MAX_DATAGRAM_SIZE},
+    socket_stream::{SocketStream, format_destination},
+    command_error::make_error_bytes
+};
+
+pub const COMMAND_NAME: &str = "tunnel";
+
+/* Like ssh -R, the other hosts cannot connect unless asked */
+pub const DEFAULT_BIND_ADDRESS: &str = "localhost";
+
+/// The writers of the open tunnels, by tunnel id
+pub type Tunnels = Arc<Mutex<HashMap<String, Sender<TunnelInput>>>>;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelOperation {
+    Open,
+    Listen,
+    Data,
+    Eof,
+    Close
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelProtocol {
+    #[default]
+    Tcp,
+    Udp
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelCommandRequestBody {
+    pub operation: TunnelOperation,
+    /* Open only */
+    #[serde(default)]
+    pub protocol: TunnelProtocol,
+    /* Open, and listen where it is the bind address (localhost by default) */
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    /* All the operations but open */
+    #[serde(default)]
+    pub tunnel_id: Option<String>,
+    /* Data only */
+    #[serde(default, with = "super::serde_base64")]
+    pub data: Vec<u8>
+}
+
+impl TunnelCommandRequestBody {
+    pub fn open(protocol: TunnelProtocol, host: &String, port: u16) -> TunnelCommandRequestBody {
+        return TunnelCommandRequestBody {
+            operation: TunnelOperation::Open,
+            protocol,
+            host: Some(host.clone()),
+            port: Some(port),
+            tunnel_id: None,
+            data: vec![]
+        };
+    }
+
+    pub fn listen(bind_address: &Option<String>, port: u16) -> TunnelCommandRequestBody {
+        return TunnelCommandRequestBody {
+            operation: TunnelOperation::Listen,
+            protocol: TunnelProtocol::Tcp,
+            host: bind_address.clone(),
+            port: Some(port),
+            tunnel_id: None,
+            data: vec![]
+        };
+    }
+
+    pub fn from_input(tunnel_id: &String, input: TunnelInput) -> TunnelCommandRequestBody {
+        let (operation, data) = match input {
+            TunnelInput::Data(data) => (TunnelOperation::Data, data),
+            TunnelInput::Eof => (TunnelOperation::Eof, vec![]),
+            TunnelInput::Close => (TunnelOperation::Close, vec![])
+        };
+        return TunnelCommandRequestBody {
+            operation,
+            protocol: TunnelProtocol::Tcp,
+            host: None,
+            port: None,
+            tunnel_id: Some(tunnel_id.clone()),
+            data
+        };
+    }
+}
+
+/// What is sent to one side of a tunnel
+#[derive(Debug, PartialEq)]
+pub enum TunnelInput {
+    Data(Vec<u8>),
+    /* Shuts down the writing side only */
+    Eof,
+    Close
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TunnelEvent {
+    Connected,
+    Data {
+        #[serde(with = "super::serde_base64")]
+        data: Vec<u8>
+    },
+    Eof,
+    /* Always the last event of a tunnel */
+    Closed {
+        error: Option<String>
+    },
+    /* Listen only */
+    Listening {
+        port: u16
+    },
+    Accepted {
+        peer: String
+    },
+    Stopped {
+        error: Option<String>
+    }
+}
+
+impl TunnelEvent {
+    /// The events of listeners tell which tunnel they are about
+    pub fn to_json(&self, tunnel_id: Option<&String>) -> serde_json::Value {
+        let mut event = serde_json::to_value(self).unwrap();
+        if let Some(tunnel_id) = tunnel_id {
+            event["tunnelId"] = serde_json::Value::String(tunnel_id.clone());
+        }
+        return event;
+    }
+}
+
+pub fn process_tunnel_command(
+    req: &Request,
+    tunnels: &Tunnels,
+    settings: &CommandSettings,
+    make_response_stream: impl FnOnce() -> ResponseStream
+) -> Result<Option<Vec<u8>>, Vec<u8>> {
+    let text_payload = maybe_string(Some(&req.payload));
+    if text_payload.is_none() {
+        return Result::Err(make_error_bytes("No text payload provided"));
+    }
+    let text_payload = text_payload.unwrap();
+
+    let request_infos = match serde_json::from_str::<TunnelCommandRequestBody>(text_payload.as_str()) {
+        Ok(request_infos) => request_infos,
+        Err(e) => { return Result::Err(make_error_bytes(format!("Invalid json payload: {}", e).as_str())) }
+    };
+
+    if request_infos.operation == TunnelOperation::Open {
+        let (host, port) = match (request_infos.host, request_infos.port) {
+            (Some(host), Some(port)) => (host, port),
+            _ => { return Result::Err(make_error_bytes("Please specify the host and the port")) }
+        };
+        let (tx, rx) = mpsc::channel();
+        /* Registered before connecting, so that the first bytes of the client can be queued */
+        tunnels.lock().unwrap().insert(req.message_id.clone(), tx);
+
+        let tunnel_id = req.message_id.clone();
+        let tunnels = tunnels.clone();
+        let response_stream = make_response_stream();
+        let protocol = request_infos.protocol;
+        thread::spawn(move || {
+            match protocol {
+                TunnelProtocol::Tcp => run_tunnel(&host, port, rx, response_stream),
+                TunnelProtocol::Udp => run_udp_tunnel(&host, port, rx, response_stream)
+            };
+            tunnels.lock().unwrap().remove(&tunnel_id);
+        });
+        return Result::Ok(None);
+    }
+
+    if request_infos.operation == TunnelOperation::Listen {
+        if !settings.allow_remote_bind {
+            return Result::Err(make_error_bytes("Remote binds are not allowed on this shell"));
+        }
+        let bind_address = request_infos.host.unwrap_or(String::from(DEFAULT_BIND_ADDRESS));
+        let port = match request_infos.port {
+            Some(port) => port,
+            None => { return Result::Err(make_error_bytes("Please specify the port")) }
+        };
+        let listener = match TcpListener::bind(format!("{}:{}", bind_address, port)) {
+            Ok(listener) => listener,
+            Err(e) => { return Result::Err(make_error_bytes(format!("Cannot listen on {}:{}: {}", bind_address, port, e).as_str())) }
+        };
+
+        let tunnels = tunnels.clone();
+        let response_stream = make_response_stream();
+        thread::spawn(move || run_listener(listener, tunnels, response_stream));
+        return Result::Ok(None);
+    }
+
+    let tunnel_id = match request_infos.tunnel_id {
+        Some(tunnel_id) => tunnel_id,
+        None => { return Result::Err(make_error_bytes("Please specify the tunnel id")) }
+    };
+    /* A shell can only use its own tunnels */
+    if requester_shell_id(&tunnel_id) != requester_shell_id(&req.message_id) {
+        return Result::Err(make_error_bytes(format!("Tunnel {} was not opened by the same shell", tunnel_id).as_str()));
+    }
+
+    let input = match request_infos.operation {
+        TunnelOperation::Data => TunnelInput::Data(request_infos.data),
+        TunnelOperation::Eof => TunnelInput::Eof,
+        _ => TunnelInput::Close
+    };
+    let sent = match tunnels.lock().unwrap().get(&tunnel_id) {
+        Some(tx) => tx.send(input).is_ok(),
+        None => false
+    };
+    if !sent {
+        return Result::Err(make_error_bytes(format!("Tunnel {} is not open", tunnel_id).as_str()));
+    }
+
+    return Result::Ok(None);
+}
+
+fn run_tunnel(host: &String, port: u16, rx: Receiver<TunnelInput>, response_stream: ResponseStream) {
+    let destination = format_destination(host, port);
+    /* Host names are resolved by the shell */
+    let stream = match SocketStream::connect(host, port, None) {
+        Ok(stream) => stream,
+        Err(e) => {
+            response_stream.fail(StatusCode::IncorrectParams, format!("Cannot connect to {}: {}", destination, e).as_str());
+            return;
+        }
+    };
+    eprintln!("[{}] Open tunnel to {}", response_stream.message_id(), destination);
+    response_stream.send_event(&TunnelEvent::Connected.to_json(None));
+
+    let error = relay_connection(stream, rx, &response_stream, None);
+
+    eprintln!("[{}] Close tunnel to {}", response_stream.message_id(), destination);
+    response_stream.finish(Some(&TunnelEvent::Closed { error }.to_json(None)));
+}
+
+/// Exchanges datagrams with a host until the tunnel is closed: there is no end of stream with UDP
+fn run_udp_tunnel(host: &String, port: u16, rx: Receiver<TunnelInput>, response_stream: ResponseStream) {
+    let socket = match connect_udp_socket(host, port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            response_stream.fail(StatusCode::IncorrectParams, e.as_str());
+            return;
+        }
+    };
+    eprintln!("[{}] Open udp tunnel to {}:{}", response_stream.message_id(), host, port);
+    response_stream.send_event(&TunnelEvent::Connected.to_json(None));
+
+    socket.set_read_timeout(Some(Duration::from_millis(STREAM_POLL_INTERVAL_MS))).unwrap();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut last_sent = Instant::now();
+
+    let error = loop {
+        if response_stream.is_cancelled() {
+            break Some(String::from("Cancelled"));
+        }
+        let mut is_closed = false;
+        loop {
+            match rx.try_recv() {
+                Ok(TunnelInput::Data(data)) => {
+                    if let Err(e) = socket.send(&data) {
+                        eprintln!("[{}] Cannot send datagram: {}", response_stream.message_id(), e);
+                    }
+                },
+                Ok(TunnelInput::Eof) | Ok(TunnelInput::Close) | Err(TryRecvError::Disconnected) => {
+                    is_closed = true;
+                    break;
+                },
+                Err(TryRecvError::Empty) => { break }
+            }
+        }
+        if is_closed {
+            break None;
+        }
+
+        match socket.recv(&mut buf) {
+            Ok(n) => {
+                response_stream.send_event(&TunnelEvent::Data { data: buf[..n].to_vec() }.to_json(None));
+                last_sent = Instant::now();
+            },
+            /* The host was not listening when a previous datagram was sent: it may be later */
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {},
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {},
+            Err(e) => { break Some(format!("Cannot receive datagram: {}", e)) }
+        }
+
+        if last_sent.elapsed() > Duration::from_millis(STREAM_HEARTBEAT_INTERVAL_MS) {
+            response_stream.send_heartbeat();
+            last_sent = Instant::now();
+        }
+    };
+
+    eprintln!("[{}] Close udp tunnel to {}:{}", response_stream.message_id(), host, port);
+    response_stream.finish(Some(&TunnelEvent::Closed { error }.to_json(None)));
+}
+
+/// Accepts connections until the request is cancelled. Each connection is a tunnel,
+/// whose events are sent in the response of the listener.
+fn run_listener(listener: TcpListener, tunnels: Tunnels, response_stream: ResponseStream) {
+    let port = listener.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    eprintln!("[{}] Listen on port {}", response_stream.message_id(), port);
+    response_stream.send_event(&TunnelEvent::Listening { port }.to_json(None));
+
+    let response_stream = Arc::new(response_stream);
+    let mut connections: Vec<(String, thread::JoinHandle<()>)> = vec![];
+    let mut last_sent = Instant::now();
+
+    let error = match listener.set_nonblocking(true) {
+        Err(e) => Some(format!("Cannot listen on port {}: {}", port, e)),
+        Ok(_) => loop {
+            if response_stream.is_cancelled() {
+                break None;
+            }
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    let tunnel_id = format!("{}-{}", response_stream.message_id(), connections.len() + 1);
+                    let (tx, rx) = mpsc::channel();
+                    tunnels.lock().unwrap().insert(tunnel_id.clone(), tx);
+                    eprintln!("[{}] Accept connection from {}", tunnel_id, peer);
+                    response_stream.send_event(&TunnelEvent::Accepted { peer: peer.to_string() }.to_json(Some(&tunnel_id)));
+
+                    let response_stream = response_stream.clone();
+                    let tunnels = tunnels.clone();
+                    let thread_tunnel_id = tunnel_id.clone();
+                    let handle = thread::spawn(move || {
+                        let error = match stream.set_nonblocking(false) {
+                            Ok(_) => relay_connection(SocketStream::Tcp(stream), rx, &response_stream, Some(&thread_tunnel_id)),
+                            Err(e) => Some(e.to_string())
+                        };
+                        tunnels.lock().unwrap().remove(&thread_tunnel_id);
+                        response_stream.send_event(&TunnelEvent::Closed { error }.to_json(Some(&thread_tunnel_id)));
+                    });
+                    connections.push((tunnel_id, handle));
+                    last_sent = Instant::now();
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(STREAM_POLL_INTERVAL_MS));
+                },
+                Err(e) => { break Some(format!("Cannot accept connections: {}", e)) }
+            }
+
+            if last_sent.elapsed() > Duration::from_millis(STREAM_HEARTBEAT_INTERVAL_MS) {
+                response_stream.send_heartbeat();
+                last_sent = Instant::now();
+            }
+        }
+    };
+
+    eprintln!("[{}] Stop listening on port {}", response_stream.message_id(), port);
+    drop(listener);
+
+    /* The tunnels end with their listener */
+    for (tunnel_id, handle) in connections.into_iter() {
+        if let Some(tx) = tunnels.lock().unwrap().get(&tunnel_id) {
+            let _ = tx.send(TunnelInput::Close);
+        }
+        let _ = handle.join();
+    }
+
+    match Arc::try_unwrap(response_stream) {
+        Ok(response_stream) => response_stream.finish(Some(&TunnelEvent::Stopped { error }.to_json(None))),
+        Err(_) => eprintln!("Cannot end the response of the listener on port {}", port)
+    }
+}
+
+/// Sends the events of a connection, until both directions are closed.
+/// Returns the error that ended the connection, if any.
+fn relay_connection(mut stream: SocketStream, rx: Receiver<TunnelInput>, response_stream: &ResponseStream, tunnel_id: Option<&String>) -> Option<String> {
+    let write_closed = Arc::new(AtomicBool::new(false));
+    let writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => { return Some(format!("Cannot use the connection: {}", e)) }
+    };
+    {
+        let write_closed = write_closed.clone();
+        thread::spawn(move || write_tunnel_inputs(writer, rx, write_closed));
+    }
+
+    stream.set_read_timeout(Some(Duration::from_millis(STREAM_POLL_INTERVAL_MS))).unwrap();
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut read_closed = false;
+    let mut last_sent = Instant::now();
+
+    let error = loop {
+        if response_stream.is_cancelled() {
+            break Some(String::from("Cancelled"));
+        }
+        /* Half-closed tunnels stay open until the other direction ends too */
+        if read_closed {
+            if write_closed.load(Ordering::SeqCst) {
+                break None;
+            }
+            thread::sleep(Duration::from_millis(STREAM_POLL_INTERVAL_MS));
+        } else {
+            match stream.read(&mut buf) {
+                Ok(0) => {
+                    read_closed = true;
+                    response_stream.send_event(&TunnelEvent::Eof.to_json(tunnel_id));
+                    last_sent = Instant::now();
+                },
+                Ok(n) => {
+                    response_stream.send_event(&TunnelEvent::Data { data: buf[..n].to_vec() }.to_json(tunnel_id));
+                    last_sent = Instant::now();
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {},
+                Err(e) => { break Some(format!("Cannot read from the connection: {}", e)) }
+            }
+        }
+
+        if last_sent.elapsed() > Duration::from_millis(STREAM_HEARTBEAT_INTERVAL_MS) {
+            response_stream.send_heartbeat();
+            last_sent = Instant::now();
+        }
+    };
+
+    let _ = stream.shutdown(Shutdown::Both);
+    return error;
+}
+
+/// Writes the inputs until the tunnel is closed, or until it is dropped
+fn write_tunnel_inputs(mut writer: SocketStream, rx: Receiver<TunnelInput>, write_closed: Arc<AtomicBool>) {
+    while let Ok(input) = rx.recv() {
+        match input {
+            TunnelInput::Data(data) => {
+                if write_closed.load(Ordering::SeqCst) { continue; }
+                if let Err(e) = writer.write_all(&data) {
+                    eprintln!("Cannot write to tunnel: {}", e);
+                    break;
+                }
+            },
+            TunnelInput::Eof => {
+                let _ = writer.shutdown(Shutdown::Write);
+                write_closed.store(true, Ordering::SeqCst);
+            },
+            TunnelInput::Close => { break; }
+        }
+    }
+    /* Also wakes up the reader */
+    let _ = writer.shutdown(Shutdown::Both);
+    write_closed.store(true, Ordering::SeqCst);
+}
+
+pub fn make_tunnel_request(make_id: impl Fn() -> String, shell_id: &String, request_body: &TunnelCommandRequestBody) -> Request {
+    let payload = serde_json::to_vec(request_body).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    };
+}
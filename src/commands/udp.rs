@@ -0,0 +1,158 @@
+use std::{io::Write, net::{ToSocketAddrs, UdpSocket}, time::Duration};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{args::{consume_arg, consume_arg_value}, constants::OutputFormat};
+use super::{request_or_response::{Request, make_shell_target, maybe_string}, command_error::make_error_bytes, json_output::make_json_output};
+
+pub const COMMAND_NAME: &str = "udp";
+
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+/* The largest payload of an UDP datagram */
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UdpCommandRequestBody {
+    pub host: String,
+    pub port: u16,
+    #[serde(with = "super::serde_base64")]
+    pub payload: Vec<u8>,
+    /* How long to wait for the response datagram */
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    /* For the servers that never answer, like statsd */
+    #[serde(default)]
+    pub no_response: bool
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UdpResponseEnvelope {
+    /* The address that sent the response, none when no response is expected */
+    pub from: Option<String>,
+    #[serde(with = "super::serde_base64")]
+    pub data: Vec<u8>
+}
+
+/// Creates a socket that only exchanges datagrams with a host, resolved by the shell
+pub fn connect_udp_socket(host: &String, port: u16) -> Result<UdpSocket, String> {
+    let address = match (host.as_str(), port).to_socket_addrs().map(|mut addresses| addresses.next()) {
+        Ok(Some(address)) => address,
+        Ok(None) => { return Err(format!("Cannot resolve {}", host)) },
+        Err(e) => { return Err(format!("Cannot resolve {}: {}", host, e)) }
+    };
+    let bind_address = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_address).map_err(|e| format!("Cannot create udp socket: {}", e))?;
+    socket.connect(address).map_err(|e| format!("Cannot connect to {}:{}: {}", host, port, e))?;
+    return Ok(socket);
+}
+
+pub fn process_udp_command(
+    payload: &[u8]
+) -> Result<Vec<u8>, Vec<u8>> {
+    let text_payload = maybe_string(Some(payload));
+    if text_payload.is_none() {
+        return Result::Err(make_error_bytes("No text payload provided"));
+    }
+    let text_payload = text_payload.unwrap();
+
+    let request_infos = match serde_json::from_str::<UdpCommandRequestBody>(text_payload.as_str()) {
+        Ok(request_infos) => request_infos,
+        Err(e) => { return Result::Err(make_error_bytes(format!("Invalid json payload: {}", e).as_str())) }
+    };
+    if request_infos.payload.len() > MAX_DATAGRAM_SIZE {
+        return Result::Err(make_error_bytes(format!("The payload is larger than {} bytes", MAX_DATAGRAM_SIZE).as_str()));
+    }
+
+    let socket = match connect_udp_socket(&request_infos.host, request_infos.port) {
+        Ok(socket) => socket,
+        Err(e) => { return Result::Err(make_error_bytes(e.as_str())) }
+    };
+    if let Err(e) = socket.send(&request_infos.payload) {
+        return Result::Err(make_error_bytes(format!("Cannot send datagram to {}:{}: {}", request_infos.host, request_infos.port, e).as_str()));
+    }
+
+    if request_infos.no_response {
+        return Result::Ok(serde_json::to_vec(&UdpResponseEnvelope { from: None, data: vec![] }).unwrap());
+    }
+
+    let timeout_ms = request_infos.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS).max(1);
+    socket.set_read_timeout(Some(Duration::from_millis(timeout_ms))).unwrap();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    return match socket.recv_from(&mut buf) {
+        Ok((n, from)) => Result::Ok(serde_json::to_vec(&UdpResponseEnvelope { from: Some(from.to_string()), data: buf[..n].to_vec() }).unwrap()),
+        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+            Result::Err(make_error_bytes(format!("No response from {}:{} after {}ms", request_infos.host, request_infos.port, timeout_ms).as_str()))
+        },
+        Err(e) => Result::Err(make_error_bytes(format!("Cannot receive datagram from {}:{}: {}", request_infos.host, request_infos.port, e).as_str()))
+    };
+}
+
+pub fn process_udp_response(response_payload: &[u8], format: OutputFormat) {
+    let envelope = match serde_json::from_slice::<UdpResponseEnvelope>(response_payload) {
+        Ok(envelope) => envelope,
+        Err(_) => {
+            eprintln!("Cannot parse udp response");
+            std::process::exit(-1);
+        }
+    };
+
+    match format {
+        OutputFormat::Json => {
+            let (data, data_encoding) = make_json_output(&envelope.data, &None, false);
+            println!("{}", serde_json::json!({
+                "from": envelope.from,
+                "data": data,
+                "dataEncoding": data_encoding
+            }));
+        },
+        OutputFormat::Text | OutputFormat::Raw => {
+            /* Responses like DNS ones are binary */
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&envelope.data).unwrap();
+            stdout.flush().unwrap();
+        }
+    }
+}
+
+/// Parses `<host> <port> [<payload>] [--data <payload>|@<file>] [--timeout <seconds>] [--no-response]`
+pub fn make_udp_request(make_id: impl Fn() -> String, shell_id: &String, args: &Vec<String>) -> Request {
+    let mut args = args.clone();
+
+    let exit_with_error = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        std::process::exit(-1);
+    };
+
+    let data = consume_arg_value(&mut args, "--data").map(|data| match data.strip_prefix('@') {
+        Some(file_path) => std::fs::read(file_path).unwrap_or_else(|e| exit_with_error(format!("Cannot read payload file {}: {}", file_path, e))),
+        None => data.into_bytes()
+    });
+    let timeout_ms = consume_arg_value(&mut args, "--timeout").map(|timeout| match timeout.parse::<f64>() {
+        Ok(timeout) if timeout > 0.0 => (timeout * 1000.0) as u64,
+        _ => exit_with_error(format!("Invalid timeout: {}", timeout))
+    });
+    let no_response = consume_arg(&mut args, "--no-response");
+
+    if args.len() < 2 {
+        exit_with_error(String::from("Please specify the host and the port"));
+    }
+    let host = args[0].clone();
+    let port = args[1].parse::<u16>().unwrap_or_else(|_| exit_with_error(format!("Invalid port: {}", args[1])));
+    let payload = match (args.get(2), data) {
+        (Some(payload), _) => payload.clone().into_bytes(),
+        (None, Some(data)) => data,
+        (None, None) => exit_with_error(String::from("Please specify the payload"))
+    };
+
+    let udp_request = UdpCommandRequestBody { host, port, payload, timeout_ms, no_response };
+    let payload = serde_json::to_vec(&udp_request).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    };
+}
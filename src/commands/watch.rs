@@ -0,0 +1,390 @@
+/**
+ * hopo command <shell_id> watch <remote_path> [--recursive] [--debounce <ms>] [--events create,modify,delete,rename] [--for <seconds>]
+ */
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime}
+};
+
+use inotify::{Inotify, WatchMask, WatchDescriptor, EventMask};
+use serde::{Serialize, Deserialize};
+
+use crate::constants::{OutputFormat, STREAM_POLL_INTERVAL_MS, STREAM_HEARTBEAT_INTERVAL_MS};
+
+use super::{
+    request_or_response::{maybe_string, Request, make_shell_target, StatusCode},
+    response_stream::ResponseStream,
+    file_list::FileInfos,
+    command_error::make_error_bytes
+};
+
+pub const COMMAND_NAME: &str = "watch";
+
+const DEFAULT_DEBOUNCE_MS: u64 = 200;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchEventType {
+    Create,
+    Modify,
+    Delete,
+    Rename,
+    Overflow
+}
+
+impl WatchEventType {
+    pub fn maybe_from(v: &str) -> Option<Self> {
+        match v {
+            "create" => Some(WatchEventType::Create),
+            "modify" => Some(WatchEventType::Modify),
+            "delete" => Some(WatchEventType::Delete),
+            "rename" => Some(WatchEventType::Rename),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WatchCommandRequestBody {
+    path: String,
+    recursive: bool,
+    debounce_ms: u64,
+    /* All the event types when empty */
+    events: Vec<WatchEventType>,
+    duration_secs: Option<u64>
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchEvent {
+    pub event: WatchEventType,
+    pub timestamp: u64,
+    pub path: String,
+    pub old_path: Option<String>,
+    /* None when the file does not exist anymore */
+    pub file: Option<FileInfos>
+}
+
+struct PendingEvent {
+    event_type: WatchEventType,
+    path: PathBuf,
+    old_path: Option<PathBuf>,
+    last_seen: Instant
+}
+
+pub fn process_watch_command(
+    payload: &[u8],
+    response_stream: ResponseStream
+) -> Result<(), Vec<u8>> {
+    let text_payload = maybe_string(Some(payload));
+    if text_payload.is_none() {
+        return Result::Err(make_error_bytes("No text payload provided"));
+    }
+    let text_payload = text_payload.unwrap();
+
+    /* Decode and validate the text payload to json */
+    let json_payload = serde_json::from_str::<WatchCommandRequestBody>(text_payload.as_str());
+    if json_payload.is_err() {
+        return Result::Err(make_error_bytes(format!("Invalid json payload: {}", json_payload.err().unwrap().to_string()).as_str()));
+    }
+    let request_infos = json_payload.unwrap();
+
+    let root_path = PathBuf::from(String::from(shellexpand::tilde(request_infos.path.as_str())));
+    if !root_path.exists() {
+        return Result::Err(make_error_bytes(format!("Path {} does not exist", root_path.to_str().unwrap()).as_str()));
+    }
+
+    let inotify = Inotify::init();
+    if let Err(e) = inotify {
+        return Result::Err(make_error_bytes(format!("Cannot initialize inotify: {}", e.to_string()).as_str()));
+    }
+    let mut inotify = inotify.unwrap();
+
+    let mut watched_folders: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    if let Err(e) = add_watches(&mut inotify, &root_path, request_infos.recursive, &mut watched_folders) {
+        return Result::Err(make_error_bytes(format!("Cannot watch {}: {}", root_path.to_str().unwrap(), e.to_string()).as_str()));
+    }
+
+    eprintln!("[{}] Watch {:?} ({} watches)", response_stream.message_id(), root_path, watched_folders.len());
+
+    thread::spawn(move || {
+        run_watch(inotify, watched_folders, request_infos, response_stream);
+    });
+
+    return Result::Ok(());
+}
+
+fn add_watches(
+    inotify: &mut Inotify,
+    path: &Path,
+    recursive: bool,
+    watched_folders: &mut HashMap<WatchDescriptor, PathBuf>
+) -> std::io::Result<()> {
+    /* Sub-folders creations and moves are always watched, to keep track of the recursive watches */
+    let mask = WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::ATTRIB
+        | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO | WatchMask::DONT_FOLLOW;
+
+    let wd = inotify.watches().add(path, mask)?;
+    watched_folders.insert(wd, path.to_path_buf());
+
+    if recursive && path.is_dir() {
+        if let Ok(files_in_folder) = std::fs::read_dir(path) {
+            for f in files_in_folder {
+                let f = match f {
+                    Err(_) => { continue },
+                    Ok(f) => f
+                };
+                let is_dir = match f.file_type() {
+                    Ok(file_type) => file_type.is_dir(),
+                    Err(_) => false
+                };
+                if is_dir {
+                    if let Err(e) = add_watches(inotify, &f.path(), recursive, watched_folders) {
+                        eprintln!("Cannot watch {:?}: {}", f.path(), e);
+                    }
+                }
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn run_watch(
+    mut inotify: Inotify,
+    mut watched_folders: HashMap<WatchDescriptor, PathBuf>,
+    request_infos: WatchCommandRequestBody,
+    response_stream: ResponseStream
+) {
+    let debounce = Duration::from_millis(request_infos.debounce_ms);
+    let deadline = request_infos.duration_secs.map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    let mut buffer = [0u8; 4096];
+    let mut pending_events: Vec<PendingEvent> = vec![];
+    /* Moves are reported as two events sharing a cookie */
+    let mut moved_from: HashMap<u32, (PathBuf, Instant)> = HashMap::new();
+    let mut last_sent = Instant::now();
+
+    loop {
+        if response_stream.is_cancelled() {
+            eprintln!("[{}] Watch has been cancelled", response_stream.message_id());
+            break;
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() > deadline { break; }
+        }
+
+        match inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                let now = Instant::now();
+                let mut new_folders = vec![];
+
+                for event in events {
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        push_pending_event(&mut pending_events, WatchEventType::Overflow, PathBuf::new(), None, now);
+                        continue;
+                    }
+                    if event.mask.contains(EventMask::IGNORED) {
+                        watched_folders.remove(&event.wd);
+                        continue;
+                    }
+
+                    let folder_path = match watched_folders.get(&event.wd) {
+                        Some(folder_path) => folder_path.clone(),
+                        None => { continue }
+                    };
+                    let path = match event.name {
+                        Some(name) => folder_path.join(name),
+                        None => folder_path
+                    };
+                    let is_dir = event.mask.contains(EventMask::ISDIR);
+
+                    if event.mask.contains(EventMask::MOVED_FROM) {
+                        moved_from.insert(event.cookie, (path, now));
+                    } else if event.mask.contains(EventMask::MOVED_TO) {
+                        match moved_from.remove(&event.cookie) {
+                            Some((old_path, _)) => {
+                                if is_dir {
+                                    rename_watched_folders(&mut watched_folders, &old_path, &path);
+                                }
+                                push_pending_event(&mut pending_events, WatchEventType::Rename, path, Some(old_path), now);
+                            },
+                            None => {
+                                /* Moved from outside of the watched folders */
+                                if is_dir && request_infos.recursive { new_folders.push(path.clone()); }
+                                push_pending_event(&mut pending_events, WatchEventType::Create, path, None, now);
+                            }
+                        }
+                    } else if event.mask.contains(EventMask::CREATE) {
+                        if is_dir && request_infos.recursive { new_folders.push(path.clone()); }
+                        push_pending_event(&mut pending_events, WatchEventType::Create, path, None, now);
+                    } else if event.mask.contains(EventMask::DELETE) {
+                        push_pending_event(&mut pending_events, WatchEventType::Delete, path, None, now);
+                    } else if event.mask.intersects(EventMask::MODIFY | EventMask::CLOSE_WRITE | EventMask::ATTRIB) {
+                        push_pending_event(&mut pending_events, WatchEventType::Modify, path, None, now);
+                    }
+                }
+
+                for folder_path in new_folders {
+                    if let Err(e) = add_watches(&mut inotify, &folder_path, true, &mut watched_folders) {
+                        eprintln!("[{}] Cannot watch {:?}: {}", response_stream.message_id(), folder_path, e);
+                    }
+                }
+            },
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    response_stream.fail(StatusCode::InternalError, format!("Cannot read inotify events: {}", e.to_string()).as_str());
+                    return;
+                }
+                thread::sleep(Duration::from_millis(STREAM_POLL_INTERVAL_MS));
+            }
+        }
+
+        /* Moves without a matching destination went outside of the watched folders */
+        let expired_cookies: Vec<u32> = moved_from.iter()
+            .filter(|(_, (_, seen))| seen.elapsed() >= debounce)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        for cookie in expired_cookies {
+            let (path, seen) = moved_from.remove(&cookie).unwrap();
+            push_pending_event(&mut pending_events, WatchEventType::Delete, path, None, seen);
+        }
+
+        if send_pending_events(&mut pending_events, &request_infos.events, debounce, &response_stream) {
+            last_sent = Instant::now();
+        }
+
+        if last_sent.elapsed() > Duration::from_millis(STREAM_HEARTBEAT_INTERVAL_MS) {
+            response_stream.send_heartbeat();
+            last_sent = Instant::now();
+        }
+    }
+
+    send_pending_events(&mut pending_events, &request_infos.events, Duration::ZERO, &response_stream);
+    response_stream.finish(None);
+}
+
+/// Events on a path that already has a pending event are merged with it
+fn push_pending_event(
+    pending_events: &mut Vec<PendingEvent>,
+    event_type: WatchEventType,
+    path: PathBuf,
+    old_path: Option<PathBuf>,
+    now: Instant
+) {
+    let pending_event = pending_events.iter_mut().find(|e| e.path == path && old_path.is_none() && match (e.event_type, event_type) {
+        (WatchEventType::Create, WatchEventType::Modify) => true,
+        (a, b) => a == b
+    });
+
+    match pending_event {
+        Some(pending_event) => {
+            pending_event.last_seen = now;
+        },
+        None => {
+            pending_events.push(PendingEvent { event_type, path, old_path, last_seen: now });
+        }
+    }
+}
+
+/// Sends the events that have not changed for the debounce duration. Returns true if any was sent.
+fn send_pending_events(
+    pending_events: &mut Vec<PendingEvent>,
+    event_types: &Vec<WatchEventType>,
+    debounce: Duration,
+    response_stream: &ResponseStream
+) -> bool {
+    let mut has_sent = false;
+
+    let mut i = 0;
+    while i < pending_events.len() {
+        if pending_events[i].last_seen.elapsed() < debounce {
+            i += 1;
+            continue;
+        }
+        let pending_event = pending_events.remove(i);
+
+        let is_requested = event_types.is_empty()
+            || pending_event.event_type == WatchEventType::Overflow
+            || event_types.contains(&pending_event.event_type);
+        if !is_requested { continue; }
+
+        let file = match pending_event.path.symlink_metadata() {
+            Ok(infos) => Some(FileInfos::from_metadata(infos, String::from(pending_event.path.to_str().unwrap_or("")))),
+            Err(_) => None
+        };
+        let event = WatchEvent {
+            event: pending_event.event_type,
+            timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+            path: String::from(pending_event.path.to_str().unwrap_or("")),
+            old_path: pending_event.old_path.map(|p| String::from(p.to_str().unwrap_or(""))),
+            file
+        };
+        response_stream.send_event(&serde_json::to_value(event).unwrap());
+        has_sent = true;
+    }
+
+    return has_sent;
+}
+
+fn rename_watched_folders(watched_folders: &mut HashMap<WatchDescriptor, PathBuf>, old_path: &Path, new_path: &Path) {
+    for folder_path in watched_folders.values_mut() {
+        if let Ok(relative_path) = folder_path.strip_prefix(old_path) {
+            *folder_path = new_path.join(relative_path);
+        }
+    }
+}
+
+pub fn process_watch_event(event: serde_json::Value, format: OutputFormat) {
+    match format {
+        OutputFormat::Json | OutputFormat::Raw => {
+            println!("{}", event);
+        },
+        OutputFormat::Text => {
+            let event = match serde_json::from_value::<WatchEvent>(event) {
+                Ok(event) => event,
+                Err(_) => {
+                    eprintln!("Failed to parse watch event");
+                    return;
+                }
+            };
+            let event_name = serde_json::to_value(event.event).unwrap();
+            let event_name = event_name.as_str().unwrap_or("");
+            match event.old_path {
+                Some(old_path) => println!("{} {} {} -> {}", event.timestamp, event_name, old_path, event.path),
+                None => println!("{} {} {}", event.timestamp, event_name, event.path)
+            }
+        }
+    }
+}
+
+pub fn make_watch_request(
+    make_id: impl Fn() -> String,
+    shell_id: &String,
+    path: &String,
+    recursive: bool,
+    debounce_ms: Option<u64>,
+    events: Vec<WatchEventType>,
+    duration_secs: Option<u64>
+) -> Request {
+    let watch_request = WatchCommandRequestBody {
+        path: path.clone(),
+        recursive,
+        debounce_ms: debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS),
+        events,
+        duration_secs
+    };
+
+    let payload = serde_json::to_vec(&watch_request).unwrap();
+
+    return Request {
+        cmd: COMMAND_NAME.to_string(),
+        message_id: make_id(),
+        target: make_shell_target(shell_id),
+        payload
+    }
+}
+
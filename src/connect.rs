@@ -16,7 +16,7 @@ use regex::Regex;
 use expect_exit::{Expected};
 
 use openssl::{ssl::{self, SslConnector, SslFiletype}};
-use crate::{commands::resize::make_size_message, constants::WAIT_TIME_RETRY_CNX_MS};
+use crate::{commands::{resize::make_size_message, command_processor::CommandSettings, schedule}, constants::WAIT_TIME_RETRY_CNX_MS};
 
 use super::constants::BUF_SIZE;
 
@@ -104,7 +104,8 @@ pub fn main_connect(args: Args) {
         &args.cmd,
         args.default_cols, args.default_rows,
         tx_to_stream, rx_cmd,
-        history_of_messages_to_stream.clone()
+        history_of_messages_to_stream.clone(),
+        CommandSettings::from_args(&args)
     );
 
     if let Err(_) = &master_pty {
@@ -113,6 +114,8 @@ pub fn main_connect(args: Args) {
 
     let master_pty = master_pty.unwrap();
 
+    schedule::start_scheduler(&args.hoposhell_folder_path);
+
     let hostname = compute_hostname(&args.server_url);
 
     let ssl_connector = if args.use_ssl {
@@ -8,7 +8,11 @@ pub const MESSAGE_PARTS_SEPARATOR: u8 = b'/';
 
 pub const WAIT_TIME_RETRY_CNX_MS: u64 = 100;
 
+pub const STREAM_POLL_INTERVAL_MS: u64 = 100;
+pub const STREAM_HEARTBEAT_INTERVAL_MS: u64 = 10000;
+
 pub const SCRIPTS_FOLDER_NAME: &str = "scripts";
+pub const JOBS_FOLDER_NAME: &str = "jobs";
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum OutputFormat {
@@ -0,0 +1,422 @@
+/**
+ * hopo forward [start] [<name>...] [--config <path>]
+ * hopo forward list|status [--config <path>]
+ * hopo forward add <name> <shell_id> [<bind address>:]<local port>|unix:<path> <host> <remote port>|unix:<path> [--udp] [--disabled] [--max-connections <n>] [--idle-timeout <secs>]
+ * hopo forward remove|enable|disable <name>
+ *
+ * The forwards are declared in <hoposhell folder>/forwards.json:
+ *     { "forwards": [{ "name": "db", "shell": "prod", "localPort": 5432, "host": "localhost", "remotePort": 5432 }] }
+ * `hopo forward` runs all the forwards that are not disabled, or the given ones, in the same process.
+ * It writes their status and their active tunnels to forwards.status.json every few seconds, for `hopo forward status`.
+ * Enabling or disabling a forward applies the next time `hopo forward` starts.
+ */
+
+use std::{
+    collections::HashMap,
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime}
+};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    args::{Args, consume_arg, consume_arg_value},
+    commands::{
+        tunnel::TunnelProtocol,
+        socket_stream::{SocketListener, format_destination, unix_socket_path}
+    },
+    constants::OutputFormat,
+    forward_tcp::{self, ForwarderHandle, TunnelStatus},
+    forward_udp::{UdpForwarderHandle, DEFAULT_IDLE_TIMEOUT_SECS}
+};
+
+const FORWARDS_FILE_NAME: &str = "forwards.json";
+const DEFAULT_BIND_ADDRESS: &str = "localhost";
+const STATUS_INTERVAL_SECS: u64 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardConfig {
+    pub name: String,
+    /* The shell that opens the connections */
+    pub shell: String,
+    /* localhost by default, or unix:<path> to listen on a Unix socket */
+    #[serde(default)]
+    pub bind_address: Option<String>,
+    #[serde(default)]
+    pub local_port: Option<u16>,
+    /* Or unix:<path> for a Unix socket of the shell */
+    pub host: String,
+    #[serde(default)]
+    pub remote_port: Option<u16>,
+    #[serde(default)]
+    pub protocol: TunnelProtocol,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    /* Udp only */
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>
+}
+
+impl ForwardConfig {
+    fn validate(&self) -> Result<(), String> {
+        let is_local_unix = self.bind_address.as_ref().map_or(false, |bind_address| unix_socket_path(bind_address).is_some());
+        let is_remote_unix = unix_socket_path(&self.host).is_some();
+        if self.protocol == TunnelProtocol::Udp && (is_local_unix || is_remote_unix) {
+            return Err(format!("Forward {}: Unix sockets cannot be used with udp", self.name));
+        }
+        if !is_local_unix && self.local_port.is_none() {
+            return Err(format!("Forward {}: please specify the local port", self.name));
+        }
+        if !is_remote_unix && self.remote_port.is_none() {
+            return Err(format!("Forward {}: please specify the remote port", self.name));
+        }
+        return Ok(());
+    }
+
+    fn local_address(&self) -> String {
+        let bind_address = self.bind_address.clone().unwrap_or(String::from(DEFAULT_BIND_ADDRESS));
+        return match unix_socket_path(&bind_address) {
+            Some(_) => bind_address,
+            None => format!("{}:{}", bind_address, self.local_port.unwrap_or(0))
+        };
+    }
+
+    fn describe(&self) -> String {
+        let protocol = serde_json::to_value(self.protocol).unwrap();
+        return format!(
+            "{} -> {} {} ({})",
+            self.local_address(),
+            self.shell,
+            format_destination(&self.host, self.remote_port.unwrap_or(0)),
+            protocol.as_str().unwrap_or("")
+        );
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ForwardsFile {
+    #[serde(default)]
+    forwards: Vec<ForwardConfig>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardState {
+    Running,
+    Failed,
+    /* Not run by a `hopo forward` process */
+    Stopped,
+    Disabled
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardStatus {
+    pub state: ForwardState,
+    pub error: Option<String>,
+    pub tunnels: Vec<TunnelStatus>
+}
+
+/// Written by the `hopo forward` process
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ForwardsStatusFile {
+    pid: u32,
+    updated_at: u64,
+    forwards: HashMap<String, ForwardStatus>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForwardInfos {
+    #[serde(flatten)]
+    pub forward: ForwardConfig,
+    #[serde(flatten)]
+    pub status: ForwardStatus
+}
+
+enum RunningForward {
+    Tcp(Arc<ForwarderHandle>),
+    Udp(UdpForwarderHandle),
+    Failed(String)
+}
+
+impl RunningForward {
+    fn status(&self) -> ForwardStatus {
+        return match self {
+            RunningForward::Tcp(forwarder) => ForwardStatus { state: ForwardState::Running, error: None, tunnels: forwarder.status() },
+            RunningForward::Udp(forwarder) => ForwardStatus { state: ForwardState::Running, error: None, tunnels: forwarder.status() },
+            RunningForward::Failed(error) => ForwardStatus { state: ForwardState::Failed, error: Some(error.clone()), tunnels: vec![] }
+        };
+    }
+}
+
+pub fn main_forward(args: Args) {
+    let mut forward_args = args.extra_args.clone();
+    let forwards_file_path = match consume_arg_value(&mut forward_args, "--config") {
+        Some(config_path) => PathBuf::from(config_path),
+        None => Path::new(&args.hoposhell_folder_path).join(FORWARDS_FILE_NAME)
+    };
+
+    let operation = if forward_args.is_empty() { String::from("start") } else { forward_args.remove(0) };
+    let res = match operation.as_str() {
+        "start" => {
+            start_forwards(&args, &forwards_file_path, &forward_args);
+            return;
+        },
+        "list" => list_forwards(&forwards_file_path, args.format),
+        "status" => show_status(&forwards_file_path, args.format),
+        "add" => add_forward(&forwards_file_path, forward_args),
+        "remove" => change_forward(&forwards_file_path, &forward_args, |forwards, index| { forwards.remove(index); }),
+        "enable" => change_forward(&forwards_file_path, &forward_args, |forwards, index| forwards[index].disabled = false),
+        "disable" => change_forward(&forwards_file_path, &forward_args, |forwards, index| forwards[index].disabled = true),
+        _ => Err(format!("Unknown forward operation: {}, expected start, list, status, add, remove, enable or disable", operation))
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    }
+}
+
+/// Runs the forwards until the process is stopped
+fn start_forwards(args: &Args, forwards_file_path: &Path, names: &Vec<String>) {
+    let forwards_file = read_forwards_file(forwards_file_path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(-1);
+    });
+    for name in names.iter() {
+        if !forwards_file.forwards.iter().any(|forward| &forward.name == name) {
+            eprintln!("Unable to find a forward with name: {}", name);
+            std::process::exit(-1);
+        }
+    }
+    /* The named forwards are run even when they are disabled */
+    let forwards: Vec<ForwardConfig> = forwards_file.forwards.into_iter()
+        .filter(|forward| if names.is_empty() { !forward.disabled } else { names.contains(&forward.name) })
+        .collect();
+    if forwards.is_empty() {
+        eprintln!("No forward to run in {}", forwards_file_path.display());
+        std::process::exit(-1);
+    }
+
+    let running_forwards: Vec<(String, RunningForward)> = forwards.iter().map(|forward| {
+        let running_forward = match start_forward(args, forward) {
+            Ok(running_forward) => {
+                eprintln!("Forward {}: {}", forward.name, forward.describe());
+                running_forward
+            },
+            Err(e) => {
+                eprintln!("Forward {} failed: {}", forward.name, e);
+                RunningForward::Failed(e)
+            }
+        };
+        return (forward.name.clone(), running_forward);
+    }).collect();
+
+    let status_file_path = get_status_file_path(forwards_file_path);
+    loop {
+        let status_file = ForwardsStatusFile {
+            pid: std::process::id(),
+            updated_at: now_secs(),
+            forwards: running_forwards.iter().map(|(name, running_forward)| (name.clone(), running_forward.status())).collect()
+        };
+        if let Err(e) = std::fs::write(&status_file_path, serde_json::to_vec_pretty(&status_file).unwrap()) {
+            eprintln!("Cannot write forwards status file: {}", e);
+        }
+        thread::sleep(Duration::from_secs(STATUS_INTERVAL_SECS));
+    }
+}
+
+fn start_forward(args: &Args, forward: &ForwardConfig) -> Result<RunningForward, String> {
+    forward.validate()?;
+
+    let mut forward_args = args.clone();
+    forward_args.shell_name = Some(forward.shell.clone());
+    /* Like with forward-tcp, the key of the shell is used by default */
+    if forward_args.shell_key_path.is_none() {
+        forward_args.shell_key_path = Some(format!("{}/{}.pem", args.hoposhell_folder_path, forward.shell));
+    }
+    let max_connections = forward.max_connections.unwrap_or(forward_tcp::DEFAULT_MAX_CONNECTIONS);
+    let local_address = forward.local_address();
+    let remote_port = forward.remote_port.unwrap_or(0);
+
+    return match forward.protocol {
+        TunnelProtocol::Tcp => {
+            let listener = SocketListener::bind(&local_address).map_err(|e| format!("Cannot listen on {}: {}", local_address, e))?;
+            let forwarder = Arc::new(ForwarderHandle::start(forward_args, max_connections));
+            {
+                let forwarder = forwarder.clone();
+                let host = forward.host.clone();
+                thread::spawn(move || forward_tcp::accept_connections(&listener, &forwarder, &host, remote_port));
+            }
+            Ok(RunningForward::Tcp(forwarder))
+        },
+        TunnelProtocol::Udp => {
+            let socket = UdpSocket::bind(&local_address).map_err(|e| format!("Cannot listen on {}: {}", local_address, e))?;
+            let idle_timeout = Duration::from_secs(forward.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS));
+            Ok(RunningForward::Udp(UdpForwarderHandle::start(forward_args, socket, forward.host.clone(), remote_port, idle_timeout, max_connections)))
+        }
+    };
+}
+
+fn list_forwards(forwards_file_path: &Path, format: OutputFormat) -> Result<(), String> {
+    let forwards_file = read_forwards_file(forwards_file_path)?;
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&forwards_file.forwards).unwrap());
+        return Ok(());
+    }
+    for forward in forwards_file.forwards.iter() {
+        println!("{} {}{}", forward.name, forward.describe(), if forward.disabled { " disabled" } else { "" });
+    }
+    return Ok(());
+}
+
+fn show_status(forwards_file_path: &Path, format: OutputFormat) -> Result<(), String> {
+    let forwards_file = read_forwards_file(forwards_file_path)?;
+    /* The status of a process that is not running anymore is ignored */
+    let mut status = read_status_file(forwards_file_path)
+        .filter(|status_file| is_process_running(status_file.pid) && now_secs() <= status_file.updated_at + 3 * STATUS_INTERVAL_SECS)
+        .map(|status_file| status_file.forwards)
+        .unwrap_or_default();
+
+    let all_infos: Vec<ForwardInfos> = forwards_file.forwards.into_iter().map(|forward| {
+        let status = match status.remove(&forward.name) {
+            Some(status) => status,
+            None => ForwardStatus {
+                state: if forward.disabled { ForwardState::Disabled } else { ForwardState::Stopped },
+                error: None,
+                tunnels: vec![]
+            }
+        };
+        return ForwardInfos { forward, status };
+    }).collect();
+
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&all_infos).unwrap());
+        return Ok(());
+    }
+    for infos in all_infos.iter() {
+        let state = serde_json::to_value(infos.status.state).unwrap();
+        let state = state.as_str().unwrap_or("");
+        match (&infos.status.error, infos.status.state) {
+            (Some(error), _) => println!("{} {}: {}", infos.forward.name, state, error),
+            (None, ForwardState::Running) => println!("{} {} {}, {} active tunnels", infos.forward.name, state, infos.forward.describe(), infos.status.tunnels.len()),
+            (None, _) => println!("{} {}", infos.forward.name, state)
+        }
+        for tunnel in infos.status.tunnels.iter() {
+            println!(
+                "    [{}] {}: sent {} bytes, received {} bytes in {:.3}s",
+                tunnel.tunnel_id,
+                tunnel.peer,
+                tunnel.bytes_sent,
+                tunnel.bytes_received,
+                tunnel.duration_ms as f64 / 1000.0
+            );
+        }
+    }
+    return Ok(());
+}
+
+/// Parses `<name> <shell_id> [<bind address>:]<local port>|unix:<path> <host> <remote port>|unix:<path> [--udp] [--disabled] [--max-connections <n>] [--idle-timeout <secs>]`
+fn add_forward(forwards_file_path: &Path, mut add_args: Vec<String>) -> Result<(), String> {
+    let protocol = if consume_arg(&mut add_args, "--udp") { TunnelProtocol::Udp } else { TunnelProtocol::Tcp };
+    let disabled = consume_arg(&mut add_args, "--disabled");
+    let max_connections = match consume_arg_value(&mut add_args, "--max-connections") {
+        Some(max_connections) => Some(max_connections.parse::<usize>().map_err(|_| format!("Invalid max connections: {}", max_connections))?),
+        None => None
+    };
+    let idle_timeout_secs = match consume_arg_value(&mut add_args, "--idle-timeout") {
+        Some(idle_timeout) => Some(idle_timeout.parse::<u64>().map_err(|_| format!("Invalid idle timeout: {}", idle_timeout))?),
+        None => None
+    };
+    if add_args.len() < 4 {
+        return Err(String::from("Usage: hopo forward add <name> <shell_id> [<bind address>:]<local port>|unix:<path> <host> <remote port>|unix:<path> [--udp] [--disabled] [--max-connections <n>] [--idle-timeout <secs>]"));
+    }
+
+    let local = &add_args[2];
+    let (bind_address, local_port) = match (unix_socket_path(local), local.rsplit_once(':')) {
+        (Some(_), _) => (Some(local.clone()), None),
+        (None, Some((bind_address, local_port))) => (Some(bind_address.to_string()), Some(local_port)),
+        (None, None) => (None, Some(local.as_str()))
+    };
+    let local_port = match local_port {
+        Some(local_port) => Some(local_port.parse::<u16>().map_err(|_| format!("Invalid local port: {}", local_port))?),
+        None => None
+    };
+    let host = add_args[3].clone();
+    let remote_port = match add_args.get(4) {
+        Some(remote_port) => Some(remote_port.parse::<u16>().map_err(|_| format!("Invalid remote port: {}", remote_port))?),
+        None => None
+    };
+
+    let forward = ForwardConfig {
+        name: add_args[0].clone(),
+        shell: add_args[1].clone(),
+        bind_address,
+        local_port,
+        host,
+        remote_port,
+        protocol,
+        disabled,
+        max_connections,
+        idle_timeout_secs
+    };
+    forward.validate()?;
+
+    let mut forwards_file = read_forwards_file(forwards_file_path)?;
+    if forwards_file.forwards.iter().any(|x| x.name == forward.name) {
+        return Err(format!("A forward with name {} already exists", forward.name));
+    }
+    eprintln!("Add forward {}: {}", forward.name, forward.describe());
+    forwards_file.forwards.push(forward);
+    return write_forwards_file(forwards_file_path, &forwards_file);
+}
+
+fn change_forward(forwards_file_path: &Path, names: &Vec<String>, change: impl Fn(&mut Vec<ForwardConfig>, usize)) -> Result<(), String> {
+    let name = names.first().ok_or_else(|| String::from("Please specify the name of the forward"))?;
+    let mut forwards_file = read_forwards_file(forwards_file_path)?;
+    let index = forwards_file.forwards.iter().position(|x| &x.name == name).ok_or_else(|| format!("Unable to find a forward with name: {}", name))?;
+    change(&mut forwards_file.forwards, index);
+    return write_forwards_file(forwards_file_path, &forwards_file);
+}
+
+fn read_forwards_file(forwards_file_path: &Path) -> Result<ForwardsFile, String> {
+    if !forwards_file_path.exists() {
+        return Ok(ForwardsFile::default());
+    }
+    let contents = std::fs::read(forwards_file_path).map_err(|e| format!("Cannot read forwards file: {}", e))?;
+    return serde_json::from_slice::<ForwardsFile>(&contents).map_err(|e| format!("Invalid forwards file {}: {}", forwards_file_path.display(), e));
+}
+
+fn write_forwards_file(forwards_file_path: &Path, forwards_file: &ForwardsFile) -> Result<(), String> {
+    let contents = serde_json::to_vec_pretty(forwards_file).unwrap();
+    return std::fs::write(forwards_file_path, contents).map_err(|e| format!("Cannot write forwards file: {}", e));
+}
+
+/// forwards.json -> forwards.status.json
+fn get_status_file_path(forwards_file_path: &Path) -> PathBuf {
+    let file_name = forwards_file_path.file_name().and_then(|x| x.to_str()).unwrap_or(FORWARDS_FILE_NAME);
+    let stem = file_name.strip_suffix(".json").unwrap_or(file_name);
+    return forwards_file_path.with_file_name(format!("{}.status.json", stem));
+}
+
+/// The status is only informative: it is none when it cannot be read
+fn read_status_file(forwards_file_path: &Path) -> Option<ForwardsStatusFile> {
+    return std::fs::read(get_status_file_path(forwards_file_path)).ok()
+        .and_then(|contents| serde_json::from_slice::<ForwardsStatusFile>(&contents).ok());
+}
+
+fn is_process_running(pid: u32) -> bool {
+    return unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+}
+
+fn now_secs() -> u64 {
+    return SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+}
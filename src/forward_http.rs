@@ -0,0 +1,216 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration
+};
+
+use crate::{
+    args::Args,
+    commands::{http::{self, HttpVerb, HttpCommandRequestBody, HttpResponseEnvelope}, send_command_handler::send_command_request},
+    make_random_id
+};
+
+/* Headers that only make sense between the browser and this proxy */
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "te", "trailer", "transfer-encoding", "upgrade", "host"
+];
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/* The body is sent to the shell in a single request */
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+const CLIENT_READ_TIMEOUT_SECS: u64 = 30;
+
+struct LocalHttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>
+}
+
+pub fn main_forward_http(args: Args) {
+    /* Serves a local port with the responses of an http server that only a remote shell can access */
+
+    /* hopo forward-http <shell_id> <local port> <remote base url> */
+
+    let usage = "Usage: hopo forward-http <shell_id> <local port> <remote base url>";
+    if args.extra_args.len() < 2 {
+        eprintln!("{}", usage);
+        std::process::exit(-1);
+    }
+    let local_port = args.extra_args[0].parse::<u16>().unwrap_or_else(|_| {
+        eprintln!("{}", usage);
+        std::process::exit(-1);
+    });
+    let base_url = args.extra_args[1].trim_end_matches('/').to_string();
+
+    let listener = TcpListener::bind(format!("localhost:{}", local_port)).unwrap_or_else(|e| {
+        eprintln!("Cannot listen on {}: {}", local_port, e);
+        std::process::exit(-1);
+    });
+    eprintln!("Forward http://localhost:{} to {} through shell {}", local_port, base_url, args.shell_name.as_ref().unwrap());
+
+    let args = Arc::new(args);
+    let base_url = Arc::new(base_url);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        /* Each browser connection is served by its own thread */
+        let args = args.clone();
+        let base_url = base_url.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_http_connection(&args, stream, &base_url, local_port) {
+                eprintln!("Failed to forward http request: {}", e);
+            }
+        });
+    }
+}
+
+fn handle_http_connection(args: &Args, stream: TcpStream, base_url: &String, local_port: u16) -> Result<(), std::io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(CLIENT_READ_TIMEOUT_SECS)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let local_req = match read_http_request(&mut reader) {
+        Ok(local_req) => local_req,
+        Err((status, reason, e)) => {
+            return write_error_response(&mut stream, status, reason, e.as_str());
+        }
+    };
+
+    let verb = match HttpVerb::maybe_from(&local_req.method) {
+        Some(verb) => verb,
+        None => {
+            return write_error_response(&mut stream, 405, "Method Not Allowed", format!("Unsupported method: {}", local_req.method).as_str());
+        }
+    };
+
+    /* The redirections to the remote server are rewritten to this proxy */
+    let local_base_url = match local_req.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("host")) {
+        Some((_, host)) => format!("http://{}", host),
+        None => format!("http://localhost:{}", local_port)
+    };
+
+    let headers: Vec<(String, String)> = local_req.headers.into_iter()
+        .filter(|(name, _)| !HOP_BY_HOP_HEADERS.contains(&name.to_lowercase().as_str()))
+        .collect();
+
+    let url = format!("{}{}", base_url, local_req.path);
+    eprintln!("{} {}", local_req.method, url);
+
+    let shell_id = args.shell_name.clone().unwrap();
+    let make_id = || {
+        let random_str = make_random_id(8);
+        return format!("{}:{}", &shell_id, random_str)
+    };
+    let mut request_body = HttpCommandRequestBody::new(verb, url);
+    request_body.headers = headers;
+    /* Redirections are for the browser to follow */
+    request_body.max_redirects = Some(0);
+    if !local_req.body.is_empty() {
+        request_body.set_body_bytes(local_req.body);
+    }
+    let req = http::make_http_command_request(make_id, &shell_id, &request_body);
+
+    let res = match send_command_request(args, &req) {
+        Ok(res) => res,
+        Err(e) => {
+            return write_error_response(&mut stream, 502, "Bad Gateway", format!("Failed to forward the request: {}", e).as_str());
+        }
+    };
+    let envelope = match serde_json::from_slice::<HttpResponseEnvelope>(&res.payload) {
+        Ok(envelope) => envelope,
+        Err(_) => {
+            return write_error_response(&mut stream, 502, "Bad Gateway", "Cannot parse http response");
+        }
+    };
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", envelope.status, envelope.reason);
+    for header in envelope.headers.iter() {
+        let name = header.name.to_lowercase();
+        if HOP_BY_HOP_HEADERS.contains(&name.as_str()) || name == "content-length" { continue; }
+        let value = match (name.as_str(), header.value.strip_prefix(base_url.as_str())) {
+            ("location", Some(path)) if path.is_empty() || path.starts_with(['/', '?', '#']) => format!("{}{}", local_base_url, path),
+            _ => header.value.clone()
+        };
+        head.push_str(format!("{}: {}\r\n", header.name, value).as_str());
+    }
+    head.push_str(format!("Content-Length: {}\r\nConnection: close\r\n\r\n", envelope.body.len()).as_str());
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&envelope.body)?;
+    return stream.flush();
+}
+
+/// Fails with the status of the error response
+fn read_http_request(reader: &mut impl BufRead) -> Result<LocalHttpRequest, (u16, &'static str, String)> {
+    let bad_request = |e: String| (400, "Bad Request", e);
+
+    let mut head_size = 0;
+    let mut read_line = |reader: &mut dyn BufRead| -> Result<String, String> {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => { return Err(String::from("Connection closed")) },
+            Ok(n) => { head_size += n },
+            Err(e) => { return Err(format!("Cannot read request: {}", e)) }
+        }
+        if head_size > MAX_HEAD_SIZE {
+            return Err(String::from("Request head is too big"));
+        }
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    };
+
+    let request_line = read_line(reader).map_err(bad_request)?;
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || !parts[1].starts_with('/') {
+        return Err(bad_request(format!("Invalid request line: {}", request_line)));
+    }
+
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader).map_err(bad_request)?;
+        if line.is_empty() { break; }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+            None => { return Err(bad_request(format!("Invalid header: {}", line))) }
+        }
+    }
+
+    let get_header = |name: &str| headers.iter().find(|(x, _)| x.eq_ignore_ascii_case(name)).map(|(_, value)| value.clone());
+    if get_header("transfer-encoding").is_some() {
+        return Err(bad_request(String::from("Chunked request bodies are not supported")));
+    }
+    let content_length = match get_header("content-length") {
+        Some(content_length) => content_length.parse::<usize>().map_err(|_| bad_request(format!("Invalid content length: {}", content_length)))?,
+        None => 0
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err((413, "Payload Too Large", format!("Request body of {} bytes is bigger than {} bytes", content_length, MAX_BODY_SIZE)));
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).map_err(|e| bad_request(format!("Cannot read request body: {}", e)))?;
+
+    return Ok(LocalHttpRequest {
+        method: parts[0].to_string(),
+        path: parts[1].to_string(),
+        headers,
+        body
+    });
+}
+
+fn write_error_response(stream: &mut TcpStream, status: u16, reason: &str, msg: &str) -> Result<(), std::io::Error> {
+    eprintln!("{} {}: {}", status, reason, msg);
+    let body = format!("{}\n", msg);
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, reason, body.len());
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    return stream.flush();
+}
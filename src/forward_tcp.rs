@@ -1,78 +1,578 @@
-use std::{net::TcpListener, io::{Read, Write}, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::Shutdown,
+    sync::{Arc, atomic::{AtomicUsize, Ordering}, mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError}},
+    thread,
+    time::{Duration, Instant}
+};
 
-use crate::{args::Args, commands::{tcp, send_command_handler::{connect_to_hoposhell, send_request_and_get_response}, request_or_response::StatusCode}, connect::compute_hostname, make_random_id, constants::BUF_SIZE};
+use serde::{Serialize, Deserialize};
 
-pub fn main_forward_tcp(args: Args) {
-    /* Create a server that forwards all access to a port
-     * to a remote shell */
+use crate::{
+    args::{Args, consume_arg_value},
+    commands::{
+        tunnel::{self, TunnelCommandRequestBody, TunnelEvent, TunnelInput, TunnelProtocol},
+        socket_stream::{SocketListener, SocketStream, format_destination, unix_socket_path},
+        send_command_handler::{connect_to_hoposhell, send_request, send_request_chunks},
+        request_or_response::{ChunkedRequestOrResponse, ChunkType, StatusCode}
+    },
+    connect::{compute_hostname, read_messages_from_stream, ReadMessageResult},
+    make_random_id,
+    constants::{BUF_SIZE, OutputFormat}
+};
+
+/* Short, because the bytes of the local connections are only sent between two reads */
+pub const TUNNEL_POLL_INTERVAL_MS: u64 = 10;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+/* Chunks read from the local connections that wait to be sent, for all of them: the readers wait when it is full */
+pub const LOCAL_READ_QUEUE_SIZE: usize = 256;
+/* Chunks from the shell that wait to be written to one local connection: it is closed when it is full */
+const LOCAL_WRITE_QUEUE_SIZE: usize = 256;
+
+/// Called once the shell is connected to the remote host, or failed to, before any byte is forwarded
+pub type ConnectCallback = Box<dyn FnOnce(&mut SocketStream, Result<(), &str>) + Send>;
+
+/// A local connection to forward to a host that the shell can reach
+pub struct ForwardRequest {
+    pub local_stream: SocketStream,
+    pub peer: String,
+    /* Or unix:<path> for a Unix socket of the shell */
+    pub host: String,
+    pub port: u16,
+    pub on_connect: Option<ConnectCallback>
+}
+
+/// What the local connections ask to the thread that talks to the server
+enum ForwarderCommand {
+    Open(ForwardRequest),
+    Input { tunnel_id: String, input: TunnelInput },
+    Status(Sender<Vec<TunnelStatus>>)
+}
+
+/// Forwards local connections through a shared connection to the server
+pub struct ForwarderHandle {
+    tx: SyncSender<ForwarderCommand>,
+    active_connections: Arc<AtomicUsize>,
+    max_connections: usize
+}
+
+impl ForwarderHandle {
+    pub fn start(args: Args, max_connections: usize) -> ForwarderHandle {
+        let active_connections = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::sync_channel(LOCAL_READ_QUEUE_SIZE);
+        {
+            let tx = tx.clone();
+            let active_connections = active_connections.clone();
+            thread::spawn(move || run_forwarder(&args, tx, rx, &active_connections));
+        }
+        return ForwarderHandle { tx, active_connections, max_connections };
+    }
 
-    /* hopo forward <local port> <shell_id> <remote_port> */
+    /// Counts a new local connection, unless there are already too many
+    pub fn try_acquire(&self, peer: &String) -> bool {
+        let acquired = self.active_connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+            if count < self.max_connections { Some(count + 1) } else { None }
+        }).is_ok();
+        if !acquired {
+            eprintln!("Refuse connection from {}: already {} connections", peer, self.max_connections);
+        }
+        return acquired;
+    }
 
-    let local_port = args.extra_args[0].parse::<u16>().unwrap();
-    let host = args.extra_args[1].clone();
-    let remote_port = args.extra_args[2].parse::<u16>().unwrap();
+    /// For the acquired connections that will not be forwarded
+    pub fn release(&self) {
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
 
-    let listener = TcpListener::bind(format!("localhost:{}", local_port)).unwrap();
-    eprintln!("Wait for connection at port {}", local_port);
+    /// Forwards an acquired connection
+    pub fn forward(&self, request: ForwardRequest) {
+        self.tx.send(ForwarderCommand::Open(request)).unwrap();
+    }
 
-    for stream in listener.incoming() {
-        eprintln!("Got incomming connection");
-        let mut stream = stream.unwrap();
-        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    /// The connections that are currently forwarded
+    pub fn status(&self) -> Vec<TunnelStatus> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if self.tx.send(ForwarderCommand::Status(reply_tx)).is_err() {
+            return vec![];
+        }
+        return reply_rx.recv_timeout(Duration::from_secs(1)).unwrap_or_default();
+    }
+}
 
-        let mut buf = [0; BUF_SIZE];
-        // let read_size = stream.read_to_end(&mut buf);
-        let read_size = stream.read(&mut buf);
+/// Forwards the connections of a local listener until it fails
+pub fn accept_connections(listener: &SocketListener, forwarder: &ForwarderHandle, host: &String, remote_port: u16) {
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
 
-        if read_size.is_err() {
-            eprintln!("- failed to read stream.");
+        if !forwarder.try_acquire(&peer) {
+            let _ = stream.shutdown(Shutdown::Both);
             continue;
         }
+        eprintln!("Got incomming connection from {}", peer);
+
+        forwarder.forward(ForwardRequest {
+            local_stream: stream,
+            peer,
+            host: host.clone(),
+            port: remote_port,
+            on_connect: None
+        });
+    }
+}
+
+pub fn parse_max_connections(extra_args: &mut Vec<String>) -> usize {
+    return match consume_arg_value(extra_args, "--max-connections") {
+        Some(max_connections) => max_connections.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!("Invalid max connections: {}", max_connections);
+            std::process::exit(-1);
+        }),
+        None => DEFAULT_MAX_CONNECTIONS
+    };
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelStatus {
+    pub tunnel_id: String,
+    pub peer: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration_ms: u64
+}
+
+/// What is reported when a forwarded connection is closed
+pub struct ConnectionStats {
+    pub peer: String,
+    /* To the other side of the tunnel */
+    pub bytes_sent: u64,
+    /* From the other side of the tunnel */
+    pub bytes_received: u64,
+    pub started_at: Instant
+}
+
+impl ConnectionStats {
+    pub fn new(peer: String) -> ConnectionStats {
+        return ConnectionStats {
+            peer,
+            bytes_sent: 0,
+            bytes_received: 0,
+            started_at: Instant::now()
+        };
+    }
+
+    pub fn status(&self, tunnel_id: &String) -> TunnelStatus {
+        return TunnelStatus {
+            tunnel_id: tunnel_id.clone(),
+            peer: self.peer.clone(),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            duration_ms: self.started_at.elapsed().as_millis() as u64
+        };
+    }
+
+    pub fn report(&self, format: OutputFormat, tunnel_id: &String, error: Option<&str>) {
+        let duration_ms = self.started_at.elapsed().as_millis();
+        match format {
+            OutputFormat::Json => {
+                let mut report = serde_json::to_value(self.status(tunnel_id)).unwrap();
+                report["error"] = serde_json::json!(error);
+                println!("{}", report);
+            },
+            _ => {
+                eprintln!(
+                    "[{}] Closed connection from {}: sent {} bytes, received {} bytes in {:.3}s{}",
+                    tunnel_id,
+                    self.peer,
+                    self.bytes_sent,
+                    self.bytes_received,
+                    duration_ms as f64 / 1000.0,
+                    error.map(|error| format!(" ({})", error)).unwrap_or_default()
+                );
+            }
+        }
+    }
+}
+
+enum LocalOutput {
+    Data(Vec<u8>),
+    Eof
+}
+
+/// Writes to a local connection from its own thread, so that a slow local client does not stall the other tunnels
+pub struct LocalWriter {
+    stream: SocketStream,
+    tx: SyncSender<LocalOutput>
+}
+
+impl LocalWriter {
+    pub fn start(stream: SocketStream) -> Result<LocalWriter, std::io::Error> {
+        let mut writer_stream = stream.try_clone()?;
+        let (tx, rx) = mpsc::sync_channel(LOCAL_WRITE_QUEUE_SIZE);
+        thread::spawn(move || {
+            for output in rx.iter() {
+                let res = match output {
+                    LocalOutput::Data(data) => writer_stream.write_all(&data),
+                    LocalOutput::Eof => writer_stream.shutdown(Shutdown::Write)
+                };
+                if res.is_err() {
+                    break;
+                }
+            }
+            /* The tunnel is closed, and what it received is written */
+            let _ = writer_stream.shutdown(Shutdown::Both);
+        });
+        return Ok(LocalWriter { stream, tx });
+    }
+
+    /// Fails when the local connection does not keep up with the remote host, or is closed
+    pub fn write(&self, data: Vec<u8>) -> Result<(), std::io::Error> {
+        return self.send(LocalOutput::Data(data));
+    }
+
+    pub fn shutdown_write(&self) -> Result<(), std::io::Error> {
+        return self.send(LocalOutput::Eof);
+    }
+
+    fn send(&self, output: LocalOutput) -> Result<(), std::io::Error> {
+        return match self.tx.try_send(output) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, "The local connection is too slow")),
+            Err(TrySendError::Disconnected(_)) => Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "The local connection is closed"))
+        };
+    }
+
+    /// Before anything is written
+    pub fn stream(&mut self) -> &mut SocketStream {
+        return &mut self.stream;
+    }
+
+    /// Closes the connection once the pending bytes are written, or right away on error
+    pub fn close(self, is_error: bool) {
+        if is_error {
+            let _ = self.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// A local connection, and the tunnel that forwards it
+struct ForwardedConnection {
+    stats: ConnectionStats,
+    local_writer: LocalWriter,
+    decoder: zstd::stream::write::Decoder<'static, Vec<u8>>,
+    event_buf: Vec<u8>,
+    /* Number of requests sent for this tunnel, to give them distinct ids */
+    request_count: u64,
+    on_connect: Option<ConnectCallback>
+}
+
+pub fn main_forward_tcp(args: Args) {
+    /* Create a server that forwards all access to a port
+     * to a remote shell */
+
+    /* hopo forward-tcp <shell_id> <local port>|unix:<local path> <host> <remote_port> [--max-connections <n>]
+     * hopo forward-tcp <shell_id> <local port>|unix:<local path> unix:<remote path> [--max-connections <n>] */
+
+    let usage = "Usage: hopo forward-tcp <shell_id> <local port>|unix:<local path> <host> <remote port>|unix:<remote path> [--max-connections <n>]";
+    let mut extra_args = args.extra_args.clone();
+    let max_connections = parse_max_connections(&mut extra_args);
+    if extra_args.len() < 2 {
+        eprintln!("{}", usage);
+        std::process::exit(-1);
+    }
+    let local_address = extra_args[0].clone();
+    let host = extra_args[1].clone();
+    /* Unix sockets have no port */
+    let remote_port = match (unix_socket_path(&host), extra_args.get(2)) {
+        (Some(_), _) => 0,
+        (None, Some(remote_port)) => remote_port.parse::<u16>().unwrap_or_else(|_| {
+            eprintln!("{}", usage);
+            std::process::exit(-1);
+        }),
+        (None, None) => {
+            eprintln!("{}", usage);
+            std::process::exit(-1);
+        }
+    };
+
+    let bind_address = match unix_socket_path(&local_address) {
+        Some(_) => local_address.clone(),
+        None => format!("localhost:{}", local_address)
+    };
+    let listener = SocketListener::bind(&bind_address).unwrap_or_else(|e| {
+        eprintln!("Cannot listen on {}: {}", local_address, e);
+        std::process::exit(-1);
+    });
+    eprintln!("Wait for connection at {}", local_address);
 
-        eprintln!("- read {} bytes", read_size.unwrap());
+    /* All the connections share the same connection to the server */
+    let forwarder = ForwarderHandle::start(args, max_connections);
+    accept_connections(&listener, &forwarder, &host, remote_port);
+}
 
-        /* Generate tcp request */
-        let shell_id = args.shell_name.clone().unwrap();
-        let make_id = || {  
-            let random_str = make_random_id(8);
-            return format!("{}:{}", &shell_id, random_str)
+/// Connects to the server when a local connection arrives, and reconnects after it was lost
+fn run_forwarder(
+    args: &Args,
+    tx: SyncSender<ForwarderCommand>,
+    rx: Receiver<ForwarderCommand>,
+    active_connections: &AtomicUsize
+) {
+    let mut connections: HashMap<String, ForwardedConnection> = HashMap::new();
+
+    loop {
+        /* Inputs of closed connections are ignored */
+        let first_open = match rx.recv() {
+            Ok(ForwarderCommand::Open(request)) => request,
+            Ok(ForwarderCommand::Input { .. }) => { continue },
+            Ok(ForwarderCommand::Status(reply_tx)) => {
+                let _ = reply_tx.send(vec![]);
+                continue;
+            },
+            Err(_) => { return }
         };
-        let req = tcp::make_tcp_request(make_id, &shell_id, host.clone(), remote_port, buf.to_vec());
-        
-        /* Connect to shell and send TCP command */
-        let (ssl_connector, tcp_stream) = connect_to_hoposhell(&args);
 
-        /* Send request to hoposhell server */
+        let (ssl_connector, tcp_stream) = connect_to_hoposhell(args);
+        tcp_stream.set_read_timeout(Some(Duration::from_millis(TUNNEL_POLL_INTERVAL_MS))).unwrap();
+        let mut forwarder = Forwarder { args, tx: &tx, connections: &mut connections, active_connections };
+
         let res = if let Some(ref ssl_connector) = ssl_connector {
             let hostname = compute_hostname(&args.server_url);
-            let ssl_stream = ssl_connector.connect(hostname, tcp_stream).unwrap();
-            send_request_and_get_response(&args, ssl_stream, &req, args.verbose)
-            // handle_command_connection(&args, ssl_stream, &req, &process_res, args.verbose)
+            match ssl_connector.connect(hostname, tcp_stream) {
+                Ok(ssl_stream) => forwarder.run(ssl_stream, &rx, first_open),
+                Err(e) => {
+                    let _ = first_open.local_stream.shutdown(Shutdown::Both);
+                    active_connections.fetch_sub(1, Ordering::SeqCst);
+                    Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+                }
+            }
         } else {
-            send_request_and_get_response(&args, tcp_stream, &req, args.verbose)
+            forwarder.run(tcp_stream, &rx, first_open)
         };
 
-        /* Process response */
-        if res.is_err() {
-            eprintln!("Failed to send/recieve to/from hoposhell server: {}", res.err().unwrap());
-            continue;
+        if let Err(e) = res {
+            eprintln!("Lost the connection to the server: {}", e);
         }
-        let res = res.unwrap();
+        forwarder.close_all(Some("Lost the connection to the server"));
+    }
+}
 
-        // eprintln!("#########################");
-        // eprintln!("{}", String::from_utf8_lossy(&res.payload));
-        // eprintln!("#########################");
+struct Forwarder<'a> {
+    args: &'a Args,
+    tx: &'a SyncSender<ForwarderCommand>,
+    connections: &'a mut HashMap<String, ForwardedConnection>,
+    active_connections: &'a AtomicUsize
+}
 
-        if res.status_code != StatusCode::Ok {
-            eprintln!("Got error status code from hoposhell server: {:?}", res.status_code);
-            continue;
+impl Forwarder<'_> {
+    /// Forwards the local connections until the server connection fails
+    fn run(
+        &mut self,
+        mut server_stream: impl Read + Write,
+        rx: &Receiver<ForwarderCommand>,
+        first_open: ForwardRequest
+    ) -> Result<(), std::io::Error> {
+        let shell_id = self.args.shell_name.clone().unwrap();
+        let mut buf_str = String::from("");
+        let mut last_received = Instant::now();
+        let mut header_sent = false;
+
+        let mut pending = vec![ForwarderCommand::Open(first_open)];
+
+        loop {
+            /* Local connections -> shell */
+            loop {
+                match rx.try_recv() {
+                    Ok(command) => pending.push(command),
+                    Err(TryRecvError::Empty) => { break },
+                    Err(TryRecvError::Disconnected) => { return Ok(()) }
+                }
+            }
+            for command in pending.drain(..) {
+                match command {
+                    ForwarderCommand::Open(request) => {
+                        let open_req = tunnel::make_tunnel_request(
+                            || format!("{}:{}", &shell_id, make_random_id(8)),
+                            &shell_id,
+                            &TunnelCommandRequestBody::open(TunnelProtocol::Tcp, &request.host, request.port)
+                        );
+                        /* The first request sends the header of the connection */
+                        if header_sent {
+                            send_request_chunks(&mut server_stream, &open_req, self.args.verbose)?;
+                        } else {
+                            send_request(self.args, &mut server_stream, &open_req, self.args.verbose);
+                            header_sent = true;
+                        }
+                        eprintln!("[{}] Forward connection from {} to {}", open_req.message_id, request.peer, format_destination(&request.host, request.port));
+                        self.open(open_req.message_id, request)?;
+                        if self.connections.len() == 1 {
+                            last_received = Instant::now();
+                        }
+                    },
+                    ForwarderCommand::Input { tunnel_id, input } => {
+                        self.send_input(&mut server_stream, &shell_id, &tunnel_id, input)?;
+                    },
+                    ForwarderCommand::Status(reply_tx) => {
+                        let status = self.connections.iter().map(|(tunnel_id, connection)| connection.stats.status(tunnel_id)).collect();
+                        let _ = reply_tx.send(status);
+                    }
+                }
+            }
+
+            /* Shell -> local connections */
+            let messages = match read_messages_from_stream(&mut server_stream, &mut buf_str, self.args.verbose) {
+                ReadMessageResult::Ok(messages) => messages,
+                ReadMessageResult::CanContinue => {
+                    /* The shell sends heartbeats while the tunnels are idle */
+                    if !self.connections.is_empty() && last_received.elapsed() > self.args.command_timeout {
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "No news from the shell"));
+                    }
+                    continue;
+                },
+                ReadMessageResult::CannotContinue => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to read tcp stream"));
+                }
+            };
+            last_received = Instant::now();
+
+            for message in messages.iter() {
+                let res = match message.content.as_ref().map(ChunkedRequestOrResponse::deserialize) {
+                    Some(ChunkedRequestOrResponse::Response(res)) => res,
+                    _ => { continue }
+                };
+                let is_for_tunnel = |tunnel_id: &&String| res.message_id == **tunnel_id || res.message_id.starts_with(format!("{}-", tunnel_id).as_str());
+                let tunnel_id = match self.connections.keys().find(is_for_tunnel) {
+                    Some(tunnel_id) => tunnel_id.clone(),
+                    None => { continue }
+                };
+
+                if res.status_code != StatusCode::Ok {
+                    let error = format!("Got status {:?}: {}", res.status_code, String::from_utf8_lossy(&res.payload));
+                    self.close(&tunnel_id, Some(error.as_str()));
+                    continue;
+                }
+                if res.message_id != tunnel_id {
+                    continue;
+                }
+                match self.process_tunnel_response(&tunnel_id, &res.payload) {
+                    Ok(Some(error)) => self.close(&tunnel_id, error.as_deref()),
+                    Ok(None) if res.chunk_type == ChunkType::Last => self.close(&tunnel_id, None),
+                    Ok(None) => {},
+                    Err(e) => {
+                        /* Otherwise the shell keeps sending to the closed connection */
+                        self.send_input(&mut server_stream, &shell_id, &tunnel_id, TunnelInput::Close)?;
+                        self.close(&tunnel_id, Some(e.to_string().as_str()));
+                    }
+                }
+            }
         }
+    }
 
-        let write_res = stream.write_all(&res.payload);
-        if write_res.is_err() {
-            eprintln!("Failed to write back to stream: {}", write_res.err().unwrap());
-            continue;
+    fn send_input(&mut self, server_stream: &mut impl Write, shell_id: &String, tunnel_id: &String, input: TunnelInput) -> Result<(), std::io::Error> {
+        let connection = match self.connections.get_mut(tunnel_id) {
+            Some(connection) => connection,
+            None => { return Ok(()) }
+        };
+        if let TunnelInput::Data(data) = &input {
+            connection.stats.bytes_sent += data.len() as u64;
+        }
+        connection.request_count += 1;
+        /* Failures are sent back with this id, which starts with the id of the tunnel */
+        let message_id = format!("{}-{}", tunnel_id, connection.request_count);
+        let req = tunnel::make_tunnel_request(|| message_id.clone(), shell_id, &TunnelCommandRequestBody::from_input(tunnel_id, input));
+        return send_request_chunks(server_stream, &req, self.args.verbose);
+    }
+
+    fn open(&mut self, tunnel_id: String, request: ForwardRequest) -> Result<(), std::io::Error> {
+        let local_writer = LocalWriter::start(request.local_stream.try_clone()?)?;
+        read_local_inputs(request.local_stream, tunnel_id.clone(), self.tx.clone());
+
+        self.connections.insert(tunnel_id, ForwardedConnection {
+            stats: ConnectionStats::new(request.peer),
+            local_writer,
+            decoder: zstd::stream::write::Decoder::new(Vec::new())?,
+            event_buf: vec![],
+            request_count: 0,
+            on_connect: request.on_connect
+        });
+        return Ok(());
+    }
+
+    /// Writes the bytes received from the remote host to the local connection.
+    /// Returns the closing error when the tunnel is closed.
+    fn process_tunnel_response(&mut self, tunnel_id: &String, payload: &[u8]) -> Result<Option<Option<String>>, std::io::Error> {
+        let connection = self.connections.get_mut(tunnel_id).unwrap();
+
+        connection.decoder.write_all(payload)?;
+        connection.decoder.flush()?;
+        let decoded = std::mem::take(connection.decoder.get_mut());
+        connection.event_buf.extend(decoded);
+
+        while let Some(line_end) = connection.event_buf.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = connection.event_buf.drain(..=line_end).collect();
+            match serde_json::from_slice::<TunnelEvent>(&line) {
+                Ok(TunnelEvent::Data { data }) => {
+                    connection.stats.bytes_received += data.len() as u64;
+                    connection.local_writer.write(data)?;
+                },
+                Ok(TunnelEvent::Eof) => {
+                    connection.local_writer.shutdown_write()?;
+                },
+                Ok(TunnelEvent::Connected) => {
+                    if let Some(on_connect) = connection.on_connect.take() {
+                        on_connect(connection.local_writer.stream(), Ok(()));
+                    }
+                },
+                Ok(TunnelEvent::Closed { error }) => {
+                    return Ok(Some(error));
+                },
+                Ok(_) => {},
+                Err(_) => eprintln!("[{}] Got an invalid event: {}", tunnel_id, String::from_utf8_lossy(&line))
+            }
+        }
+        return Ok(None);
+    }
+
+    fn close(&mut self, tunnel_id: &String, error: Option<&str>) {
+        let mut connection = match self.connections.remove(tunnel_id) {
+            Some(connection) => connection,
+            None => { return }
+        };
+        if let Some(on_connect) = connection.on_connect.take() {
+            on_connect(connection.local_writer.stream(), Err(error.unwrap_or("Closed before connecting")));
         }
-        eprintln!("Wrote {} bytes back to stream", res.payload.len());
+        connection.local_writer.close(error.is_some());
+        self.active_connections.fetch_sub(1, Ordering::SeqCst);
+        connection.stats.report(self.args.format, tunnel_id, error);
     }
-}
\ No newline at end of file
+
+    fn close_all(&mut self, error: Option<&str>) {
+        let tunnel_ids: Vec<String> = self.connections.keys().cloned().collect();
+        for tunnel_id in tunnel_ids.iter() {
+            self.close(tunnel_id, error);
+        }
+    }
+}
+
+/// Reads a local connection in another thread
+fn read_local_inputs(mut local_reader: SocketStream, tunnel_id: String, tx: SyncSender<ForwarderCommand>) {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; BUF_SIZE];
+        loop {
+            let input = match local_reader.read(&mut buf) {
+                Ok(0) => TunnelInput::Eof,
+                Ok(n) => TunnelInput::Data(buf[..n].to_vec()),
+                Err(_) => TunnelInput::Close
+            };
+            let is_data = matches!(input, TunnelInput::Data(_));
+            if tx.send(ForwarderCommand::Input { tunnel_id: tunnel_id.clone(), input }).is_err() || !is_data {
+                return;
+            }
+        }
+    });
+}
@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant}
+};
+
+use crate::{
+    args::{Args, consume_arg_value},
+    commands::{
+        tunnel::{self, TunnelCommandRequestBody, TunnelEvent, TunnelInput, TunnelProtocol},
+        send_command_handler::{connect_to_hoposhell, send_request, send_request_chunks},
+        request_or_response::{ChunkedRequestOrResponse, ChunkType, StatusCode},
+        udp::MAX_DATAGRAM_SIZE
+    },
+    connect::{compute_hostname, read_messages_from_stream, ReadMessageResult},
+    forward_tcp::{ConnectionStats, TunnelStatus, TUNNEL_POLL_INTERVAL_MS, parse_max_connections},
+    make_random_id
+};
+
+/* UDP has no end of connection: the sessions end when they are idle */
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+
+/// The datagrams exchanged between a local peer and the remote host, through one tunnel
+struct UdpSession {
+    peer: SocketAddr,
+    stats: ConnectionStats,
+    decoder: zstd::stream::write::Decoder<'static, Vec<u8>>,
+    event_buf: Vec<u8>,
+    /* Number of requests sent for this tunnel, to give them distinct ids */
+    request_count: u64,
+    last_active: Instant
+}
+
+/// What the local peers ask to the thread that talks to the server
+enum UdpForwarderCommand {
+    Datagram(SocketAddr, Vec<u8>),
+    Status(Sender<Vec<TunnelStatus>>)
+}
+
+pub fn main_forward_udp(args: Args) {
+    /* Forward the datagrams sent to a local port to a host
+     * reachable from a remote shell, and send back its answers */
+
+    /* hopo forward-udp <shell_id> <local port> <host> <remote port> [--idle-timeout <secs>] [--max-connections <n>] */
+
+    let mut extra_args = args.extra_args.clone();
+    let max_sessions = parse_max_connections(&mut extra_args);
+    let idle_timeout = match consume_arg_value(&mut extra_args, "--idle-timeout") {
+        Some(idle_timeout) => Duration::from_secs(idle_timeout.parse::<u64>().unwrap_or_else(|_| {
+            eprintln!("Invalid idle timeout: {}", idle_timeout);
+            std::process::exit(-1);
+        })),
+        None => Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS)
+    };
+    if extra_args.len() < 3 {
+        eprintln!("Usage: hopo forward-udp <shell_id> <local port> <host> <remote port> [--idle-timeout <secs>] [--max-connections <n>]");
+        std::process::exit(-1);
+    }
+    let local_port = extra_args[0].parse::<u16>().unwrap();
+    let host = extra_args[1].clone();
+    let remote_port = extra_args[2].parse::<u16>().unwrap();
+
+    let socket = UdpSocket::bind(format!("localhost:{}", local_port)).unwrap();
+    eprintln!("Wait for datagrams at port {}", local_port);
+
+    let forwarder = UdpForwarderHandle::start(args, socket, host, remote_port, idle_timeout, max_sessions);
+    forwarder.join();
+}
+
+/// Forwards the datagrams of a local socket through a connection to the server
+pub struct UdpForwarderHandle {
+    tx: Sender<UdpForwarderCommand>,
+    thread: thread::JoinHandle<()>
+}
+
+impl UdpForwarderHandle {
+    pub fn start(
+        args: Args,
+        socket: UdpSocket,
+        host: String,
+        remote_port: u16,
+        idle_timeout: Duration,
+        max_sessions: usize
+    ) -> UdpForwarderHandle {
+        let (tx, rx) = mpsc::channel();
+        {
+            let socket = socket.try_clone().unwrap();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+                loop {
+                    match socket.recv_from(&mut buf) {
+                        Ok((n, peer)) => {
+                            if tx.send(UdpForwarderCommand::Datagram(peer, buf[..n].to_vec())).is_err() {
+                                return;
+                            }
+                        },
+                        Err(e) => eprintln!("Failed to receive datagram: {}", e)
+                    }
+                }
+            });
+        }
+
+        let thread = thread::spawn(move || {
+            let mut sessions: HashMap<String, UdpSession> = HashMap::new();
+            loop {
+                /* Connects to the server when a datagram arrives, and reconnects after it was lost */
+                let first_datagram = match rx.recv() {
+                    Ok(UdpForwarderCommand::Datagram(peer, data)) => (peer, data),
+                    Ok(UdpForwarderCommand::Status(reply_tx)) => {
+                        let _ = reply_tx.send(vec![]);
+                        continue;
+                    },
+                    Err(_) => { return }
+                };
+
+                let (ssl_connector, tcp_stream) = connect_to_hoposhell(&args);
+                tcp_stream.set_read_timeout(Some(Duration::from_millis(TUNNEL_POLL_INTERVAL_MS))).unwrap();
+                let mut forwarder = UdpForwarder {
+                    args: &args,
+                    socket: &socket,
+                    host: &host,
+                    remote_port,
+                    idle_timeout,
+                    max_sessions,
+                    sessions: &mut sessions
+                };
+
+                let res = if let Some(ref ssl_connector) = ssl_connector {
+                    let hostname = compute_hostname(&args.server_url);
+                    match ssl_connector.connect(hostname, tcp_stream) {
+                        Ok(ssl_stream) => forwarder.run(ssl_stream, &rx, first_datagram),
+                        Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+                    }
+                } else {
+                    forwarder.run(tcp_stream, &rx, first_datagram)
+                };
+
+                if let Err(e) = res {
+                    eprintln!("Lost the connection to the server: {}", e);
+                }
+                forwarder.close_all(Some("Lost the connection to the server"));
+            }
+        });
+
+        return UdpForwarderHandle { tx, thread };
+    }
+
+    /// The sessions that are currently forwarded
+    pub fn status(&self) -> Vec<TunnelStatus> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if self.tx.send(UdpForwarderCommand::Status(reply_tx)).is_err() {
+            return vec![];
+        }
+        return reply_rx.recv_timeout(Duration::from_secs(1)).unwrap_or_default();
+    }
+
+    pub fn join(self) {
+        let _ = self.thread.join();
+    }
+}
+
+struct UdpForwarder<'a> {
+    args: &'a Args,
+    socket: &'a UdpSocket,
+    host: &'a String,
+    remote_port: u16,
+    idle_timeout: Duration,
+    max_sessions: usize,
+    sessions: &'a mut HashMap<String, UdpSession>
+}
+
+impl UdpForwarder<'_> {
+    /// Forwards the datagrams until the server connection fails
+    fn run(
+        &mut self,
+        mut server_stream: impl Read + Write,
+        rx: &Receiver<UdpForwarderCommand>,
+        first_datagram: (SocketAddr, Vec<u8>)
+    ) -> Result<(), std::io::Error> {
+        let shell_id = self.args.shell_name.clone().unwrap();
+        let mut buf_str = String::from("");
+        let mut last_received = Instant::now();
+        let mut header_sent = false;
+
+        let mut pending = vec![first_datagram];
+
+        loop {
+            /* Local peers -> shell */
+            loop {
+                match rx.try_recv() {
+                    Ok(UdpForwarderCommand::Datagram(peer, data)) => pending.push((peer, data)),
+                    Ok(UdpForwarderCommand::Status(reply_tx)) => {
+                        let status = self.sessions.iter().map(|(tunnel_id, session)| session.stats.status(tunnel_id)).collect();
+                        let _ = reply_tx.send(status);
+                    },
+                    Err(TryRecvError::Empty) => { break },
+                    Err(TryRecvError::Disconnected) => { return Ok(()) }
+                }
+            }
+            for (peer, data) in pending.drain(..) {
+                let tunnel_id = match self.sessions.iter().find(|(_, session)| session.peer == peer) {
+                    Some((tunnel_id, _)) => tunnel_id.clone(),
+                    None => {
+                        if self.sessions.len() >= self.max_sessions {
+                            eprintln!("Drop datagram from {}: already {} sessions", peer, self.max_sessions);
+                            continue;
+                        }
+                        let open_req = tunnel::make_tunnel_request(
+                            || format!("{}:{}", &shell_id, make_random_id(8)),
+                            &shell_id,
+                            &TunnelCommandRequestBody::open(TunnelProtocol::Udp, self.host, self.remote_port)
+                        );
+                        /* The first request sends the header of the connection */
+                        if header_sent {
+                            send_request_chunks(&mut server_stream, &open_req, self.args.verbose)?;
+                        } else {
+                            send_request(self.args, &mut server_stream, &open_req, self.args.verbose);
+                            header_sent = true;
+                        }
+                        eprintln!("[{}] Forward datagrams from {} to {}:{}", open_req.message_id, peer, self.host, self.remote_port);
+                        if self.sessions.is_empty() {
+                            last_received = Instant::now();
+                        }
+                        self.sessions.insert(open_req.message_id.clone(), UdpSession {
+                            peer,
+                            stats: ConnectionStats::new(peer.to_string()),
+                            decoder: zstd::stream::write::Decoder::new(Vec::new())?,
+                            event_buf: vec![],
+                            request_count: 0,
+                            last_active: Instant::now()
+                        });
+                        open_req.message_id
+                    }
+                };
+                self.send_input(&mut server_stream, &shell_id, &tunnel_id, TunnelInput::Data(data))?;
+            }
+
+            /* Ends the idle sessions */
+            let idle_tunnel_ids: Vec<String> = self.sessions.iter()
+                .filter(|(_, session)| session.last_active.elapsed() > self.idle_timeout)
+                .map(|(tunnel_id, _)| tunnel_id.clone())
+                .collect();
+            for tunnel_id in idle_tunnel_ids.iter() {
+                self.send_input(&mut server_stream, &shell_id, tunnel_id, TunnelInput::Close)?;
+                self.close(tunnel_id, None);
+            }
+
+            /* Shell -> local peers */
+            let messages = match read_messages_from_stream(&mut server_stream, &mut buf_str, self.args.verbose) {
+                ReadMessageResult::Ok(messages) => messages,
+                ReadMessageResult::CanContinue => {
+                    /* The shell sends heartbeats while the tunnels are idle */
+                    if !self.sessions.is_empty() && last_received.elapsed() > self.args.command_timeout {
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "No news from the shell"));
+                    }
+                    continue;
+                },
+                ReadMessageResult::CannotContinue => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to read tcp stream"));
+                }
+            };
+            last_received = Instant::now();
+
+            for message in messages.iter() {
+                let res = match message.content.as_ref().map(ChunkedRequestOrResponse::deserialize) {
+                    Some(ChunkedRequestOrResponse::Response(res)) => res,
+                    _ => { continue }
+                };
+                let is_for_tunnel = |tunnel_id: &&String| res.message_id == **tunnel_id || res.message_id.starts_with(format!("{}-", tunnel_id).as_str());
+                let tunnel_id = match self.sessions.keys().find(is_for_tunnel) {
+                    Some(tunnel_id) => tunnel_id.clone(),
+                    None => { continue }
+                };
+
+                if res.status_code != StatusCode::Ok {
+                    let error = format!("Got status {:?}: {}", res.status_code, String::from_utf8_lossy(&res.payload));
+                    self.close(&tunnel_id, Some(error.as_str()));
+                    continue;
+                }
+                if res.message_id != tunnel_id {
+                    continue;
+                }
+                match self.process_tunnel_response(&tunnel_id, &res.payload) {
+                    Ok(Some(error)) => self.close(&tunnel_id, error.as_deref()),
+                    Ok(None) if res.chunk_type == ChunkType::Last => self.close(&tunnel_id, None),
+                    Ok(None) => {},
+                    Err(e) => self.close(&tunnel_id, Some(e.to_string().as_str()))
+                }
+            }
+        }
+    }
+
+    fn send_input(&mut self, server_stream: impl Write, shell_id: &String, tunnel_id: &String, input: TunnelInput) -> Result<(), std::io::Error> {
+        let session = match self.sessions.get_mut(tunnel_id) {
+            Some(session) => session,
+            None => { return Ok(()) }
+        };
+        if let TunnelInput::Data(data) = &input {
+            session.stats.bytes_sent += data.len() as u64;
+            session.last_active = Instant::now();
+        }
+        session.request_count += 1;
+        /* Failures are sent back with this id, which starts with the id of the tunnel */
+        let message_id = format!("{}-{}", tunnel_id, session.request_count);
+        let req = tunnel::make_tunnel_request(|| message_id.clone(), shell_id, &TunnelCommandRequestBody::from_input(tunnel_id, input));
+        return send_request_chunks(server_stream, &req, self.args.verbose);
+    }
+
+    /// Sends the datagrams received from the remote host to the local peer.
+    /// Returns the closing error when the tunnel is closed.
+    fn process_tunnel_response(&mut self, tunnel_id: &String, payload: &[u8]) -> Result<Option<Option<String>>, std::io::Error> {
+        let session = self.sessions.get_mut(tunnel_id).unwrap();
+
+        session.decoder.write_all(payload)?;
+        session.decoder.flush()?;
+        let decoded = std::mem::take(session.decoder.get_mut());
+        session.event_buf.extend(decoded);
+
+        while let Some(line_end) = session.event_buf.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = session.event_buf.drain(..=line_end).collect();
+            match serde_json::from_slice::<TunnelEvent>(&line) {
+                Ok(TunnelEvent::Data { data }) => {
+                    session.stats.bytes_received += data.len() as u64;
+                    session.last_active = Instant::now();
+                    if let Err(e) = self.socket.send_to(&data, session.peer) {
+                        eprintln!("[{}] Cannot send datagram to {}: {}", tunnel_id, session.peer, e);
+                    }
+                },
+                Ok(TunnelEvent::Closed { error }) => {
+                    return Ok(Some(error));
+                },
+                Ok(_) => {},
+                Err(_) => eprintln!("[{}] Got an invalid event: {}", tunnel_id, String::from_utf8_lossy(&line))
+            }
+        }
+        return Ok(None);
+    }
+
+    fn close(&mut self, tunnel_id: &String, error: Option<&str>) {
+        if let Some(session) = self.sessions.remove(tunnel_id) {
+            session.stats.report(self.args.format, tunnel_id, error);
+        }
+    }
+
+    fn close_all(&mut self, error: Option<&str>) {
+        let tunnel_ids: Vec<String> = self.sessions.keys().cloned().collect();
+        for tunnel_id in tunnel_ids.iter() {
+            self.close(tunnel_id, error);
+        }
+    }
+}
@@ -11,8 +11,12 @@ mod commands {
     pub mod request_or_response;
     pub mod command_processor;
     pub mod command_history;
+    pub mod response_stream;
+    pub mod serde_base64;
+    pub mod json_output;
     /* */
     pub mod restart;
+    pub mod cancel;
     pub mod resize;
     /* */
     pub mod file_list;
@@ -22,9 +26,25 @@ mod commands {
     pub mod glob;
     pub mod http;
     pub mod tcp;
+    pub mod udp;
+    pub mod socket_stream;
     pub mod scripts;
+    pub mod script_catalog;
+    pub mod sandbox;
+    pub mod jobs;
+    pub mod cron;
+    pub mod schedule;
+    pub mod du;
+    pub mod watch;
+    pub mod tunnel;
+    pub mod policy;
 }
 pub mod forward_tcp;
+pub mod forward_udp;
+pub mod forward_http;
+pub mod reverse_forward_tcp;
+pub mod forward;
+pub mod socks;
 
 use rand::Rng;
 use rand::{self, distributions::Alphanumeric};
@@ -60,6 +80,21 @@ fn main() {
         },
         ArgsCommand::ForwardTcp => {
             forward_tcp::main_forward_tcp(args);
+        },
+        ArgsCommand::ForwardUdp => {
+            forward_udp::main_forward_udp(args);
+        },
+        ArgsCommand::ForwardHttp => {
+            forward_http::main_forward_http(args);
+        },
+        ArgsCommand::ReverseForwardTcp => {
+            reverse_forward_tcp::main_reverse_forward_tcp(args);
+        },
+        ArgsCommand::Socks => {
+            socks::main_socks(args);
+        },
+        ArgsCommand::Forward => {
+            forward::main_forward(args);
         }
     }
 }
@@ -124,5 +159,6 @@ fn make_random_id(n: usize) -> String {
 enum ParseCommandResponseResult {
     CanContinue,
     ReachedLastChunk,
-    Error
+    Error,
+    ErrorStatus(commands::request_or_response::StatusCode)
 }
\ No newline at end of file
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::mpsc::{self, SyncSender, TryRecvError},
+    thread,
+    time::{Duration, Instant}
+};
+
+use crate::{
+    args::{Args, consume_arg_value},
+    commands::{
+        tunnel::{self, TunnelCommandRequestBody, TunnelEvent, TunnelInput},
+        socket_stream::SocketStream,
+        cancel,
+        send_command_handler::{connect_to_hoposhell, send_request, send_request_chunks, catch_interrupt, is_interrupted},
+        request_or_response::{ChunkedRequestOrResponse, ChunkType, StatusCode}
+    },
+    connect::{compute_hostname, read_messages_from_stream, ReadMessageResult},
+    forward_tcp::{ConnectionStats, LocalWriter, LOCAL_READ_QUEUE_SIZE, TUNNEL_POLL_INTERVAL_MS},
+    make_random_id,
+    constants::BUF_SIZE
+};
+
+/// A connection accepted by the shell, and the local connection it is forwarded to
+struct ReversedConnection {
+    stats: ConnectionStats,
+    local_writer: LocalWriter,
+    /* Number of requests sent for this tunnel, to give them distinct ids */
+    request_count: u64
+}
+
+pub fn main_reverse_forward_tcp(args: Args) {
+    /* Make a remote shell listen on a port, and forward
+     * the connections it accepts to a local port */
+
+    /* hopo reverse-forward-tcp <shell_id> <remote bind port> <local host> <local port> [--bind-address <address>] */
+
+    let mut extra_args = args.extra_args.clone();
+    let bind_address = consume_arg_value(&mut extra_args, "--bind-address");
+    if extra_args.len() < 3 {
+        eprintln!("Usage: hopo reverse-forward-tcp <shell_id> <remote bind port> <local host> <local port> [--bind-address <address>]");
+        std::process::exit(-1);
+    }
+    let remote_port = extra_args[0].parse::<u16>().unwrap();
+    let local_host = extra_args[1].clone();
+    let local_port = extra_args[2].parse::<u16>().unwrap();
+
+    let (ssl_connector, tcp_stream) = connect_to_hoposhell(&args);
+    tcp_stream.set_read_timeout(Some(Duration::from_millis(TUNNEL_POLL_INTERVAL_MS))).unwrap();
+    /* Ctrl-C stops the listener on the shell */
+    catch_interrupt();
+
+    let mut forwarder = ReverseForwarder {
+        args: &args,
+        local_host,
+        local_port,
+        connections: HashMap::new()
+    };
+    let res = if let Some(ref ssl_connector) = ssl_connector {
+        let hostname = compute_hostname(&args.server_url);
+        let ssl_stream = ssl_connector.connect(hostname, tcp_stream).unwrap();
+        forwarder.run(ssl_stream, &bind_address, remote_port)
+    } else {
+        forwarder.run(tcp_stream, &bind_address, remote_port)
+    };
+
+    forwarder.close_all(Some("Stopped listening"));
+    if let Err(e) = res {
+        eprintln!("Reverse forwarding failed: {}", e);
+        std::process::exit(-1);
+    }
+}
+
+struct ReverseForwarder<'a> {
+    args: &'a Args,
+    local_host: String,
+    local_port: u16,
+    connections: HashMap<String, ReversedConnection>
+}
+
+impl ReverseForwarder<'_> {
+    /// Forwards the connections accepted by the shell until it stops listening
+    fn run(
+        &mut self,
+        mut server_stream: impl Read + Write,
+        bind_address: &Option<String>,
+        remote_port: u16
+    ) -> Result<(), std::io::Error> {
+        let shell_id = self.args.shell_name.clone().unwrap();
+        let make_id = || {
+            let random_str = make_random_id(8);
+            return format!("{}:{}", &shell_id, random_str)
+        };
+
+        let listen_req = tunnel::make_tunnel_request(make_id, &shell_id, &TunnelCommandRequestBody::listen(bind_address, remote_port));
+        let listener_id = listen_req.message_id.clone();
+        send_request(self.args, &mut server_stream, &listen_req, self.args.verbose);
+
+        let (tx, rx) = mpsc::sync_channel::<(String, TunnelInput)>(LOCAL_READ_QUEUE_SIZE);
+        let mut buf_str = String::from("");
+        let mut decoder = zstd::stream::write::Decoder::new(Vec::new())?;
+        let mut event_buf: Vec<u8> = vec![];
+        let mut last_received = Instant::now();
+        let mut has_sent_cancel = false;
+
+        loop {
+            if is_interrupted() && !has_sent_cancel {
+                eprintln!("[{}] Stop listening (Ctrl-C again to quit now)", listener_id);
+                let cancel_req = cancel::make_cancel_request(make_id, &shell_id, &listener_id);
+                send_request_chunks(&mut server_stream, &cancel_req, self.args.verbose)?;
+                has_sent_cancel = true;
+            }
+
+            /* Local connections -> shell */
+            loop {
+                let (tunnel_id, input) = match rx.try_recv() {
+                    Ok(tunnel_input) => tunnel_input,
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => { break }
+                };
+                let connection = match self.connections.get_mut(&tunnel_id) {
+                    Some(connection) => connection,
+                    None => { continue }
+                };
+                if let TunnelInput::Data(data) = &input {
+                    connection.stats.bytes_sent += data.len() as u64;
+                }
+                connection.request_count += 1;
+                /* Failures are sent back with this id, which starts with the id of the tunnel */
+                let message_id = format!("{}-{}", tunnel_id, connection.request_count);
+                let req = tunnel::make_tunnel_request(|| message_id.clone(), &shell_id, &TunnelCommandRequestBody::from_input(&tunnel_id, input));
+                send_request_chunks(&mut server_stream, &req, self.args.verbose)?;
+            }
+
+            /* Shell -> local connections */
+            let messages = match read_messages_from_stream(&mut server_stream, &mut buf_str, self.args.verbose) {
+                ReadMessageResult::Ok(messages) => messages,
+                ReadMessageResult::CanContinue => {
+                    /* The shell sends heartbeats while the listener is idle */
+                    if last_received.elapsed() > self.args.command_timeout {
+                        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "No news from the shell"));
+                    }
+                    continue;
+                },
+                ReadMessageResult::CannotContinue => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "Unable to read tcp stream"));
+                }
+            };
+            last_received = Instant::now();
+
+            let mut is_last = false;
+            for message in messages.iter() {
+                let res = match message.content.as_ref().map(ChunkedRequestOrResponse::deserialize) {
+                    Some(ChunkedRequestOrResponse::Response(res)) => res,
+                    _ => { continue }
+                };
+                if res.message_id == listener_id {
+                    if res.status_code != StatusCode::Ok {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("Got status {:?}: {}", res.status_code, String::from_utf8_lossy(&res.payload))));
+                    }
+                    decoder.write_all(&res.payload)?;
+                    is_last = is_last || res.chunk_type == ChunkType::Last;
+                } else if res.status_code != StatusCode::Ok {
+                    /* A request of a tunnel failed */
+                    let is_for_tunnel = |tunnel_id: &&String| res.message_id.starts_with(format!("{}-", tunnel_id).as_str());
+                    if let Some(tunnel_id) = self.connections.keys().find(is_for_tunnel).cloned() {
+                        self.close(&tunnel_id, Some(String::from_utf8_lossy(&res.payload).as_ref()));
+                    }
+                }
+            }
+            decoder.flush()?;
+            event_buf.append(decoder.get_mut());
+
+            while let Some(line_end) = event_buf.iter().position(|x| *x == b'\n') {
+                let line: Vec<u8> = event_buf.drain(..=line_end).collect();
+                let event = match serde_json::from_slice::<serde_json::Value>(&line) {
+                    Ok(event) => event,
+                    Err(_) => {
+                        eprintln!("[{}] Got an invalid event: {}", listener_id, String::from_utf8_lossy(&line));
+                        continue;
+                    }
+                };
+                let tunnel_id = event.get("tunnelId").and_then(|tunnel_id| tunnel_id.as_str()).unwrap_or("").to_string();
+
+                match serde_json::from_value::<TunnelEvent>(event) {
+                    Ok(TunnelEvent::Listening { port }) => {
+                        eprintln!("[{}] Shell {} listens on port {}, forwarded to {}:{}", listener_id, shell_id, port, self.local_host, self.local_port);
+                    },
+                    Ok(TunnelEvent::Accepted { peer }) => {
+                        if let Err(e) = self.open(&tunnel_id, peer, &tx) {
+                            eprintln!("[{}] Cannot connect to {}:{}: {}", tunnel_id, self.local_host, self.local_port, e);
+                            let req = tunnel::make_tunnel_request(make_id, &shell_id, &TunnelCommandRequestBody::from_input(&tunnel_id, TunnelInput::Close));
+                            send_request_chunks(&mut server_stream, &req, self.args.verbose)?;
+                        }
+                    },
+                    Ok(TunnelEvent::Data { data }) => {
+                        let res = match self.connections.get_mut(&tunnel_id) {
+                            Some(connection) => {
+                                connection.stats.bytes_received += data.len() as u64;
+                                connection.local_writer.write(data)
+                            },
+                            None => Ok(())
+                        };
+                        if let Err(e) = res {
+                            /* Otherwise the shell keeps sending to the closed connection */
+                            let req = tunnel::make_tunnel_request(make_id, &shell_id, &TunnelCommandRequestBody::from_input(&tunnel_id, TunnelInput::Close));
+                            send_request_chunks(&mut server_stream, &req, self.args.verbose)?;
+                            self.close(&tunnel_id, Some(e.to_string().as_str()));
+                        }
+                    },
+                    Ok(TunnelEvent::Eof) => {
+                        if let Some(connection) = self.connections.get_mut(&tunnel_id) {
+                            let _ = connection.local_writer.shutdown_write();
+                        }
+                    },
+                    Ok(TunnelEvent::Closed { error }) => {
+                        self.close(&tunnel_id, error.as_deref());
+                    },
+                    Ok(TunnelEvent::Stopped { error }) => {
+                        return match error {
+                            Some(error) => Err(std::io::Error::new(std::io::ErrorKind::Other, error)),
+                            None => Ok(())
+                        };
+                    },
+                    Ok(TunnelEvent::Connected) => {},
+                    Err(_) => eprintln!("[{}] Got an invalid event: {}", listener_id, String::from_utf8_lossy(&line))
+                }
+            }
+
+            if is_last {
+                return Ok(());
+            }
+        }
+    }
+
+    fn open(&mut self, tunnel_id: &String, peer: String, tx: &SyncSender<(String, TunnelInput)>) -> Result<(), std::io::Error> {
+        let local_stream = TcpStream::connect(format!("{}:{}", self.local_host, self.local_port))?;
+        let local_writer = LocalWriter::start(SocketStream::Tcp(local_stream.try_clone()?))?;
+        eprintln!("[{}] Forward connection from {} on the shell", tunnel_id, peer);
+        read_local_inputs(local_stream, tunnel_id.clone(), tx.clone());
+
+        self.connections.insert(tunnel_id.clone(), ReversedConnection {
+            stats: ConnectionStats::new(peer),
+            local_writer,
+            request_count: 0
+        });
+        return Ok(());
+    }
+
+    fn close(&mut self, tunnel_id: &String, error: Option<&str>) {
+        if let Some(connection) = self.connections.remove(tunnel_id) {
+            connection.local_writer.close(error.is_some());
+            connection.stats.report(self.args.format, tunnel_id, error);
+        }
+    }
+
+    fn close_all(&mut self, error: Option<&str>) {
+        let tunnel_ids: Vec<String> = self.connections.keys().cloned().collect();
+        for tunnel_id in tunnel_ids.iter() {
+            self.close(tunnel_id, error);
+        }
+    }
+}
+
+/// Reads a local connection in another thread
+fn read_local_inputs(mut local_reader: TcpStream, tunnel_id: String, tx: SyncSender<(String, TunnelInput)>) {
+    thread::spawn(move || {
+        let mut buf = vec![0u8; BUF_SIZE];
+        loop {
+            let input = match local_reader.read(&mut buf) {
+                Ok(0) => TunnelInput::Eof,
+                Ok(n) => TunnelInput::Data(buf[..n].to_vec()),
+                Err(_) => TunnelInput::Close
+            };
+            let is_data = matches!(input, TunnelInput::Data(_));
+            if tx.send((tunnel_id.clone(), input)).is_err() || !is_data {
+                return;
+            }
+        }
+    });
+}
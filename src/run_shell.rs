@@ -9,6 +9,8 @@ use portable_pty as pty;
 
 use crate::constants::PATH_VAR_SEP;
 
+use crate::commands::{command_processor::CommandSettings, response_stream::send_response};
+
 use super::message::{Message, MessageTypeToCmd, MessageTypeToStream};
 use super::constants::{BUF_SIZE, MAX_MESSAGE_HISTORY_SIZE, WAIT_TIME_RETRY_CNX_MS};
 
@@ -21,7 +23,8 @@ pub fn run_shell(
     rows: u16,
     tx_to_stream: Arc<Mutex<Sender<Message<MessageTypeToStream>>>>,
     rx_cmd: Arc<Mutex<Receiver<Message<MessageTypeToCmd>>>>,
-    history_of_messages_to_stream: Arc<Mutex<Vec<Message<MessageTypeToStream>>>>
+    history_of_messages_to_stream: Arc<Mutex<Vec<Message<MessageTypeToStream>>>>,
+    command_settings: CommandSettings
 ) -> io::Result<Arc<Mutex<Box<dyn portable_pty::MasterPty + Send>>>>
 {
     let pty_system = pty::native_pty_system();
@@ -61,7 +64,7 @@ pub fn run_shell(
     let master_stdin = master.clone();
     let hoposhell_folder = hoposhell_folder.clone();
     let _stdin_handle = thread::spawn(move || {
-        let mut commands = crate::commands::command_processor::CommandProcessor::new();
+        let mut commands = crate::commands::command_processor::CommandProcessor::new(command_settings, tx_to_stream_stdin.clone());
 
         loop {
             if let Ok(msg) = rx_cmd.lock().unwrap().recv() {
@@ -89,16 +92,9 @@ pub fn run_shell(
                                 if let Some(res) = commands.process_msg(&c, &hoposhell_folder) {
                                     /* consume and send the response back */
                                     eprintln!("[{}] Send response of command {:?}.", &res.message_id, res.cmd);
-                                    for chunk in res.chunk() {
-                                        // eprintln!("- send response chunk: {} {} {:?}", chunk.cmd, chunk.message_id, chunk.chunk_type);
-                                        let msg = Message {
-                                            mtype: MessageTypeToStream::COMMAND,
-                                            content: Some(chunk.to_message_payload())
-                                        };
-                                        send_message(msg);
-                                    }
+                                    send_response(&tx_to_stream_stdin, res, false);
                                 } else {
-                                    eprintln!("Got an invalid command.");
+                                    eprintln!("Got an invalid, incomplete or streamed command.");
                                 }
                             }
                         }
@@ -0,0 +1,175 @@
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, TcpStream},
+    sync::Arc,
+    thread
+};
+
+use crate::{
+    args::Args,
+    commands::socket_stream::SocketStream,
+    forward_tcp::{ForwarderHandle, ForwardRequest, parse_max_connections}
+};
+
+/* See RFC 1928 */
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN_NAME: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+pub fn main_socks(args: Args) {
+    /* Create a local SOCKS5 server that opens the requested
+     * connections from a remote shell, like ssh -D */
+
+    /* hopo socks <shell_id> <local port> [--max-connections <n>] */
+
+    let mut extra_args = args.extra_args.clone();
+    let max_connections = parse_max_connections(&mut extra_args);
+    if extra_args.is_empty() {
+        eprintln!("Usage: hopo socks <shell_id> <local port> [--max-connections <n>]");
+        std::process::exit(-1);
+    }
+    let local_port = extra_args[0].parse::<u16>().unwrap();
+
+    let listener = TcpListener::bind(format!("localhost:{}", local_port)).unwrap();
+    eprintln!("SOCKS5 proxy listening at port {}", local_port);
+
+    /* All the connections share the same connection to the server */
+    let forwarder = Arc::new(ForwarderHandle::start(args, max_connections));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+
+        if !forwarder.try_acquire(&peer) {
+            let _ = stream.shutdown(Shutdown::Both);
+            continue;
+        }
+
+        /* The handshake must not block the other clients */
+        let forwarder = forwarder.clone();
+        thread::spawn(move || {
+            let mut stream = stream;
+            match read_connect_request(&mut stream) {
+                Ok((host, port)) => {
+                    eprintln!("Got SOCKS request from {} to {}:{}", peer, host, port);
+                    forwarder.forward(ForwardRequest {
+                        local_stream: SocketStream::Tcp(stream),
+                        peer,
+                        host,
+                        port,
+                        on_connect: Some(Box::new(write_connect_reply))
+                    });
+                },
+                Err(e) => {
+                    eprintln!("Invalid SOCKS request from {}: {}", peer, e);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    forwarder.release();
+                }
+            }
+        });
+    }
+}
+
+/// Negotiates the authentication method and reads the destination of a CONNECT request
+fn read_connect_request(stream: &mut TcpStream) -> Result<(String, u16), std::io::Error> {
+    /* Greeting: VER NMETHODS METHODS */
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+    if header[0] != SOCKS_VERSION {
+        return Err(invalid_data(format!("Unsupported SOCKS version {}", header[0])));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+        stream.write_all(&[SOCKS_VERSION, METHOD_NOT_ACCEPTABLE])?;
+        return Err(invalid_data("Only connections without authentication are supported".to_string()));
+    }
+    stream.write_all(&[SOCKS_VERSION, METHOD_NO_AUTHENTICATION])?;
+
+    /* Request: VER CMD RSV ATYP DST.ADDR DST.PORT */
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request)?;
+    if request[0] != SOCKS_VERSION {
+        return Err(invalid_data(format!("Unsupported SOCKS version {}", request[0])));
+    }
+    if request[1] != COMMAND_CONNECT {
+        write_reply(stream, REPLY_COMMAND_NOT_SUPPORTED)?;
+        return Err(invalid_data(format!("Unsupported command {}", request[1])));
+    }
+    let host = match request[3] {
+        ADDRESS_IPV4 => {
+            let mut address = [0u8; 4];
+            stream.read_exact(&mut address)?;
+            Ipv4Addr::from(address).to_string()
+        },
+        ADDRESS_DOMAIN_NAME => {
+            /* Resolved by the shell */
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            let mut domain_name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut domain_name)?;
+            String::from_utf8(domain_name).map_err(|_| invalid_data("Invalid domain name".to_string()))?
+        },
+        ADDRESS_IPV6 => {
+            let mut address = [0u8; 16];
+            stream.read_exact(&mut address)?;
+            Ipv6Addr::from(address).to_string()
+        },
+        address_type => {
+            write_reply(stream, REPLY_ADDRESS_TYPE_NOT_SUPPORTED)?;
+            return Err(invalid_data(format!("Unsupported address type {}", address_type)));
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port)?;
+
+    return Ok((host, u16::from_be_bytes(port)));
+}
+
+/// Tells the client whether the shell could connect to the destination
+fn write_connect_reply(stream: &mut SocketStream, result: Result<(), &str>) {
+    let reply = match result {
+        Ok(()) => REPLY_SUCCEEDED,
+        Err(error) => {
+            /* The shell only gives the error message */
+            let error = error.to_lowercase();
+            if error.contains("refused") {
+                REPLY_CONNECTION_REFUSED
+            } else if error.contains("network is unreachable") {
+                REPLY_NETWORK_UNREACHABLE
+            } else if error.contains("cannot connect") {
+                REPLY_HOST_UNREACHABLE
+            } else {
+                REPLY_GENERAL_FAILURE
+            }
+        }
+    };
+    let _ = write_reply(stream, reply);
+}
+
+fn write_reply(stream: &mut impl Write, reply: u8) -> Result<(), std::io::Error> {
+    /* The address the shell is bound to is not known, so it is left empty */
+    return stream.write_all(&[SOCKS_VERSION, reply, 0x00, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0]);
+}
+
+fn invalid_data(message: String) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+}